@@ -0,0 +1,332 @@
+//! Access control for bot commands
+//!
+//! An Acl assigns roles to users, and each role carries a set of permissions.
+//! Users are matched by hostmask, by services account, or by their status in a
+//! channel. Commands can require a permission, which Bot checks before running them.
+//!
+//! An Acl can be saved to and loaded from a text file with one entry per line:
+//!
+//!     role admin *
+//!     role op kick,ban,topic
+//!     grant admin account alice
+//!     grant op mask *!*@trusted.example.com
+//!     grant op status #chan o
+//!     require quit admin
+//!
+//! The permission `*` grants every permission. Blank lines and lines starting
+//! with `#` are ignored.
+
+use std::io;
+use std::io::{IoError, IoResult, File};
+use std::str;
+use mask;
+use state::State;
+use {User, CaseMapAscii};
+
+/// Identifies a set of users that a role can be granted to
+#[deriving(Eq,Clone)]
+pub enum Subject {
+    /// Users matching a nick!user@host glob
+    ByMask(~[u8]),
+    /// Users logged in to a services account matching a glob
+    ByAccount(~[u8]),
+    /// Users holding the given prefix mode (e.g. 'o') or higher in a channel
+    ByStatus(~[u8], u8)
+}
+
+impl Subject {
+    /// Returns `true` if the user matches the subject.
+    /// `state` supplies the user's account and channel status.
+    pub fn matches(&self, user: &User, state: &State) -> bool {
+        let casemap = state.casemapping();
+        match *self {
+            ByMask(ref m) => mask::glob_match(mask::normalize(*m), user.raw(), casemap),
+            ByAccount(ref m) => {
+                state.user(user.nick()).and_then(|i| i.account.as_ref())
+                     .map_or(false, |account| mask::glob_match(*m, *account, casemap))
+            }
+            ByStatus(ref chan, mode) => state.has_status(*chan, user.nick(), mode)
+        }
+    }
+
+    fn to_raw(&self) -> ~[u8] {
+        match *self {
+            ByMask(ref m) => bytes!("mask ") + *m,
+            ByAccount(ref m) => bytes!("account ") + *m,
+            ByStatus(ref chan, mode) => bytes!("status ") + *chan + &[' ' as u8, mode]
+        }
+    }
+
+    fn parse(words: &[&str]) -> Option<Subject> {
+        match words {
+            ["mask", m] => Some(ByMask(m.as_bytes().to_owned())),
+            ["account", m] => Some(ByAccount(m.as_bytes().to_owned())),
+            ["status", chan, mode] if mode.len() == 1 => {
+                Some(ByStatus(chan.as_bytes().to_owned(), mode[0]))
+            }
+            _ => None
+        }
+    }
+}
+
+/// A named set of permissions
+#[deriving(Eq,Clone)]
+pub struct Role {
+    /// The role name
+    name: ~str,
+    /// The permissions the role carries
+    permissions: ~[~str]
+}
+
+/// Roles, the users they are granted to, and the permissions commands require
+#[deriving(Clone)]
+pub struct Acl {
+    priv roles: ~[Role],
+    priv grants: ~[(Subject, ~str)],
+    priv required: ~[(~str, ~str)],
+    priv path: Option<Path>
+}
+
+impl Acl {
+    /// Returns a new, empty Acl
+    pub fn new() -> Acl {
+        Acl {
+            roles: ~[],
+            grants: ~[],
+            required: ~[],
+            path: None
+        }
+    }
+
+    /// Loads an Acl from the file at `path`. The Acl remembers the path, for save().
+    /// If the file doesn't exist, an empty Acl is returned.
+    pub fn load(path: &Path) -> IoResult<Acl> {
+        let mut acl = if path.exists() {
+            let text = match File::open(path).and_then(|mut f| f.read_to_end()) {
+                Err(e) => return Err(e),
+                Ok(text) => text
+            };
+            match Acl::parse(str::from_utf8_lossy(text).as_slice()) {
+                Ok(acl) => acl,
+                Err(lineno) => return Err(IoError{
+                    kind: io::InvalidInput,
+                    desc: "invalid ACL entry",
+                    detail: Some(format!("{} line {}", path.display(), lineno))
+                })
+            }
+        } else {
+            Acl::new()
+        };
+        acl.path = Some(path.clone());
+        Ok(acl)
+    }
+
+    /// Writes the Acl back to the file it was loaded from.
+    /// Does nothing if it wasn't loaded from a file.
+    pub fn save(&self) -> IoResult<()> {
+        match self.path {
+            None => Ok(()),
+            Some(ref path) => File::create(path).and_then(|mut f| f.write(self.to_raw()))
+        }
+    }
+
+    /// Parses the text format. On error, returns the number of the bad line.
+    pub fn parse(text: &str) -> Result<Acl, uint> {
+        let mut acl = Acl::new();
+        for (i, line) in text.lines_any().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+            let words: ~[&str] = line.words().collect();
+            match words.as_slice() {
+                ["role", name] => acl.add_role(name, []),
+                ["role", name, perms] => {
+                    let perms: ~[&str] = perms.split(',').filter(|p| !p.is_empty()).collect();
+                    acl.add_role(name, perms)
+                }
+                ["grant", role, ..subject] => {
+                    match Subject::parse(subject) {
+                        None => return Err(i + 1),
+                        Some(subject) => acl.grant(subject, role)
+                    }
+                }
+                ["require", command, perm] => acl.require(command, perm),
+                _ => return Err(i + 1)
+            }
+        }
+        Ok(acl)
+    }
+
+    /// Formats the Acl in the text format
+    pub fn to_raw(&self) -> ~[u8] {
+        let mut out = ~[];
+        for role in self.roles.iter() {
+            let perms = role.permissions.connect(",");
+            out.push_all(format!("role {} {}\n", role.name, perms).as_bytes());
+        }
+        for &(ref subject, ref role) in self.grants.iter() {
+            out.push_all(format!("grant {} ", *role).as_bytes());
+            out.push_all(subject.to_raw());
+            out.push('\n' as u8);
+        }
+        for &(ref command, ref perm) in self.required.iter() {
+            out.push_all(format!("require {} {}\n", *command, *perm).as_bytes());
+        }
+        out
+    }
+
+    /// Adds a role, replacing any existing role with the same name
+    pub fn add_role(&mut self, name: &str, permissions: &[&str]) {
+        self.roles.retain(|r| r.name.as_slice() != name);
+        self.roles.push(Role{
+            name: name.to_owned(),
+            permissions: permissions.iter().map(|p| p.to_owned()).collect()
+        });
+    }
+
+    /// Removes a role and all grants of it. Returns `false` if the role was not found.
+    pub fn remove_role(&mut self, name: &str) -> bool {
+        let len = self.roles.len();
+        self.roles.retain(|r| r.name.as_slice() != name);
+        self.grants.retain(|&(_, ref role)| role.as_slice() != name);
+        self.roles.len() != len
+    }
+
+    /// Returns the role with the given name, if any
+    pub fn role<'a>(&'a self, name: &str) -> Option<&'a Role> {
+        self.roles.iter().find(|r| r.name.as_slice() == name)
+    }
+
+    /// Returns all roles
+    pub fn roles<'a>(&'a self) -> &'a [Role] {
+        self.roles.as_slice()
+    }
+
+    /// Grants a role to the users matching the subject
+    pub fn grant(&mut self, subject: Subject, role: &str) {
+        if !self.grants.iter().any(|&(ref s, ref r)| *s == subject && r.as_slice() == role) {
+            self.grants.push((subject, role.to_owned()));
+        }
+    }
+
+    /// Revokes a grant. Returns `false` if the grant was not found.
+    pub fn revoke(&mut self, subject: &Subject, role: &str) -> bool {
+        let len = self.grants.len();
+        self.grants.retain(|&(ref s, ref r)| !(s == subject && r.as_slice() == role));
+        self.grants.len() != len
+    }
+
+    /// Returns all grants, as (subject, role) pairs
+    pub fn grants<'a>(&'a self) -> &'a [(Subject, ~str)] {
+        self.grants.as_slice()
+    }
+
+    /// Makes the command require the permission. Command names are case-insensitive.
+    pub fn require(&mut self, command: &str, permission: &str) {
+        let command = CaseMapAscii.to_lower_str(command);
+        self.required.retain(|&(ref c, _)| *c != command);
+        self.required.push((command, permission.to_owned()));
+    }
+
+    /// Returns the permission the command requires, if any
+    pub fn required<'a>(&'a self, command: &str) -> Option<&'a str> {
+        let command = CaseMapAscii.to_lower_str(command);
+        self.required.iter().find(|&&(ref c, _)| *c == command).map(|&(_, ref p)| p.as_slice())
+    }
+
+    /// Returns the names of the roles the user holds
+    pub fn roles_of(&self, user: &User, state: &State) -> ~[~str] {
+        let mut roles: ~[~str] = ~[];
+        for &(ref subject, ref role) in self.grants.iter() {
+            if !roles.contains(role) && subject.matches(user, state) {
+                roles.push(role.clone());
+            }
+        }
+        roles
+    }
+
+    /// Returns `true` if the user holds a role with the permission
+    pub fn has_permission(&self, user: &User, state: &State, permission: &str) -> bool {
+        self.roles_of(user, state).iter().any(|name| {
+            self.role(*name).map_or(false, |r| {
+                r.permissions.iter().any(|p| p.as_slice() == "*" || p.as_slice() == permission)
+            })
+        })
+    }
+
+    /// Returns `true` if the user may run the command.
+    /// Commands that don't require a permission may be run by anyone.
+    pub fn check(&self, command: &str, user: &User, state: &State) -> bool {
+        match self.required(command) {
+            None => true,
+            Some(perm) => self.has_permission(user, state, perm)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Acl, ByMask, ByStatus};
+    use state::State;
+    use conn::Line;
+    use std::str;
+    use User;
+
+    static Text: &'static str = "# test ACL
+role admin *
+role op kick,topic
+grant admin account alice
+grant op mask *!*@trusted.example.com
+grant op status #chan o
+require quit admin
+require KICK kick
+";
+
+    #[test]
+    fn test_parse() {
+        let acl = Acl::parse(Text).unwrap();
+        assert_eq!(acl.roles().len(), 2);
+        assert_eq!(acl.role("op").unwrap().permissions, ~[~"kick", ~"topic"]);
+        assert_eq!(acl.grants().len(), 3);
+        match acl.grants()[2] {
+            (ref subject, ref role) => {
+                assert_eq!(*subject, ByStatus(bytes!("#chan").to_owned(), 'o' as u8));
+                assert_eq!(role.as_slice(), "op");
+            }
+        }
+        assert_eq!(acl.required("Kick"), Some("kick"));
+        let raw = acl.to_raw();
+        assert_eq!(Acl::parse(str::from_utf8(raw).unwrap()).unwrap().to_raw(), raw);
+        assert_eq!(Acl::parse("role admin *\ngrant admin nick bob\n").err(), Some(2));
+    }
+
+    #[test]
+    fn test_check() {
+        let mut acl = Acl::parse(Text).unwrap();
+        let mut state = State::new();
+        for line in [bytes!(":me!u@h JOIN #chan"),
+                     bytes!(":server 353 me = #chan :@me @carol bob"),
+                     bytes!(":alice!a@home JOIN #chan alice :Alice")].iter() {
+            state.handle_line(bytes!("me"), &Line::parse(*line).unwrap());
+        }
+        let alice = User::parse(bytes!("alice!a@home"));
+        let bob = User::parse(bytes!("bob!b@trusted.example.com"));
+        let carol = User::parse(bytes!("carol!c@elsewhere"));
+        let dave = User::parse(bytes!("dave!d@elsewhere"));
+
+        assert!(acl.check("quit", &alice, &state));
+        assert!(!acl.check("quit", &bob, &state));
+        assert!(acl.check("kick", &bob, &state));
+        assert!(acl.check("kick", &carol, &state));
+        assert!(!acl.check("kick", &dave, &state));
+        assert!(acl.check("hello", &dave, &state));
+
+        acl.grant(ByMask(bytes!("dave").to_owned()), "admin");
+        assert!(acl.check("quit", &dave, &state));
+        assert!(acl.revoke(&ByMask(bytes!("dave").to_owned()), "admin"));
+        assert!(!acl.check("quit", &dave, &state));
+        assert!(acl.remove_role("op"));
+        assert!(!acl.check("kick", &bob, &state));
+    }
+}
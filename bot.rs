@@ -0,0 +1,421 @@
+//! A command framework for bots
+//!
+//! A Bot recognizes commands in PRIVMSGs and routes them to registered handlers.
+//! Commands can be given with a prefix (`!op bob`), by addressing the bot by nick
+//! (`mybot: op bob`), or in a private message with no prefix at all.
+//!
+//!     let mut bot = Bot::new();
+//!     bot.register_fn("hello", "hello - says hello", hello);
+//!     bot.alias("hi", "hello");
+//!     irc::conn::connect(opts, |conn, event| { bot.handle_event(conn, &event); })
+//!
+//! Arguments are split on whitespace. Single or double quotes group words into one
+//! argument, and a backslash escapes the next character.
+
+use std::str;
+use conn::{Conn, Event, LineReceived, IRCCmd};
+use dispatch::{Handler, Propagation, Continue, Consume};
+use acl::Acl;
+use {User, CaseMapping, CaseMapAscii};
+
+/// The context a command runs in
+pub struct Context<'a, 'b> {
+    /// The connection the command arrived on
+    conn: &'a mut Conn<'b>,
+    /// The user who sent the command
+    sender: &'a User,
+    /// Where the command was sent: a channel, or our own nick for a private message
+    target: &'a [u8],
+    /// Where replies should go: the channel, or the sender for a private message
+    reply_to: ~[u8],
+    /// The name of the command, after alias resolution
+    command: ~str,
+    /// The command's arguments
+    args: ~[~[u8]],
+    /// The bot's Acl, if it has one
+    acl: Option<&'a mut Acl>
+}
+
+impl<'a, 'b> Context<'a, 'b> {
+    /// Returns `true` if the command was sent in a private message
+    pub fn is_private(&self) -> bool {
+        self.reply_to.as_slice() != self.target
+    }
+
+    /// Sends a PRIVMSG to the reply target
+    pub fn reply(&mut self, msg: &[u8]) {
+        self.conn.privmsg(self.reply_to.as_slice(), msg);
+    }
+
+    /// Sends a NOTICE to the reply target
+    pub fn notice(&mut self, msg: &[u8]) {
+        self.conn.send_command(IRCCmd(~"NOTICE"), [self.reply_to.as_slice(), msg], true);
+    }
+}
+
+/// A command handler that can be registered with a Bot
+pub trait CommandHandler {
+    /// Runs the command
+    fn run(&mut self, ctx: &mut Context);
+
+    /// Returns the id of whoever registered the handler, if it has one.
+    /// See Bot::unregister_owned().
+    fn owner(&self) -> Option<uint> { None }
+}
+
+/// A CommandHandler that wraps a plain function
+pub struct FnCommand(fn(&mut Context));
+
+impl CommandHandler for FnCommand {
+    fn run(&mut self, ctx: &mut Context) {
+        let FnCommand(f) = *self;
+        f(ctx)
+    }
+}
+
+struct Registered {
+    name: ~str,
+    help: ~str,
+    handler: ~CommandHandler
+}
+
+/// Routes commands to registered handlers
+pub struct Bot {
+    /// Prefixes that mark a message as a command, e.g. "!"
+    prefixes: ~[~str],
+    /// If `true`, messages starting with our nick followed by `:` or `,` are commands
+    addressed: bool,
+    /// If `true`, every private message is a command, with or without a prefix
+    private: bool,
+    /// If `true`, a `help` command lists the commands and shows their help text
+    help: bool,
+    /// If set, commands that require a permission in the Acl can only be run
+    /// by users that have it
+    acl: Option<Acl>,
+    priv commands: ~[Registered],
+    priv aliases: ~[(~str, ~str)]
+}
+
+impl Bot {
+    /// Returns a new Bot with the `!` prefix, accepting addressed and private commands,
+    /// and with the help command enabled.
+    pub fn new() -> Bot {
+        Bot {
+            prefixes: ~[~"!"],
+            addressed: true,
+            private: true,
+            help: true,
+            acl: None,
+            commands: ~[],
+            aliases: ~[]
+        }
+    }
+
+    /// Registers a command. Command names are case-insensitive.
+    /// Any existing command with the same name is replaced.
+    pub fn register(&mut self, name: &str, help: &str, handler: ~CommandHandler) {
+        let name = CaseMapAscii.to_lower_str(name);
+        self.commands.retain(|c| c.name != name);
+        self.commands.push(Registered{ name: name, help: help.to_owned(), handler: handler });
+    }
+
+    /// Registers a plain function as a command.
+    /// See register() for details.
+    pub fn register_fn(&mut self, name: &str, help: &str, f: fn(&mut Context)) {
+        self.register(name, help, ~FnCommand(f) as ~CommandHandler)
+    }
+
+    /// Removes a command. Returns `false` if the command was not found.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        let name = CaseMapAscii.to_lower_str(name);
+        let len = self.commands.len();
+        self.commands.retain(|c| c.name != name);
+        self.commands.len() != len
+    }
+
+    /// Removes a command if its handler has the given owner. Returns `false` if the
+    /// command was not found, or has been replaced by a handler with another owner.
+    pub fn unregister_owned(&mut self, name: &str, owner: uint) -> bool {
+        let name = CaseMapAscii.to_lower_str(name);
+        let len = self.commands.len();
+        self.commands.retain(|c| c.name != name || c.handler.owner() != Some(owner));
+        self.commands.len() != len
+    }
+
+    /// Returns `true` if a command with the given name is registered
+    pub fn has_command(&self, name: &str) -> bool {
+        let name = CaseMapAscii.to_lower_str(name);
+        self.commands.iter().any(|c| c.name == name)
+    }
+
+    /// Makes `alias` run the command `name`
+    pub fn alias(&mut self, alias: &str, name: &str) {
+        let alias = CaseMapAscii.to_lower_str(alias);
+        self.aliases.retain(|&(ref a, _)| *a != alias);
+        self.aliases.push((alias, CaseMapAscii.to_lower_str(name)));
+    }
+
+    /// Returns the names of the registered commands
+    pub fn commands(&self) -> ~[&str] {
+        self.commands.iter().map(|c| c.name.as_slice()).collect()
+    }
+
+    /// Returns the command text of a message, without its prefix, if the message
+    /// is a command. `nick` is our nickname, which is compared using `casemap`.
+    pub fn command_text<'a>(&self, msg: &'a [u8], nick: &[u8], casemap: CaseMapping,
+                            private: bool) -> Option<&'a [u8]> {
+        for prefix in self.prefixes.iter() {
+            if !prefix.is_empty() && msg.starts_with(prefix.as_bytes()) {
+                return Some(msg.slice_from(prefix.len()));
+            }
+        }
+        if self.addressed && msg.len() > nick.len() &&
+           casemap.equiv(msg.slice_to(nick.len()), nick) &&
+           (msg[nick.len()] == ':' as u8 || msg[nick.len()] == ',' as u8) {
+            return Some(msg.slice_from(nick.len()+1));
+        }
+        if self.private && private {
+            return Some(msg);
+        }
+        None
+    }
+
+    /// Handles an event, running the command it contains, if any.
+    /// Returns `true` if a command was run.
+    pub fn handle_event(&mut self, conn: &mut Conn, event: &Event) -> bool {
+        let line = match *event {
+            LineReceived(ref line) => line,
+            _ => return false
+        };
+        let (sender, target, msg) = match (&line.command, line.prefix.as_ref(),
+                                           line.args.as_slice()) {
+            (&IRCCmd(ref cmd), Some(user), [ref target, ref msg]) if *cmd == ~"PRIVMSG" => {
+                (user, target.as_slice(), msg.as_slice())
+            }
+            _ => return false
+        };
+        let private = !conn.state().is_channel(target);
+        let casemap = conn.state().casemapping();
+        let args = match self.command_text(msg, conn.me().nick(), casemap, private) {
+            None => return false,
+            Some(text) => tokenize(text)
+        };
+        if args.is_empty() {
+            return false;
+        }
+        let name = CaseMapAscii.to_lower_str(str::from_utf8_lossy(args[0]).as_slice());
+        let args = args.slice_from(1).to_owned();
+        let alias = self.aliases.iter().find(|&&(ref a, _)| *a == name)
+                                .map(|&(_, ref n)| n.clone());
+        let name = alias.unwrap_or(name);
+        let reply_to = if private { sender.nick().to_owned() } else { target.to_owned() };
+
+        let allowed = self.acl.as_ref().map_or(true, |acl| {
+            acl.check(name, sender, conn.state())
+        });
+        if !allowed {
+            let msg = format!("You don't have permission to use {}", name);
+            conn.send_command(IRCCmd(~"NOTICE"), [sender.nick(), msg.as_bytes()], true);
+            return true;
+        }
+
+        match self.commands.iter().position(|c| c.name == name) {
+            Some(idx) => {
+                let mut ctx = Context {
+                    conn: conn,
+                    sender: sender,
+                    target: target,
+                    reply_to: reply_to,
+                    command: name,
+                    args: args,
+                    acl: self.acl.as_mut()
+                };
+                self.commands[idx].handler.run(&mut ctx);
+                true
+            }
+            None if self.help && name.as_slice() == "help" => {
+                self.show_help(conn, reply_to, args);
+                true
+            }
+            None => {
+                if private {
+                    let msg = format!("Unknown command: {}", name);
+                    conn.send_command(IRCCmd(~"NOTICE"), [reply_to.as_slice(), msg.as_bytes()],
+                                      true);
+                }
+                false
+            }
+        }
+    }
+
+    fn show_help(&self, conn: &mut Conn, reply_to: &[u8], args: &[~[u8]]) {
+        let msg = match args.head() {
+            None => {
+                let mut names: ~[&str] = self.commands();
+                if self.help {
+                    names.push("help");
+                }
+                names.sort();
+                format!("Commands: {}", names.connect(", "))
+            }
+            Some(arg) => {
+                let name = CaseMapAscii.to_lower_str(str::from_utf8_lossy(*arg).as_slice());
+                let name = self.aliases.iter().find(|&&(ref a, _)| *a == name)
+                                       .map_or(name.clone(), |&(_, ref n)| n.clone());
+                match self.commands.iter().find(|c| c.name == name) {
+                    None => format!("No such command: {}", name),
+                    Some(c) if c.help.is_empty() => format!("No help for {}", name),
+                    Some(c) => c.help.clone()
+                }
+            }
+        };
+        conn.send_command(IRCCmd(~"NOTICE"), [reply_to, msg.as_bytes()], true);
+    }
+}
+
+impl Handler for Bot {
+    fn handle(&mut self, conn: &mut Conn, event: &Event) -> Propagation {
+        if self.handle_event(conn, event) { Consume } else { Continue }
+    }
+}
+
+/// Splits command text into arguments.
+///
+/// Arguments are separated by spaces. Text in single or double quotes is kept
+/// together, and a backslash escapes the next byte. An unterminated quote runs
+/// to the end of the text.
+pub fn tokenize(text: &[u8]) -> ~[~[u8]] {
+    let mut args = ~[];
+    let mut cur = ~[];
+    // whether cur holds an argument, which may be empty if it was quoted
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut iter = text.iter();
+    loop {
+        let b = match iter.next() {
+            None => break,
+            Some(&b) => b
+        };
+        match (b as char, quote) {
+            ('\\', _) => {
+                in_arg = true;
+                match iter.next() {
+                    None => cur.push(b),
+                    Some(&next) => cur.push(next)
+                }
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (_, Some(_)) => cur.push(b),
+            ('"', None) | ('\'', None) => {
+                in_arg = true;
+                quote = Some(b as char);
+            }
+            (' ', None) | ('\t', None) => {
+                if in_arg {
+                    args.push(cur);
+                    cur = ~[];
+                    in_arg = false;
+                }
+            }
+            _ => {
+                in_arg = true;
+                cur.push(b);
+            }
+        }
+    }
+    if in_arg {
+        args.push(cur);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bot, Context, tokenize};
+    use conn;
+    use conn::{Options, LineReceived, IRCCode};
+    use acl::{Acl, ByMask};
+    use {CaseMapAscii, CaseMapRfc1459};
+    use mock::{MockServer, Expect, ExpectEventually, Send, Close};
+
+    #[test]
+    fn test_tokenize() {
+        macro_rules! t(
+            ($text:expr, [$($arg:expr),*]) => (
+                assert_eq!(tokenize(bytes!($text)), ~[$(bytes!($arg).to_owned()),*])
+            )
+        )
+        t!("op bob", ["op", "bob"]);
+        t!("  say   #chan  \"hello there\" ", ["say", "#chan", "hello there"]);
+        t!("say 'it''s' \"\"", ["say", "its", ""]);
+        t!("a\\ b c\\\"d", ["a b", "c\"d"]);
+        t!("say \"unterminated quote", ["say", "unterminated quote"]);
+        t!("", []);
+    }
+
+    #[test]
+    fn test_command_text() {
+        let mut bot = Bot::new();
+        bot.prefixes.push(~"@@");
+        assert_eq!(bot.command_text(bytes!("bot{}: op"), bytes!("Bot[]"), CaseMapAscii, false),
+                   None);
+        let t = |msg: &[u8], private: bool| {
+            bot.command_text(msg, bytes!("Bot[]"), CaseMapRfc1459, private)
+        };
+        assert_eq!(t(bytes!("!op bob"), false), Some(bytes!("op bob")));
+        assert_eq!(t(bytes!("@@op bob"), false), Some(bytes!("op bob")));
+        assert_eq!(t(bytes!("bot{}: op bob"), false), Some(bytes!(" op bob")));
+        assert_eq!(t(bytes!("Bot[], op"), false), Some(bytes!(" op")));
+        assert_eq!(t(bytes!("bot{}her"), false), None);
+        assert_eq!(t(bytes!("op bob"), false), None);
+        assert_eq!(t(bytes!("op bob"), true), Some(bytes!("op bob")));
+    }
+
+    fn echo(ctx: &mut Context) {
+        let msg = ctx.args.connect_vec(&(' ' as u8));
+        ctx.reply(msg);
+    }
+
+    fn quit(ctx: &mut Context) {
+        ctx.conn.quit([]);
+    }
+
+    #[test]
+    fn test_bot() {
+        let server = MockServer::start(~[
+            ExpectEventually(~"USER *"),
+            Send(~":irc.test 001 ircnick :Welcome"),
+            Send(~":bob!b@host PRIVMSG #chan :!say \"hello world\" again"),
+            Expect(~"PRIVMSG #chan :hello world again"),
+            Send(~":bob!b@host PRIVMSG ircnick :help say"),
+            Expect(~"NOTICE bob :say <text> - repeats the text"),
+            Send(~":eve!e@host PRIVMSG #chan :!quit"),
+            Expect(~"NOTICE eve :You don't have permission to use quit"),
+            Send(~":bob!b@host PRIVMSG #chan :ircnick: bye"),
+            Expect(~"QUIT"),
+            Close
+        ]).unwrap();
+
+        let mut bot = Bot::new();
+        bot.register_fn("echo", "say <text> - repeats the text", echo);
+        bot.register_fn("quit", "", quit);
+        bot.alias("say", "echo");
+        bot.alias("bye", "quit");
+        let mut acl = Acl::new();
+        acl.add_role("admin", ["*"]);
+        acl.grant(ByMask(bytes!("bob!*@*").to_owned()), "admin");
+        acl.require("quit", "admin");
+        bot.acl = Some(acl);
+        let mut handled = 0;
+        let opts = Options::new("127.0.0.1", server.port());
+        let res = conn::connect(opts, |conn, event| {
+            match event {
+                LineReceived(ref line) if line.command == IRCCode(1) => (),
+                ref event => if bot.handle_event(conn, event) { handled += 1 }
+            }
+        });
+        assert!(res.is_ok());
+        assert_eq!(handled, 4);
+        server.finish().assert_ok();
+    }
+}
@@ -0,0 +1,750 @@
+//! Loading connection and bot settings from a configuration file
+//!
+//! Configuration files are JSON objects. Every key is optional except `servers`:
+//!
+//!     {
+//!         "servers": [{"host": "chat.freenode.net", "port": 6667}],
+//!         "nicks": ["rustbot", "rustbot_"],
+//!         "user": "rustbot",
+//!         "real": "rust-irclib bot",
+//!         "channels": ["#rust", "#secret key"],
+//!         "perform": ["MODE rustbot +B"],
+//!         "nickserv_password": "hunter2",
+//!         "regain_nick": true,
+//!         "rejoin": {"delay": 5000, "attempts": 3},
+//!         "proxy": {"type": "socks5", "host": "localhost", "port": 1080},
+//!         "ctcp": {"VERSION": "rustbot 0.1"},
+//!         "sasl": {"account": "rustbot", "password": "hunter2"},
+//!         "rate_limit": {"burst": 5, "interval": 2000},
+//!         "bot": {"prefixes": ["!", "."], "addressed": true, "private": false,
+//!                 "acl": "bot.acl"}
+//!     }
+//!
+//! The first nick is the requested nickname and the rest are alternates. Servers
+//! are listed in order of preference, and may also have a `password`. The `bot`
+//! settings are those of bot::Bot, and `acl` names a file in the acl module's format.
+//!
+//! Servers also accept `"tls": false`. Connections don't support TLS, so `true` is an
+//! error rather than being quietly ignored.
+//!
+//! A loaded Config owns all of its strings. Options and Bots are built from it on demand:
+//!
+//!     let config = Config::load(&Path::new("bot.json")).unwrap();
+//!     let mut bot = config.bot.new_bot().unwrap();
+//!     config.with_server(0, |opts| irc::conn::connect(opts, handler))
+
+use std::fmt;
+use std::str;
+use std::u64;
+use std::io::{IoError, File};
+use serialize::json;
+use serialize::json::Json;
+use conn::{Options, Host, Rejoin, NickStrategy, AppendUnderscore, AppendDigits, RandomSuffix};
+use conn::{RegainCommand, NickServRegain, NickServGhost, WebIrc, DefaultPort};
+use conn::{DefaultConnectTimeout, Sasl, RateLimit};
+use proxy::{Proxy, ProxyKind, Socks5, HttpConnect};
+use record::Recorder;
+use bot::Bot;
+use acl::Acl;
+
+macro_rules! check(
+    ($e:expr) => (match $e { Ok(v) => v, Err(e) => return Err(e) })
+)
+
+/// Errors that can occur while loading a configuration
+pub enum ConfigError {
+    /// The file could not be read
+    ConfigIO(IoError),
+    /// The file is not valid JSON
+    ConfigSyntax(~str),
+    /// A setting is missing or invalid. The first field is the key, as a path
+    /// like `servers[1].port`, and the second describes the problem.
+    ConfigInvalid(~str, ~str)
+}
+
+impl fmt::Show for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigIO(ref err) => err.fmt(f),
+            ConfigSyntax(ref msg) => write!(f.buf, "invalid JSON: {}", *msg),
+            ConfigInvalid(ref key, ref msg) => write!(f.buf, "{}: {}", *key, *msg)
+        }
+    }
+}
+
+/// Typedef for results that may contain a ConfigError
+pub type ConfigResult<T> = Result<T, ConfigError>;
+
+/// An owned version of Proxy
+pub struct OwnedProxy {
+    /// The kind of proxy
+    kind: ProxyKind,
+    /// The proxy host
+    host: ~str,
+    /// The proxy port
+    port: u16,
+    /// The username and password to authenticate with, if any
+    auth: Option<(~str, ~str)>,
+    /// See Proxy.remote_dns
+    remote_dns: bool
+}
+
+/// An owned version of WebIrc
+pub struct OwnedWebIrc {
+    /// The password configured for the gateway on the server
+    password: ~str,
+    /// The name of the gateway
+    gateway: ~str,
+    /// The user's hostname
+    hostname: ~str,
+    /// The user's IP address
+    ip: ~str
+}
+
+/// An owned version of Options, which can outlive the strings it was built from.
+/// The fields have the same meaning as in Options.
+///
+/// Options.commands can't be shared, so it is left out. Set it on the Options
+/// passed to with_options() instead.
+pub struct OwnedOptions {
+    /// The server host to connect to
+    host: ~str,
+    /// The server port to connect to
+    port: u16,
+    /// How long to wait for each connection attempt, in milliseconds
+    connect_timeout: Option<u64>,
+    /// A SOCKS5 or HTTP proxy to connect through
+    proxy: Option<OwnedProxy>,
+    /// The nickname to use
+    nick: ~str,
+    /// The username to use
+    user: ~str,
+    /// The real name to use
+    real: ~str,
+    /// The server password, sent with PASS
+    password: Option<~str>,
+    /// WEBIRC parameters
+    webirc: Option<OwnedWebIrc>,
+    /// The mode bitmask sent with USER
+    user_mode: uint,
+    /// Alternate nicknames to try, in order
+    alt_nicks: ~[~str],
+    /// How to generate further nicknames once alt_nicks is exhausted
+    nick_strategy: NickStrategy,
+    /// Whether to regain the requested nickname
+    regain_nick: bool,
+    /// The NickServ password for the requested nickname
+    nickserv_password: Option<~str>,
+    /// The NickServ command used to free up the requested nickname
+    nickserv_regain: RegainCommand,
+    /// Whether to detect netsplits and netjoins
+    netsplits: bool,
+    /// If set, channels are rejoined after we are kicked from them
+    rejoin_on_kick: Option<Rejoin>,
+    /// Channels to join, as (channel, key) pairs
+    autojoin: ~[(~str, ~str)],
+    /// Raw commands to send once registration is complete
+    perform: ~[~str],
+    /// Whether to wait for the end of the MOTD before perform and autojoin
+    wait_for_motd: bool,
+    /// Replies to CTCP requests, as (command, reply) pairs
+    ctcp_replies: ~[(~str, ~str)],
+    /// The account and password for SASL PLAIN authentication
+    sasl: Option<(~str, ~str)>,
+    /// If set, lines are sent no faster than the limit allows
+    rate_limit: Option<RateLimit>,
+    /// If set, every line sent and received is recorded
+    recorder: Option<Recorder>
+}
+
+impl OwnedOptions {
+    /// Returns a new OwnedOptions with the same defaults as Options::new()
+    pub fn new(host: &str, port: u16) -> OwnedOptions {
+        OwnedOptions {
+            host: host.to_owned(),
+            port: port,
+            connect_timeout: Some(DefaultConnectTimeout),
+            proxy: None,
+            nick: ~"ircnick",
+            user: ~"ircuser",
+            real: ~"rust-irclib user",
+            password: None,
+            webirc: None,
+            user_mode: 8,
+            alt_nicks: ~[],
+            nick_strategy: AppendUnderscore,
+            regain_nick: false,
+            nickserv_password: None,
+            nickserv_regain: NickServRegain,
+            netsplits: false,
+            rejoin_on_kick: None,
+            autojoin: ~[],
+            perform: ~[],
+            wait_for_motd: false,
+            ctcp_replies: ~[],
+            sasl: None,
+            rate_limit: None,
+            recorder: None
+        }
+    }
+
+    /// Calls `f` with Options borrowing from the OwnedOptions, and returns its result
+    pub fn with_options<T>(&self, f: |Options| -> T) -> T {
+        let alt_nicks: ~[&str] = self.alt_nicks.iter().map(|s| s.as_slice()).collect();
+        let autojoin: ~[(&str, &str)] = self.autojoin.iter().map(|&(ref chan, ref key)| {
+            (chan.as_slice(), key.as_slice())
+        }).collect();
+        let perform: ~[&str] = self.perform.iter().map(|s| s.as_slice()).collect();
+        let ctcp_replies: ~[(&str, &str)] = self.ctcp_replies.iter().map(|&(ref c, ref r)| {
+            (c.as_slice(), r.as_slice())
+        }).collect();
+
+        let mut opts = Options::new(self.host.as_slice(), self.port);
+        opts.connect_timeout = self.connect_timeout;
+        opts.proxy = self.proxy.as_ref().map(|p| Proxy {
+            kind: p.kind,
+            host: p.host.as_slice(),
+            port: p.port,
+            auth: p.auth.as_ref().map(|&(ref u, ref pw)| (u.as_slice(), pw.as_slice())),
+            remote_dns: p.remote_dns
+        });
+        opts.nick = self.nick.as_slice();
+        opts.user = self.user.as_slice();
+        opts.real = self.real.as_slice();
+        opts.password = self.password.as_ref().map(|s| s.as_slice());
+        opts.webirc = self.webirc.as_ref().map(|w| WebIrc {
+            password: w.password.as_slice(),
+            gateway: w.gateway.as_slice(),
+            hostname: w.hostname.as_slice(),
+            ip: w.ip.as_slice()
+        });
+        opts.user_mode = self.user_mode;
+        opts.alt_nicks = alt_nicks.as_slice();
+        opts.nick_strategy = self.nick_strategy;
+        opts.regain_nick = self.regain_nick;
+        opts.nickserv_password = self.nickserv_password.as_ref().map(|s| s.as_slice());
+        opts.nickserv_regain = self.nickserv_regain;
+        opts.netsplits = self.netsplits;
+        opts.rejoin_on_kick = self.rejoin_on_kick;
+        opts.autojoin = autojoin.as_slice();
+        opts.perform = perform.as_slice();
+        opts.wait_for_motd = self.wait_for_motd;
+        opts.ctcp_replies = ctcp_replies.as_slice();
+        opts.sasl = self.sasl.as_ref().map(|&(ref account, ref password)| Sasl {
+            account: account.as_slice(),
+            password: password.as_slice()
+        });
+        opts.rate_limit = self.rate_limit;
+        opts.recorder = self.recorder.clone();
+        f(opts)
+    }
+}
+
+/// A server to connect to
+pub struct ServerConfig {
+    /// The server host
+    host: ~str,
+    /// The server port
+    port: u16,
+    /// The server password, if any
+    password: Option<~str>
+}
+
+/// Settings for a bot::Bot
+pub struct BotConfig {
+    /// Prefixes that mark a message as a command
+    prefixes: ~[~str],
+    /// Whether messages addressed to the bot by nick are commands
+    addressed: bool,
+    /// Whether every private message is a command
+    private: bool,
+    /// Whether the help command is enabled
+    help: bool,
+    /// The file to load the Acl from, if any
+    acl: Option<Path>
+}
+
+impl BotConfig {
+    /// Returns a new BotConfig with the same defaults as Bot::new()
+    pub fn new() -> BotConfig {
+        let bot = Bot::new();
+        BotConfig {
+            prefixes: bot.prefixes.clone(),
+            addressed: bot.addressed,
+            private: bot.private,
+            help: bot.help,
+            acl: None
+        }
+    }
+
+    /// Returns a new Bot with these settings, loading its Acl if one is configured.
+    /// A missing Acl file gives an empty Acl, which Acl.save() will create.
+    pub fn new_bot(&self) -> ConfigResult<Bot> {
+        let mut bot = Bot::new();
+        bot.prefixes = self.prefixes.clone();
+        bot.addressed = self.addressed;
+        bot.private = self.private;
+        bot.help = self.help;
+        match self.acl {
+            None => (),
+            Some(ref path) => match Acl::load(path) {
+                Err(e) => return Err(ConfigIO(e)),
+                Ok(acl) => bot.acl = Some(acl)
+            }
+        }
+        Ok(bot)
+    }
+}
+
+/// Settings loaded from a configuration file
+pub struct Config {
+    /// The servers to connect to, in order of preference
+    servers: ~[ServerConfig],
+    /// The connection settings. The host, port and password are those of the
+    /// first server.
+    options: OwnedOptions,
+    /// The bot settings
+    bot: BotConfig
+}
+
+impl Config {
+    /// Loads a Config from the JSON file at `path`
+    pub fn load(path: &Path) -> ConfigResult<Config> {
+        match File::open(path).and_then(|mut f| f.read_to_end()) {
+            Err(e) => Err(ConfigIO(e)),
+            Ok(text) => Config::parse(str::from_utf8_lossy(text).as_slice())
+        }
+    }
+
+    /// Parses a Config from JSON text
+    pub fn parse(text: &str) -> ConfigResult<Config> {
+        match json::from_str(text) {
+            Err(e) => Err(ConfigSyntax(e.to_str())),
+            Ok(json) => Config::from_json(&json)
+        }
+    }
+
+    /// Builds a Config from a parsed JSON object
+    pub fn from_json(json: &Json) -> ConfigResult<Config> {
+        let obj = match *json {
+            json::Object(ref obj) => obj,
+            _ => return Err(invalid("(top level)", "expected an object"))
+        };
+        let mut servers = ~[];
+        let mut opts = OwnedOptions::new("", DefaultPort);
+        let mut bot = BotConfig::new();
+
+        for (key, value) in obj.iter() {
+            let key = key.as_slice();
+            match key {
+                "servers" => {
+                    for (i, server) in check!(list(value, key)).iter().enumerate() {
+                        servers.push(check!(parse_server(server, format!("servers[{}]", i))));
+                    }
+                }
+                "nicks" => {
+                    let nicks = check!(strings(value, key));
+                    if nicks.is_empty() {
+                        return Err(invalid(key, "expected at least one nickname"));
+                    }
+                    opts.nick = nicks[0].clone();
+                    opts.alt_nicks = nicks.slice_from(1).to_owned();
+                }
+                "user" => opts.user = check!(string(value, key)),
+                "real" => opts.real = check!(string(value, key)),
+                "user_mode" => opts.user_mode = check!(number(value, key, 255)) as uint,
+                "connect_timeout" => {
+                    opts.connect_timeout = match *value {
+                        json::Null => None,
+                        _ => Some(check!(number(value, key, u64::MAX)))
+                    }
+                }
+                "proxy" => opts.proxy = Some(check!(parse_proxy(value, key))),
+                "webirc" => {
+                    let fields = check!(string_fields(value, key,
+                                                      ["password", "gateway", "hostname", "ip"],
+                                                      []));
+                    let mut fields = fields.move_iter().map(|v| v.unwrap());
+                    opts.webirc = Some(OwnedWebIrc {
+                        password: fields.next().unwrap(),
+                        gateway: fields.next().unwrap(),
+                        hostname: fields.next().unwrap(),
+                        ip: fields.next().unwrap()
+                    });
+                }
+                "nick_strategy" => {
+                    opts.nick_strategy = match check!(string(value, key)).as_slice() {
+                        "underscore" => AppendUnderscore,
+                        "digits" => AppendDigits,
+                        "random" => RandomSuffix(3),
+                        _ => return Err(invalid(key, "expected underscore, digits or random"))
+                    }
+                }
+                "regain_nick" => opts.regain_nick = check!(boolean(value, key)),
+                "nickserv_password" => opts.nickserv_password = Some(check!(string(value, key))),
+                "nickserv_regain" => {
+                    opts.nickserv_regain = match check!(string(value, key)).as_slice() {
+                        "regain" => NickServRegain,
+                        "ghost" => NickServGhost,
+                        _ => return Err(invalid(key, "expected regain or ghost"))
+                    }
+                }
+                "netsplits" => opts.netsplits = check!(boolean(value, key)),
+                "rejoin" => opts.rejoin_on_kick = Some(check!(parse_rejoin(value, key))),
+                "channels" => {
+                    for (i, chan) in check!(strings(value, key)).move_iter().enumerate() {
+                        let words: ~[&str] = chan.words().collect();
+                        match words.as_slice() {
+                            [name] => opts.autojoin.push((name.to_owned(), ~"")),
+                            [name, chan_key] => {
+                                opts.autojoin.push((name.to_owned(), chan_key.to_owned()))
+                            }
+                            _ => {
+                                let key = format!("channels[{}]", i);
+                                let msg = "expected \"#channel\" or \"#channel key\"";
+                                return Err(invalid(key, msg));
+                            }
+                        }
+                    }
+                }
+                "perform" => opts.perform = check!(strings(value, key)),
+                "wait_for_motd" => opts.wait_for_motd = check!(boolean(value, key)),
+                "ctcp" => {
+                    let replies = match *value {
+                        json::Object(ref replies) => replies,
+                        _ => return Err(invalid(key, "expected an object"))
+                    };
+                    for (cmd, reply) in replies.iter() {
+                        let reply = check!(string(reply, format!("ctcp.{}", *cmd)));
+                        opts.ctcp_replies.push((cmd.clone(), reply));
+                    }
+                }
+                "sasl" => {
+                    let fields = check!(string_fields(value, key, ["account", "password"], []));
+                    let mut fields = fields.move_iter().map(|v| v.unwrap());
+                    opts.sasl = Some((fields.next().unwrap(), fields.next().unwrap()));
+                }
+                "rate_limit" => opts.rate_limit = Some(check!(parse_rate_limit(value, key))),
+                "bot" => bot = check!(parse_bot(value, key)),
+                _ => return Err(invalid(key, "unknown key"))
+            }
+        }
+
+        if servers.is_empty() {
+            return Err(invalid("servers", "expected at least one server"));
+        }
+        opts.host = servers[0].host.clone();
+        opts.port = servers[0].port;
+        opts.password = servers[0].password.clone();
+        Ok(Config {
+            servers: servers,
+            options: opts,
+            bot: bot
+        })
+    }
+
+    /// Calls `f` with Options for the server at index `idx` in servers,
+    /// and returns its result.
+    pub fn with_server<T>(&self, idx: uint, f: |Options| -> T) -> T {
+        let server = &self.servers[idx];
+        self.options.with_options(|opts| {
+            let mut opts = opts;
+            opts.host = Host(server.host.as_slice());
+            opts.port = server.port;
+            opts.password = server.password.as_ref().map(|s| s.as_slice());
+            f(opts)
+        })
+    }
+}
+
+fn invalid<S: Str>(key: S, msg: &str) -> ConfigError {
+    ConfigInvalid(key.as_slice().to_owned(), msg.to_owned())
+}
+
+fn string<S: Str>(value: &Json, key: S) -> ConfigResult<~str> {
+    match *value {
+        json::String(ref s) => Ok(s.clone()),
+        _ => Err(invalid(key, "expected a string"))
+    }
+}
+
+fn boolean<S: Str>(value: &Json, key: S) -> ConfigResult<bool> {
+    match *value {
+        json::Boolean(b) => Ok(b),
+        _ => Err(invalid(key, "expected true or false"))
+    }
+}
+
+fn number<S: Str>(value: &Json, key: S, max: u64) -> ConfigResult<u64> {
+    match *value {
+        json::Number(n) if n >= 0.0 && n <= max as f64 && (n as u64) as f64 == n => Ok(n as u64),
+        _ => Err(invalid(key, format!("expected a whole number from 0 to {}", max).as_slice()))
+    }
+}
+
+fn list<'a, S: Str>(value: &'a Json, key: S) -> ConfigResult<&'a [Json]> {
+    match *value {
+        json::List(ref l) => Ok(l.as_slice()),
+        _ => Err(invalid(key, "expected a list"))
+    }
+}
+
+fn strings<S: Str>(value: &Json, key: S) -> ConfigResult<~[~str]> {
+    let mut out = ~[];
+    for (i, v) in check!(list(value, key.as_slice())).iter().enumerate() {
+        out.push(check!(string(v, format!("{}[{}]", key.as_slice(), i))));
+    }
+    Ok(out)
+}
+
+// Reads an object whose values are all strings. The result has an entry for each
+// name in `required` and then `optional`, in order; required entries are always Some.
+fn string_fields(value: &Json, key: &str, required: &[&str], optional: &[&str])
+                 -> ConfigResult<~[Option<~str>]> {
+    let obj = match *value {
+        json::Object(ref obj) => obj,
+        _ => return Err(invalid(key, "expected an object"))
+    };
+    for (name, _) in obj.iter() {
+        if !required.iter().chain(optional.iter()).any(|&n| n == name.as_slice()) {
+            return Err(invalid(format!("{}.{}", key, *name), "unknown key"));
+        }
+    }
+    let mut out = ~[];
+    for (i, &name) in required.iter().chain(optional.iter()).enumerate() {
+        let subkey = format!("{}.{}", key, name);
+        match obj.find(&name.to_owned()) {
+            Some(v) => out.push(Some(check!(string(v, subkey)))),
+            None if i < required.len() => return Err(invalid(subkey, "missing")),
+            None => out.push(None)
+        }
+    }
+    Ok(out)
+}
+
+fn parse_server(value: &Json, key: ~str) -> ConfigResult<ServerConfig> {
+    let obj = match *value {
+        json::Object(ref obj) => obj,
+        _ => return Err(invalid(key, "expected an object"))
+    };
+    let mut server = ServerConfig{ host: ~"", port: DefaultPort, password: None };
+    let mut has_host = false;
+    for (name, v) in obj.iter() {
+        let subkey = format!("{}.{}", key, *name);
+        match name.as_slice() {
+            "host" => {
+                server.host = check!(string(v, subkey));
+                has_host = true;
+            }
+            "port" => server.port = check!(number(v, subkey, 65535)) as u16,
+            "password" => server.password = Some(check!(string(v, subkey))),
+            "tls" => {
+                if check!(boolean(v, subkey.as_slice())) {
+                    return Err(invalid(subkey, "TLS is not supported"));
+                }
+            }
+            _ => return Err(invalid(subkey, "unknown key"))
+        }
+    }
+    if !has_host {
+        return Err(invalid(key + ".host", "missing"));
+    }
+    Ok(server)
+}
+
+fn parse_proxy(value: &Json, key: &str) -> ConfigResult<OwnedProxy> {
+    let obj = match *value {
+        json::Object(ref obj) => obj,
+        _ => return Err(invalid(key, "expected an object"))
+    };
+    let mut proxy = OwnedProxy{ kind: Socks5, host: ~"", port: 0, auth: None, remote_dns: true };
+    let (mut has_type, mut has_host, mut has_port) = (false, false, false);
+    let (mut username, mut password) = (None, None);
+    for (name, v) in obj.iter() {
+        let subkey = format!("{}.{}", key, *name);
+        match name.as_slice() {
+            "type" => {
+                proxy.kind = match check!(string(v, subkey.as_slice())).as_slice() {
+                    "socks5" => Socks5,
+                    "http" => HttpConnect,
+                    _ => return Err(invalid(subkey, "expected socks5 or http"))
+                };
+                has_type = true;
+            }
+            "host" => {
+                proxy.host = check!(string(v, subkey));
+                has_host = true;
+            }
+            "port" => {
+                proxy.port = check!(number(v, subkey, 65535)) as u16;
+                has_port = true;
+            }
+            "username" => username = Some(check!(string(v, subkey))),
+            "password" => password = Some(check!(string(v, subkey))),
+            "remote_dns" => proxy.remote_dns = check!(boolean(v, subkey)),
+            _ => return Err(invalid(subkey, "unknown key"))
+        }
+    }
+    for &(has, name) in [(has_type, "type"), (has_host, "host"), (has_port, "port")].iter() {
+        if !has {
+            return Err(invalid(format!("{}.{}", key, name), "missing"));
+        }
+    }
+    proxy.auth = match (username, password) {
+        (Some(u), Some(p)) => Some((u, p)),
+        (None, None) => None,
+        (Some(_), None) => return Err(invalid(format!("{}.password", key), "missing")),
+        (None, Some(_)) => return Err(invalid(format!("{}.username", key), "missing"))
+    };
+    Ok(proxy)
+}
+
+fn parse_rejoin(value: &Json, key: &str) -> ConfigResult<Rejoin> {
+    let obj = match *value {
+        json::Object(ref obj) => obj,
+        _ => return Err(invalid(key, "expected an object"))
+    };
+    let mut rejoin = Rejoin{ delay: 0, attempts: 1 };
+    for (name, v) in obj.iter() {
+        let subkey = format!("{}.{}", key, *name);
+        match name.as_slice() {
+            "delay" => rejoin.delay = check!(number(v, subkey, u64::MAX)),
+            "attempts" => rejoin.attempts = check!(number(v, subkey, 1000)) as uint,
+            _ => return Err(invalid(subkey, "unknown key"))
+        }
+    }
+    Ok(rejoin)
+}
+
+fn parse_rate_limit(value: &Json, key: &str) -> ConfigResult<RateLimit> {
+    let obj = match *value {
+        json::Object(ref obj) => obj,
+        _ => return Err(invalid(key, "expected an object"))
+    };
+    let mut limit = RateLimit{ burst: 5, interval: 2000 };
+    for (name, v) in obj.iter() {
+        let subkey = format!("{}.{}", key, *name);
+        match name.as_slice() {
+            "burst" => {
+                limit.burst = check!(number(v, subkey.as_slice(), 1000)) as uint;
+                if limit.burst == 0 {
+                    return Err(invalid(subkey, "expected at least 1"));
+                }
+            }
+            "interval" => limit.interval = check!(number(v, subkey, u64::MAX)),
+            _ => return Err(invalid(subkey, "unknown key"))
+        }
+    }
+    Ok(limit)
+}
+
+fn parse_bot(value: &Json, key: &str) -> ConfigResult<BotConfig> {
+    let obj = match *value {
+        json::Object(ref obj) => obj,
+        _ => return Err(invalid(key, "expected an object"))
+    };
+    let mut bot = BotConfig::new();
+    for (name, v) in obj.iter() {
+        let subkey = format!("{}.{}", key, *name);
+        match name.as_slice() {
+            "prefixes" => bot.prefixes = check!(strings(v, subkey)),
+            "addressed" => bot.addressed = check!(boolean(v, subkey)),
+            "private" => bot.private = check!(boolean(v, subkey)),
+            "help" => bot.help = check!(boolean(v, subkey)),
+            "acl" => bot.acl = Some(Path::new(check!(string(v, subkey)))),
+            _ => return Err(invalid(subkey, "unknown key"))
+        }
+    }
+    Ok(bot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigInvalid};
+    use conn::{Host, RandomSuffix};
+    use proxy::HttpConnect;
+
+    static Text: &'static str = r#"{
+        "servers": [{"host": "irc.example.com", "port": 6697, "password": "pw"},
+                    {"host": "irc2.example.com", "tls": false}],
+        "nicks": ["bot", "bot_", "bot__"],
+        "user": "botuser",
+        "channels": ["#a", "#b key"],
+        "perform": ["MODE bot +B"],
+        "nick_strategy": "random",
+        "rejoin": {"delay": 5000, "attempts": 3},
+        "proxy": {"type": "http", "host": "proxy", "port": 8080,
+                  "username": "u", "password": "p"},
+        "ctcp": {"VERSION": "bot 1.0"},
+        "sasl": {"account": "acct", "password": "pw"},
+        "rate_limit": {"burst": 3},
+        "bot": {"prefixes": ["."], "private": false}
+    }"#;
+
+    fn error(text: &str) -> (~str, ~str) {
+        match Config::parse(text) {
+            Err(ConfigInvalid(key, msg)) => (key, msg),
+            Err(e) => fail!("unexpected error: {}", e),
+            Ok(_) => fail!("config parsed")
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = match Config::parse(Text) {
+            Ok(config) => config,
+            Err(e) => fail!("{}", e)
+        };
+        assert_eq!(config.servers.len(), 2);
+        assert_eq!(config.servers[1].port, 6667);
+        let bot = config.bot.new_bot().unwrap();
+        assert_eq!(bot.prefixes, ~[~"."]);
+        assert!(bot.addressed && !bot.private && bot.acl.is_none());
+
+        config.with_server(0, |opts| {
+            match opts.host { Host(h) => assert_eq!(h, "irc.example.com"), _ => fail!() }
+            assert_eq!(opts.port, 6697);
+            assert_eq!(opts.password, Some("pw"));
+            assert_eq!(opts.nick, "bot");
+            assert_eq!(opts.alt_nicks.to_owned(), ~["bot_", "bot__"]);
+            assert_eq!(opts.user, "botuser");
+            assert_eq!(opts.autojoin.to_owned(), ~[("#a", ""), ("#b", "key")]);
+            assert_eq!(opts.perform.to_owned(), ~["MODE bot +B"]);
+            assert_eq!(opts.ctcp_replies.to_owned(), ~[("VERSION", "bot 1.0")]);
+            assert_eq!(opts.sasl.map(|s| (s.account, s.password)), Some(("acct", "pw")));
+            assert_eq!(opts.rate_limit.map(|r| (r.burst, r.interval)), Some((3, 2000)));
+            match opts.nick_strategy { RandomSuffix(3) => (), _ => fail!() }
+            assert_eq!(opts.rejoin_on_kick.map(|r| (r.delay, r.attempts)), Some((5000, 3)));
+            let proxy = opts.proxy.unwrap();
+            assert!(proxy.kind == HttpConnect);
+            assert_eq!(proxy.auth, Some(("u", "p")));
+        });
+        config.with_server(1, |opts| {
+            match opts.host { Host(h) => assert_eq!(h, "irc2.example.com"), _ => fail!() }
+            assert_eq!(opts.password, None);
+            assert_eq!(opts.nick, "bot");
+        });
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error(r#"{"nicks": ["bot"]}"#), (~"servers", ~"expected at least one server"));
+        assert_eq!(error(r#"{"servers": [{"host": "a"}, {"host": "b", "port": 70000}]}"#),
+                   (~"servers[1].port", ~"expected a whole number from 0 to 65535"));
+        assert_eq!(error(r#"{"servers": [{"host": "a", "tls": true}]}"#),
+                   (~"servers[0].tls", ~"TLS is not supported"));
+        assert_eq!(error(r#"{"servers": [{"host": "a"}], "sasl": {"account": "a"}}"#),
+                   (~"sasl.password", ~"missing"));
+        assert_eq!(error(r#"{"servers": [{"host": "a"}], "rate_limit": {"burst": 0}}"#),
+                   (~"rate_limit.burst", ~"expected at least 1"));
+        assert_eq!(error(r#"{"servers": [{"host": "a"}], "bot": {"prefixes": "!"}}"#),
+                   (~"bot.prefixes", ~"expected a list"));
+        assert_eq!(error(r#"{"servers": [{"host": "a"}], "nicks": ["a", 1]}"#),
+                   (~"nicks[1]", ~"expected a string"));
+        assert_eq!(error(r#"{"servers": [{"host": "a"}], "proxy": {"type": "socks5"}}"#),
+                   (~"proxy.host", ~"missing"));
+        assert_eq!(error(r#"{"servers": [{"host": "a"}], "nick": "a"}"#),
+                   (~"nick", ~"unknown key"));
+        assert!(Config::parse("{").is_err());
+    }
+}
@@ -3,14 +3,26 @@
 use std::fmt;
 use std::io;
 use std::io::{IoError, IoResult, TcpStream, IpAddr};
+use std::io::net::ip::{Ipv4Addr, Ipv6Addr};
 use std::io::net::addrinfo;
 use std::io::net::ip::SocketAddr;
 use std::io::BufferedStream;
+use std::io::timer;
 use std::{char,str,vec,uint};
 use std::vec::MutableCloneableVector;
-use std::cmp::min;
+use std::cmp::{min, max};
 use std::{comm,task};
+use std::sync::arc::UnsafeArc;
+use std::sync::atomics::{AtomicBool, SeqCst};
+use time;
+use time::Timespec;
 use User;
+use state::State;
+use netsplit;
+use netsplit::{Detector, Split};
+use proxy;
+use proxy::{Proxy, ProxyError, Socks5, HttpConnect};
+use record::{Recorder, Received, Sent};
 
 mod handlers;
 
@@ -18,8 +30,45 @@ mod handlers;
 pub struct Conn<'a> {
     priv host: OptionsHost<'a>,
     priv write_chan: Option<Chan<~[u8]>>,
+    priv alive: Option<UnsafeArc<AtomicBool>>,
     priv logged_in: bool,
-    priv user: User
+    priv user: User,
+    priv modes: ~[u8],
+    priv away: bool,
+    priv oper: bool,
+    priv nick: NickState,
+    priv isupport: ~[(~[u8], ~[u8])],
+    priv caps: ~[~[u8]],
+    priv cap_req: ~[~[u8]],
+    priv state: State,
+    priv netsplit: Option<Detector>,
+    priv netsplit_timer: Option<TimerId>,
+    priv channels: ~[(~[u8], ~[u8])],
+    priv rejoin: Option<Rejoin>,
+    priv rejoining: ~[(~[u8], uint)],
+    priv timer_chan: Option<Chan<Cmd>>,
+    priv timers: ~[Timer],
+    priv next_timer: uint,
+    priv perform: ~[~[u8]],
+    priv wait_for_motd: bool,
+    priv performed: bool,
+    priv ctcp_replies: ~[(~[u8], ~[u8])],
+    priv sasl: Option<(~[u8], ~[u8])>,
+    priv events: ~[Event]
+}
+
+struct NickState {
+    requested: ~[u8],
+    alts: ~[~[u8]],
+    next_alt: uint,
+    attempts: uint,
+    // the nick length the server truncated or rejected our nick at, before NICKLEN is known
+    maxlen: Option<uint>,
+    strategy: NickStrategy,
+    regain: bool,
+    regain_cmd: RegainCommand,
+    password: Option<~[u8]>,
+    monitoring: bool
 }
 
 /// OptionsHost allows for using an IP address or a host string
@@ -45,12 +94,25 @@ pub struct Options<'a> {
     host: OptionsHost<'a>,
     /// The server port to connect to
     port: u16,
+    /// How long to wait for each connection attempt, in milliseconds.
+    /// When the host resolves to several addresses, an attempt that times out
+    /// moves on to the next address.
+    connect_timeout: Option<u64>,
+    /// A SOCKS5 or HTTP proxy to connect through
+    proxy: Option<Proxy<'a>>,
     /// The nickname to use
     nick: &'a str,
     /// The username to use
     user: &'a str,
     /// The real name to use
     real: &'a str,
+    /// The server password, sent with PASS
+    password: Option<&'a str>,
+    /// WEBIRC parameters, for connections made on behalf of a web gateway's users
+    webirc: Option<WebIrc<'a>>,
+    /// The mode bitmask sent with USER. Per RFC 2812, bit 2 (4) requests +w
+    /// and bit 3 (8) requests +i.
+    user_mode: uint,
     /// A Port to send procs to.
     /// The Port will be closed when connect() returns.
     /// Any proc sent to this port will be executed on the connection's task,
@@ -61,6 +123,110 @@ pub struct Options<'a> {
     /// to the channel after the channel is drained, but before it's closed, will be
     /// discarded.
     commands: Option<Port<Cmd>>,
+    /// Alternate nicknames to try, in order, if the nickname is unavailable
+    /// during registration.
+    alt_nicks: &'a [&'a str],
+    /// How to generate further nicknames once alt_nicks is exhausted.
+    nick_strategy: NickStrategy,
+    /// If `true`, and registration completes under a different nickname, watch for
+    /// the requested nickname to become available (using MONITOR if the server
+    /// supports it, or ISON polling otherwise) and switch back to it.
+    regain_nick: bool,
+    /// The NickServ password for the requested nickname. If provided along with
+    /// regain_nick, NickServ is asked to free up the nickname after registration.
+    nickserv_password: Option<&'a str>,
+    /// The NickServ command used to free up the requested nickname
+    nickserv_regain: RegainCommand,
+    /// If `true`, netsplits and netjoins are detected and reported with the NetSplit
+    /// and NetJoin events. The individual QUIT and JOIN lines that make them up are
+    /// not sent as LineReceived events, although they are still tracked in the State.
+    netsplits: bool,
+    /// If set, channels are rejoined after we are kicked from them
+    rejoin_on_kick: Option<Rejoin>,
+    /// Channels to join once registration is complete, as (channel, key) pairs.
+    /// Use "" for channels without a key.
+    autojoin: &'a [(&'a str, &'a str)],
+    /// Raw commands to send once registration is complete, before joining any
+    /// channels. Useful for OPER, user modes, or identifying to services.
+    perform: &'a [&'a str],
+    /// If `true`, the perform and autojoin commands are delayed until the end of the MOTD
+    /// instead of being sent as soon as registration completes.
+    wait_for_motd: bool,
+    /// Replies to CTCP requests, as (command, reply) pairs, e.g. ("VERSION", "mybot 1.0").
+    /// Commands are matched case-insensitively. Other requests are left to the callback.
+    ctcp_replies: &'a [(&'a str, &'a str)],
+    /// Credentials to authenticate with using SASL PLAIN during registration. If the
+    /// server doesn't offer the `sasl` capability, or authentication fails, registration
+    /// continues without it.
+    sasl: Option<Sasl<'a>>,
+    /// If set, lines are sent no faster than the limit allows, including lines sent
+    /// through a Handle
+    rate_limit: Option<RateLimit>,
+    /// If set, every line sent and received is recorded. See the record module.
+    recorder: Option<Recorder>
+}
+
+/// Credentials for SASL PLAIN authentication
+pub struct Sasl<'a> {
+    /// The services account to log in to
+    account: &'a str,
+    /// The account's password
+    password: &'a str
+}
+
+/// A limit on how fast lines are sent, to stay clear of the server's flood protection
+pub struct RateLimit {
+    /// How many lines may be sent at once
+    burst: uint,
+    /// How long it takes for another line to be allowed, in milliseconds
+    interval: u64
+}
+
+/// Policy for rejoining channels after being kicked
+pub struct Rejoin {
+    /// The delay in milliseconds before each rejoin attempt
+    delay: u64,
+    /// The number of times to try rejoining before giving up on the channel
+    attempts: uint
+}
+
+/// NickServ commands for reclaiming a nickname
+pub enum RegainCommand {
+    /// `REGAIN nick password`, which frees the nickname and changes to it
+    NickServRegain,
+    /// `GHOST nick password`, which disconnects whoever is using the nickname
+    NickServGhost
+}
+
+/// Parameters for the WEBIRC command, which lets a trusted gateway pass along
+/// the real hostname and IP address of the user it is connecting for.
+pub struct WebIrc<'a> {
+    /// The password configured for the gateway on the server
+    password: &'a str,
+    /// The name of the gateway
+    gateway: &'a str,
+    /// The user's hostname
+    hostname: &'a str,
+    /// The user's IP address
+    ip: &'a str
+}
+
+/// Strategies for generating a new nickname when the requested one is unavailable
+/// during registration.
+///
+/// Generated nicknames are truncated to respect the server's NICKLEN. NICKLEN is usually
+/// only sent after registration, so until then it is inferred from the server truncating
+/// or rejecting our nickname.
+pub enum NickStrategy {
+    /// Append underscores to the nickname
+    AppendUnderscore,
+    /// Append an increasing number to the nickname
+    AppendDigits,
+    /// Append the given number of random digits to the nickname
+    RandomSuffix(uint),
+    /// Call the given function with the requested nickname and the attempt number
+    /// (starting at 1). Returning None gives up and quits the connection.
+    NickGenerator(fn(&[u8], uint) -> Option<~[u8]>)
 }
 
 impl<'a> Options<'a> {
@@ -70,10 +236,29 @@ impl<'a> Options<'a> {
         Options {
             host: Host(host),
             port: port,
+            connect_timeout: Some(DefaultConnectTimeout),
+            proxy: None,
             nick: "ircnick",
             user: "ircuser",
             real: "rust-irclib user",
-            commands: None
+            password: None,
+            webirc: None,
+            user_mode: 8,
+            commands: None,
+            alt_nicks: &[],
+            nick_strategy: AppendUnderscore,
+            regain_nick: false,
+            nickserv_password: None,
+            nickserv_regain: NickServRegain,
+            netsplits: false,
+            rejoin_on_kick: None,
+            autojoin: &[],
+            perform: &[],
+            wait_for_motd: false,
+            ctcp_replies: &[],
+            sasl: None,
+            rate_limit: None,
+            recorder: None
         }
     }
 }
@@ -82,6 +267,7 @@ impl<'a> Options<'a> {
 pub type Cmd = proc(&mut Conn);
 
 /// Events that can be handled in the callback
+#[deriving(Clone)]
 pub enum Event {
     /// The connection was established
     Connected,
@@ -90,7 +276,52 @@ pub enum Event {
     /// The first received line should be 001
     LineReceived(Line),
     /// The connection has terminated
-    Disconnected
+    Disconnected,
+    /// Registration completed under a different nickname than the one requested.
+    /// The first arg is the requested nickname, the second is the actual nickname.
+    NickFallback(~[u8], ~[u8]),
+    /// A group of users quit due to a netsplit.
+    /// Only sent if Options.netsplits is set.
+    NetSplit(Split),
+    /// A group of users rejoined after a netsplit.
+    /// Only sent if Options.netsplits is set.
+    NetJoin(Split),
+    /// The server refused to let us join a channel.
+    /// The first arg is the channel.
+    JoinFailed(~[u8], JoinError),
+    /// A timer started with Conn.set_timer() fired
+    TimerFired(TimerId),
+    /// We sent a PRIVMSG, NOTICE, action, CTCP or CTCP reply through the Conn.
+    /// The line has our own user as its prefix, as if it had been received.
+    /// Lines sent through a Handle are not reported.
+    MessageSent(Line)
+}
+
+/// Identifies a timer started on a Conn
+#[deriving(Eq,Clone)]
+pub struct TimerId(uint);
+
+// A pending timer. Event timers have no action.
+struct Timer {
+    id: TimerId,
+    delay: u64,
+    repeat: bool,
+    action: Option<Cmd>
+}
+
+/// Reasons the server may refuse a JOIN
+#[deriving(Eq,Clone)]
+pub enum JoinError {
+    /// The channel is full (471)
+    ChannelIsFull,
+    /// The channel is invite-only (473)
+    InviteOnly,
+    /// We are banned from the channel (474)
+    Banned,
+    /// The channel key was missing or wrong (475)
+    BadKey,
+    /// The channel requires a registered nickname (477)
+    NeedRegistered
 }
 
 /// Errors that can be returned from connect()
@@ -99,6 +330,8 @@ pub enum Error {
     ErrResolve(IoError),
     /// Error connecting to server
     ErrConnect(IoError),
+    /// Error negotiating with the proxy
+    ErrProxy(ProxyError),
     /// I/O error raised while connection is active
     ErrIO(IoError)
 }
@@ -108,6 +341,7 @@ impl fmt::Show for Error {
         match *self {
             ErrResolve(ref err) => { write!(f.buf, "resolve error: {}", *err) }
             ErrConnect(ref err) => { write!(f.buf, "connect error: {}", *err) }
+            ErrProxy(ref err) => { write!(f.buf, "proxy error: {}", *err) }
             ErrIO(ref err) => err.fmt(f)
         }
     }
@@ -118,6 +352,17 @@ pub type Result = ::std::result::Result<(),Error>;
 
 pub static DefaultPort: u16 = 6667;
 
+/// The default per-attempt connect timeout, in milliseconds
+pub static DefaultConnectTimeout: u64 = 30 * 1000;
+
+// how long to wait for a connection attempt before starting the next one in parallel,
+// per RFC 8305
+static ConnectionAttemptDelay: u64 = 250;
+
+// the longest hostname a server will report. This is HOSTLEN in most ircds (hybrid, ratbox,
+// charybdis, InspIRCd's default), which is shorter than the 255 bytes DNS allows.
+static MaxHostLen: uint = 63;
+
 /// Connects to the remote server. This method will not return until the connection
 /// is terminated. Returns Ok(()) after connection termination if the connection was
 /// established successfully, or Err(_) if the connection could not be established in the
@@ -125,31 +370,94 @@ pub static DefaultPort: u16 = 6667;
 ///
 /// This method spawns some I/O-blocked tasks, so it is recommended that it be called
 /// from a libgreen task.
+///
+/// Connections always use the local address the OS picks. std's TcpStream can only be
+/// created by connect(), which can't bind a local address (vhost) first, so to connect
+/// from a particular address, go through a proxy that has it.
 pub fn connect(opts: Options, cb: |&mut Conn, Event|) -> Result {
-    let addr = {
-        match opts.host {
-            Addr(x) => x,
-            Host(host) => {
-                match addrinfo::get_host_addresses(host) {
-                    Err(e) => return Err(ErrResolve(e)),
-                    Ok([x, ..]) => x,
-                    Ok([]) => fail!("addrinfo returned 0 addresses")
-                }
-            }
-        }
+    // with a proxy, we connect to the proxy and it connects to the server
+    let (host, port) = match opts.proxy {
+        None => (opts.host, opts.port),
+        Some(ref proxy) => (Host(proxy.host), proxy.port)
+    };
+    let addrs = match resolve(host) {
+        Err(e) => return Err(ErrResolve(e)),
+        Ok(addrs) => addrs
     };
-    let addr = SocketAddr{ ip: addr, port: opts.port };
 
-    let stream = match TcpStream::connect(addr) {
+    let mut stream = match connect_any(sort_addrs(addrs), port, opts.connect_timeout) {
         Err(e) => return Err(ErrConnect(e)),
         Ok(stream) => stream
     };
 
+    match opts.proxy {
+        None => (),
+        Some(ref proxy) => {
+            let target = match (opts.host, proxy.kind) {
+                (Host(name), Socks5) if !proxy.remote_dns => {
+                    match resolve(Host(name)) {
+                        Err(e) => return Err(ErrResolve(e)),
+                        Ok(addrs) => Addr(sort_addrs(addrs)[0])
+                    }
+                }
+                (host, _) => host
+            };
+            let res = match proxy.kind {
+                Socks5 => proxy::socks5_connect(&mut stream, target, opts.port, proxy.auth),
+                HttpConnect => proxy::http_connect(&mut stream, target, opts.port, proxy.auth)
+            };
+            match res {
+                Err(e) => return Err(ErrProxy(e)),
+                Ok(()) => ()
+            }
+        }
+    }
+
     let mut conn = Conn{
         host: opts.host,
         write_chan: None,
+        alive: None,
         logged_in: false,
-        user: User::new(opts.nick.as_bytes(), Some(opts.user.as_bytes()), None)
+        user: User::new(opts.nick.as_bytes(), Some(opts.user.as_bytes()), None),
+        modes: ~[],
+        away: false,
+        oper: false,
+        nick: NickState{
+            requested: opts.nick.as_bytes().to_owned(),
+            alts: opts.alt_nicks.iter().map(|n| n.as_bytes().to_owned()).collect(),
+            next_alt: 0,
+            attempts: 0,
+            maxlen: None,
+            strategy: opts.nick_strategy,
+            regain: opts.regain_nick,
+            regain_cmd: opts.nickserv_regain,
+            password: opts.nickserv_password.map(|p| p.as_bytes().to_owned()),
+            monitoring: false
+        },
+        isupport: ~[],
+        caps: ~[],
+        cap_req: ~[],
+        state: State::new(),
+        netsplit: if opts.netsplits { Some(Detector::new()) } else { None },
+        netsplit_timer: None,
+        channels: opts.autojoin.iter().map(|&(c, k)| {
+            (c.as_bytes().to_owned(), k.as_bytes().to_owned())
+        }).collect(),
+        rejoin: opts.rejoin_on_kick,
+        rejoining: ~[],
+        timer_chan: None,
+        timers: ~[],
+        next_timer: 0,
+        perform: opts.perform.iter().map(|p| p.as_bytes().to_owned()).collect(),
+        wait_for_motd: opts.wait_for_motd,
+        performed: false,
+        ctcp_replies: opts.ctcp_replies.iter().map(|&(c, r)| {
+            (c.as_bytes().to_owned(), r.as_bytes().to_owned())
+        }).collect(),
+        sasl: opts.sasl.map(|s| {
+            (s.account.as_bytes().to_owned(), s.password.as_bytes().to_owned())
+        }),
+        events: ~[]
     };
 
     cb(&mut conn, Connected);
@@ -164,13 +472,193 @@ pub fn connect(opts: Options, cb: |&mut Conn, Event|) -> Result {
     }
 }
 
+// Resolves the host, treating an empty result as an error
+fn resolve(host: OptionsHost) -> IoResult<~[IpAddr]> {
+    match host {
+        Addr(x) => Ok(~[x]),
+        Host(host) => {
+            match addrinfo::get_host_addresses(host) {
+                Ok([]) => Err(IoError{
+                    kind: io::OtherIoError,
+                    desc: "host resolved to no addresses",
+                    detail: None
+                }),
+                res => res
+            }
+        }
+    }
+}
+
+// Removes duplicate addresses and interleaves the address families, starting with
+// the family of the first address, as described in RFC 8305 section 4.
+fn sort_addrs(addrs: ~[IpAddr]) -> ~[IpAddr] {
+    let mut unique: ~[IpAddr] = ~[];
+    for addr in addrs.move_iter() {
+        if !unique.contains(&addr) {
+            unique.push(addr);
+        }
+    }
+    fn is_v6(addr: &IpAddr) -> bool {
+        match *addr { Ipv6Addr(..) => true, Ipv4Addr(..) => false }
+    }
+    let first_v6 = unique.head().map_or(false, |a| is_v6(a));
+    let (first, second) = unique.partition(|a| is_v6(a) == first_v6);
+    let mut sorted = vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.move_iter(), second.move_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => {
+                for &addr in a.iter().chain(b.iter()) {
+                    sorted.push(addr);
+                }
+            }
+        }
+    }
+    sorted
+}
+
+// Connects to the first address that accepts, starting a new attempt every
+// ConnectionAttemptDelay ms (or as soon as an attempt fails) while earlier ones are
+// still pending. Returns the error from the last attempt if none succeed.
+//
+// A single Timer covers the attempt timeouts and start delays, and is dropped on return.
+// An attempt that times out can't be cancelled, so its task stays blocked in connect
+// until the OS gives up on it.
+fn connect_any(addrs: ~[IpAddr], port: u16, timeout: Option<u64>) -> IoResult<TcpStream> {
+    let (port_, chan) = Chan::new();
+    let mut clock = match timer::Timer::new() {
+        Err(e) => return Err(e),
+        Ok(t) => t
+    };
+    // for each started attempt, whether it is pending and when it times out
+    let mut pending: ~[bool] = ~[];
+    let mut deadlines: ~[Option<u64>] = ~[];
+    let mut next_start = now_ms();
+    let mut last_err = None;
+
+    loop {
+        let now = now_ms();
+        for idx in range(0, pending.len()) {
+            match deadlines[idx] {
+                Some(t) if pending[idx] && t <= now => {
+                    pending[idx] = false;
+                    last_err = Some(IoError{
+                        kind: io::TimedOut,
+                        desc: "connection attempt timed out",
+                        detail: None
+                    });
+                    next_start = now;
+                }
+                _ => ()
+            }
+        }
+        if pending.len() < addrs.len() && next_start <= now {
+            let idx = pending.len();
+            let addr = SocketAddr{ ip: addrs[idx], port: port };
+            let c = chan.clone();
+            task::spawn(proc() {
+                c.try_send((idx, TcpStream::connect(addr)));
+            });
+            pending.push(true);
+            deadlines.push(timeout.map(|ms| now + ms));
+            next_start = now + ConnectionAttemptDelay;
+        }
+        if pending.len() == addrs.len() && !pending.iter().any(|&p| p) {
+            return Err(last_err.unwrap());
+        }
+
+        // sleep until the next deadline, or until an attempt finishes
+        let mut wake = if pending.len() < addrs.len() { Some(next_start) } else { None };
+        for (&p, &d) in pending.iter().zip(deadlines.iter()) {
+            match d {
+                Some(t) if p => wake = Some(wake.map_or(t, |w| min(w, t))),
+                _ => ()
+            }
+        }
+        let alarm = wake.map(|t| clock.oneshot(t - now));
+        {
+            let select = comm::Select::new();
+            let mut attempt_handle = select.handle(&port_);
+            unsafe { attempt_handle.add() }
+            let mut alarm_handle = alarm.as_ref().map(|p| select.handle(p));
+            for h in alarm_handle.mut_iter() {
+                unsafe { h.add() }
+            }
+            select.wait();
+        }
+        match port_.try_recv() {
+            comm::Data((idx, _)) if !pending[idx] => (),
+            // any other pending attempts report to a closed port and drop their streams
+            comm::Data((_, Ok(stream))) => return Ok(stream),
+            comm::Data((idx, Err(e))) => {
+                pending[idx] = false;
+                last_err = Some(e);
+                next_start = now_ms();
+            }
+            // the alarm went off
+            comm::Empty | comm::Disconnected => ()
+        }
+    }
+}
+
+// A token bucket for RateLimit, used by the writer task
+struct Throttle {
+    limit: RateLimit,
+    tokens: uint,
+    // when tokens were last added, in milliseconds
+    refilled: u64
+}
+
+impl Throttle {
+    fn new(limit: RateLimit) -> Throttle {
+        Throttle{ limit: limit, tokens: max(limit.burst, 1), refilled: now_ms() }
+    }
+
+    // blocks until another line may be sent
+    fn wait(&mut self) {
+        let burst = max(self.limit.burst, 1);
+        let interval = max(self.limit.interval, 1);
+        loop {
+            let now = now_ms();
+            if self.tokens >= burst {
+                // a full bucket doesn't keep filling
+                self.refilled = now;
+            } else {
+                let added = (now - self.refilled) / interval;
+                self.tokens = min(burst, self.tokens + added as uint);
+                self.refilled += added * interval;
+            }
+            if self.tokens > 0 {
+                self.tokens -= 1;
+                return;
+            }
+            timer::sleep(self.refilled + interval - now);
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    time::precise_time_ns() / 1000000
+}
+
 impl<'a> Conn<'a> {
     fn run(&mut self, stream: TcpStream, opts: Options, cb: |&mut Conn, Event|) -> IoResult<()> {
         // spawn I/O tasks
         let (write_port, write_chan) = Chan::new();
         self.write_chan = Some(write_chan);
+        self.alive = Some(UnsafeArc::new(AtomicBool::new(true)));
         let (read_port, read_chan) = Chan::new();
         let (err_port, err_chan) = Chan::new();
+        let (timer_port, timer_chan) = Chan::new();
+        self.timer_chan = Some(timer_chan);
+        // arm any timers set before the connection was running
+        let pending: ~[(TimerId, u64)] = self.timers.iter().map(|t| (t.id, t.delay)).collect();
+        for &(id, delay) in pending.iter() {
+            self.after(delay, proc(conn: &mut Conn) { conn.fire_timer(id) });
+        }
+        let recorder = opts.recorder.clone();
+        let throttle = opts.rate_limit.map(|limit| Throttle::new(limit));
 
         {
             let mut write_task = task::task();
@@ -178,15 +666,25 @@ impl<'a> Conn<'a> {
             write_task.name("libirc writer");
             let stream = stream.clone();
             let err_chan = err_chan.clone();
+            let recorder = recorder.clone();
             write_task.spawn(proc() {
                 let mut stream = stream;
+                let mut throttle = throttle;
                 loop {
                     let line = match write_port.recv_opt() {
                         None => break,
-                        Some(v) => v
+                        // an empty line is sent by run() to shut us down
+                        Some(v) => if v.is_empty() { break } else { v }
                     };
+                    for t in throttle.mut_iter() {
+                        t.wait();
+                    }
                     match stream.write(line).and_then(|_| stream.flush()) {
-                        Ok(_) => (),
+                        Ok(_) => {
+                            for r in recorder.iter() {
+                                r.record(Sent, line);
+                            }
+                        }
                         Err(e) => {
                             if e.kind != io::EndOfFile {
                                 err_chan.send(Err(e));
@@ -218,6 +716,9 @@ impl<'a> Conn<'a> {
                         break;
                     }
                     if line.len() > 0 {
+                        for r in recorder.iter() {
+                            r.record(Received, line);
+                        }
                         if !read_chan.try_send(line) {
                             break;
                         }
@@ -226,9 +727,24 @@ impl<'a> Conn<'a> {
             })
         }
 
-        // send handshake commands
+        // send handshake commands.
+        // WEBIRC must come first, and PASS must come before NICK and USER.
+        match opts.webirc {
+            None => (),
+            Some(ref webirc) => {
+                self.send_command(IRCCmd(~"WEBIRC"), [webirc.password.as_bytes(),
+                                  webirc.gateway.as_bytes(), webirc.hostname.as_bytes(),
+                                  webirc.ip.as_bytes()], false);
+            }
+        }
+        self.send_command(IRCCmd(~"CAP"), [bytes!("LS"), bytes!("302")], false);
+        match opts.password {
+            None => (),
+            Some(pass) => self.send_command(IRCCmd(~"PASS"), [pass.as_bytes()], true)
+        }
         self.send_command(IRCCmd(~"NICK"), [opts.nick.as_bytes()], false);
-        self.send_command(IRCCmd(~"USER"), [opts.user.as_bytes(), bytes!("8 *"),
+        let mode = opts.user_mode.to_str();
+        self.send_command(IRCCmd(~"USER"), [opts.user.as_bytes(), mode.as_bytes(), bytes!("*"),
                           opts.real.as_bytes()], true);
 
 
@@ -241,6 +757,8 @@ impl<'a> Conn<'a> {
             unsafe { read_handle.add() }
             let mut err_handle = select.handle(&err_port);
             unsafe { err_handle.add() }
+            let mut timer_handle = select.handle(&timer_port);
+            unsafe { timer_handle.add() }
             let commands = opts.commands;
             let mut cmd_handle = commands.as_ref().map(|p| select.handle(p));
             if cmd_handle.is_some() {
@@ -258,6 +776,10 @@ impl<'a> Conn<'a> {
                         break;
                     }
                 }
+                match timer_port.try_recv() {
+                    comm::Data(cmd) => cmd(self),
+                    comm::Empty | comm::Disconnected => ()
+                }
                 if commands.is_some() {
                     match commands.as_ref().unwrap().try_recv() {
                         comm::Empty => (),
@@ -270,6 +792,7 @@ impl<'a> Conn<'a> {
                         }
                     }
                 }
+                self.flush_events(|c,e| cb(c,e));
                 let line = match read_port.try_recv() {
                     comm::Empty => continue,
                     comm::Disconnected => break,
@@ -286,10 +809,22 @@ impl<'a> Conn<'a> {
                     let line = line.to_raw();
                     debug!("[DEBUG] Received line: {}", str::from_utf8_lossy(line));
                 }
+                // completed netsplits need to be delivered before the line that ended them
+                let suppress = match self.netsplit {
+                    None => false,
+                    Some(ref mut detector) => {
+                        detector.handle_line(&line, &self.state, &mut self.events)
+                    }
+                };
+                if suppress {
+                    self.schedule_netsplit_flush();
+                }
+                self.flush_events(|c,e| cb(c,e));
                 handlers::handle_line(self, &line);
-                if self.logged_in {
+                if self.logged_in && !suppress {
                     cb(self, LineReceived(line));
                 }
+                self.flush_events(|c,e| cb(c,e));
             }
             if result.is_ok() {
                 // check the err_handle one more time
@@ -316,10 +851,25 @@ impl<'a> Conn<'a> {
                 }
             }
         };
+        // report any netsplit still in progress before we disconnect
+        self.flush_netsplits();
+        self.flush_events(|c,e| cb(c,e));
+
         // at this point the commands port is out of scope and therefore closed
         // ensure our write handle is closed out, in case we stopped due to read shutting down,
-        // and then run any buffered procs
-        self.write_chan = None;
+        // and then run any buffered procs.
+        // Outstanding Handles keep the write channel open, so tell the writer to stop.
+        self.timer_chan = None;
+        self.timers = ~[];
+        self.netsplit_timer = None;
+        match self.alive.take() {
+            None => (),
+            Some(alive) => unsafe { (*alive.get()).store(false, SeqCst) }
+        }
+        match self.write_chan.take() {
+            None => (),
+            Some(chan) => { chan.try_send(~[]); }
+        }
         match procs {
             None => (),
             Some(procs) => {
@@ -333,6 +883,35 @@ impl<'a> Conn<'a> {
         result
     }
 
+    // completes a netsplit or netjoin once no more of its lines have arrived for a while
+    fn schedule_netsplit_flush(&mut self) {
+        match self.netsplit_timer.take() {
+            None => (),
+            Some(id) => { self.cancel_timer(id); }
+        }
+        let id = self.run_after(netsplit::BurstTimeout, proc(conn: &mut Conn) {
+            conn.netsplit_timer = None;
+            conn.flush_netsplits();
+        });
+        self.netsplit_timer = Some(id);
+    }
+
+    fn flush_netsplits(&mut self) {
+        match self.netsplit {
+            None => (),
+            Some(ref mut detector) => detector.flush(&mut self.events)
+        }
+    }
+
+    // delivers any events queued by the built-in handlers
+    fn flush_events(&mut self, cb: |&mut Conn, Event|) {
+        if self.events.is_empty() { return }
+        let events = ::std::mem::replace(&mut self.events, ~[]);
+        for event in events.move_iter() {
+            cb(self, event);
+        }
+    }
+
     /// Returns `true` if the connection is still active
     /// (or was at the last pass through the runloop).
     pub fn is_connected(&self) -> bool {
@@ -345,10 +924,69 @@ impl<'a> Conn<'a> {
     }
 
     /// Returns the current User.
+    ///
+    /// The hostname is filled in once the server tells us what it is, either by echoing
+    /// one of our own lines back to us, or with RPL_HOSTHIDDEN (396).
     pub fn me<'a>(&'a self) -> &'a User {
         &self.user
     }
 
+    /// Returns our current user modes, e.g. "iw"
+    pub fn modes<'a>(&'a self) -> &'a [u8] {
+        self.modes.as_slice()
+    }
+
+    /// Returns `true` if we have the given user mode set
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.modes.contains(&(mode as u8))
+    }
+
+    /// Returns `true` if we are marked as away
+    pub fn is_away(&self) -> bool {
+        self.away
+    }
+
+    /// Returns `true` if we are an IRC operator
+    pub fn is_oper(&self) -> bool {
+        self.oper
+    }
+
+    /// Returns the maximum length of a message that can be sent to `dst` with
+    /// privmsg() without being truncated when the server relays it to others.
+    ///
+    /// The server prefixes relayed lines with our full nick!user@host. If our
+    /// hostname isn't known yet, the longest possible hostname is assumed.
+    pub fn max_privmsg_len(&self, dst: &[u8]) -> uint {
+        // :nick!user@host PRIVMSG dst :msg
+        let prefix = 1 + self.user.raw().len() + 1 + match self.user.host() {
+            None => 1 + MaxHostLen,
+            Some(_) => 0
+        };
+        let overhead = prefix + "PRIVMSG ".len() + dst.len() + " :".len();
+        if overhead > 510 { 0 } else { 510 - overhead }
+    }
+
+    /// Returns the value of the given ISUPPORT (005) parameter, if the server sent it.
+    /// Parameters sent without a value return an empty slice.
+    pub fn isupport<'a>(&'a self, key: &[u8]) -> Option<&'a [u8]> {
+        self.isupport.iter().find(|&&(ref k, _)| k.as_slice() == key).map(|&(_, ref v)| v.as_slice())
+    }
+
+    /// Returns `true` if the given IRCv3 capability has been enabled
+    pub fn has_cap(&self, cap: &[u8]) -> bool {
+        self.caps.iter().any(|c| c.as_slice() == cap)
+    }
+
+    /// Returns the tracked users and channels
+    pub fn state<'a>(&'a self) -> &'a State {
+        &self.state
+    }
+
+    /// Returns the maximum nickname length advertised by the server, if known.
+    pub fn nicklen(&self) -> Option<uint> {
+        self.isupport(bytes!("NICKLEN")).and_then(|v| uint::parse_bytes(v, 10))
+    }
+
     /// Sends a command to the server.
     /// The line is truncated to 510 bytes (not including newline) before sending.
     ///
@@ -365,74 +1003,9 @@ impl<'a> Conn<'a> {
     ///
     /// The add_colon flag causes the final argument in the args list to have a ':' prepended.
     pub fn send_command<V: Vector<u8>>(&mut self, cmd: Command, args: &[V], add_colon: bool) {
-        if !{
-            let chan = match self.write_chan {
-                None => return,
-                Some(ref mut c) => c
-            };
-            let mut line = [0u8, ..512];
-            let len = {
-                let mut buf = line.mut_slice_to(510);
-
-                fn append(buf: &mut &mut [u8], v: &[u8]) {
-                    let len = buf.copy_from(v);
-                    // this should work:
-                    //   *buf = buf.mut_slice_from(len);
-                    // but I'm getting weird borrowck issues (see mozilla/rust#11361)
-                    *buf = unsafe { ::std::cast::transmute(buf.mut_slice_from(len)) };
-                }
-
-                let is_ctcp = cmd.is_ctcp();
-                match cmd {
-                    IRCCmd(cmd) => {
-                        append(&mut buf, cmd.as_bytes());
-                    }
-                    IRCCode(code) => {
-                        uint::to_str_bytes(code, 10, |v| {
-                            append(&mut buf, v);
-                        });
-                    }
-                    IRCAction(ref dst) | IRCCTCP(ref dst,_) => {
-                        append(&mut buf, bytes!("PRIVMSG "));
-                        append(&mut buf, *dst);
-                        append(&mut buf, bytes!(" :\x01"));
-                        let action = match cmd {
-                            IRCAction(_) => { static b: &'static [u8] = bytes!("ACTION"); b }
-                            IRCCTCP(_,ref action) => action.as_slice(),
-                            _ => unreachable!()
-                        };
-                        append(&mut buf, action);
-                    }
-                    IRCCTCPReply(dst, action) => {
-                        append(&mut buf, bytes!("NOTICE "));
-                        append(&mut buf, dst);
-                        append(&mut buf, bytes!(" :\x01"));
-                        append(&mut buf, action);
-                    }
-                }
-                if !args.is_empty() {
-                    for arg in args.init().iter() {
-                        append(&mut buf, bytes!(" "));
-                        append(&mut buf, arg.as_slice());
-                    }
-                    if add_colon {
-                        append(&mut buf, bytes!(" :"));
-                    } else {
-                        append(&mut buf, bytes!(" "));
-                    }
-                    append(&mut buf, args.last().unwrap().as_slice());
-                }
-                if is_ctcp {
-                    append(&mut buf, bytes!("\x01"));
-                }
-                510 - buf.len()
-            };
-            debug!("[DEBUG] Sent line: {}", str::from_utf8_lossy(line.slice_to(len)));
-            line.mut_slice_from(len).copy_from(bytes!("\r\n"));
-            chan.try_send(line.slice_to(len+2).to_owned())
-        } {
-            self.write_chan = None;
-        }
+        if self.write_chan.is_none() { return }
+        let line = format_command(cmd, args, add_colon);
+        self.send_line(line);
     }
 
     /// Sends a raw command to the server
@@ -440,20 +1013,40 @@ impl<'a> Conn<'a> {
     /// The line is sent exactly as provided, except truncated to 510 characters
     /// and terminated with \r\n.
     pub fn send_raw(&mut self, raw: &[u8]) {
-        let raw = chomp(raw);
-        if raw.is_empty() { return }
+        if self.write_chan.is_none() { return }
+        match format_raw(raw) {
+            None => (),
+            Some(line) => self.send_line(line)
+        }
+    }
+
+    fn send_line(&mut self, line: ~[u8]) {
+        if self.write_chan.is_none() { return }
+        let msg = sent_message(&self.user, line.as_slice());
         if !{
             let chan = match self.write_chan {
                 None => return,
-                Some(ref mut c) => c
+                Some(ref c) => c
             };
-            let mut line = [0u8, ..512];
-            let len = line.mut_slice_to(510).copy_from(raw);
-            debug!("[DEBUG] Sent line: {}", str::from_utf8_lossy(line.slice_to(len)));
-            line.mut_slice_from(len).copy_from(bytes!("\r\n"));
-            chan.try_send(line.slice_to(len+2).to_owned())
+            chan.try_send(line)
         } {
             self.write_chan = None;
+            return;
+        }
+        match msg {
+            None => (),
+            Some(msg) => self.events.push(MessageSent(msg))
+        }
+    }
+
+    /// Returns a Handle that can be used to send commands to this connection
+    /// from any task, or None if the connection is not active.
+    pub fn handle(&self) -> Option<Handle> {
+        match (&self.write_chan, &self.alive) {
+            (&Some(ref chan), &Some(ref alive)) => {
+                Some(Handle{ chan: chan.clone(), alive: alive.clone() })
+            }
+            _ => None
         }
     }
 
@@ -486,7 +1079,15 @@ impl<'a> Conn<'a> {
 
     /// Sends a JOIN
     /// Pass [] for keys if there are none.
+    ///
+    /// The channels are remembered, along with their keys, until they are parted.
+    /// Multiple comma-separated channels and keys may be given.
     pub fn join(&mut self, room: &[u8], keys: &[u8]) {
+        let mut keyit = keys.split(|&b| b == ',' as u8);
+        for chan in room.split(|&b| b == ',' as u8) {
+            let key = keyit.next().unwrap_or(&[]);
+            self.remember_channel(chan, Some(key));
+        }
         if keys.is_empty() {
             self.send_command(IRCCmd(~"JOIN"), [room], false);
         } else {
@@ -494,9 +1095,153 @@ impl<'a> Conn<'a> {
         }
     }
 
+    /// Returns the channels we have joined or are trying to join, with their keys.
+    /// Channels without a key have an empty key.
+    pub fn joined_channels<'a>(&'a self) -> &'a [(~[u8], ~[u8])] {
+        self.channels.as_slice()
+    }
+
+    // records a channel we're in. A key of None keeps any existing key.
+    fn remember_channel(&mut self, chan: &[u8], key: Option<&[u8]>) {
+        let casemap = self.state.casemapping();
+        match self.channels.iter().position(|&(ref c, _)| casemap.equiv(*c, chan)) {
+            Some(idx) => {
+                match key {
+                    Some(key) => match self.channels[idx] {
+                        (_, ref mut k) => *k = key.to_owned()
+                    },
+                    None => ()
+                }
+                return;
+            }
+            None => ()
+        }
+        self.channels.push((chan.to_owned(), key.unwrap_or(&[]).to_owned()));
+    }
+
+    fn forget_channel(&mut self, chan: &[u8]) {
+        let casemap = self.state.casemapping();
+        self.channels.retain(|&(ref c, _)| !casemap.equiv(*c, chan));
+        self.rejoining.retain(|&(ref c, _)| !casemap.equiv(*c, chan));
+    }
+
+    // schedules a rejoin of a remembered channel according to the rejoin policy.
+    // Returns false if there are no attempts left, in which case the channel is forgotten.
+    fn schedule_rejoin(&mut self, chan: &[u8]) -> bool {
+        let rejoin = match self.rejoin {
+            None => return false,
+            Some(r) => r
+        };
+        let casemap = self.state.casemapping();
+        let attempts = match self.rejoining.iter().position(|&(ref c, _)| casemap.equiv(*c, chan)) {
+            Some(idx) => match self.rejoining[idx] {
+                (_, ref mut n) => { *n += 1; *n }
+            },
+            None => 0
+        };
+        if attempts == 0 {
+            self.rejoining.push((chan.to_owned(), 1));
+        } else if attempts > rejoin.attempts {
+            self.forget_channel(chan);
+            return false;
+        }
+        let chan = chan.to_owned();
+        self.after(rejoin.delay, proc(conn: &mut Conn) {
+            let key = {
+                let casemap = conn.state.casemapping();
+                match conn.channels.iter().find(|&&(ref c, _)| casemap.equiv(*c, chan)) {
+                    None => return, // parted in the meantime
+                    Some(&(_, ref key)) => key.clone()
+                }
+            };
+            conn.join(chan, key);
+        });
+        true
+    }
+
+    /// Starts a timer that fires after `delay` milliseconds, delivering a TimerFired
+    /// event to the callback. If `repeat` is set, the timer keeps firing every `delay`
+    /// milliseconds until it is cancelled.
+    ///
+    /// Timers are discarded when the connection terminates.
+    pub fn set_timer(&mut self, delay: u64, repeat: bool) -> TimerId {
+        self.add_timer(delay, repeat, None)
+    }
+
+    /// Runs the proc on the connection's task after `delay` milliseconds.
+    /// The proc can call run_after() again to repeat itself.
+    pub fn run_after(&mut self, delay: u64, cmd: Cmd) -> TimerId {
+        self.add_timer(delay, false, Some(cmd))
+    }
+
+    /// Cancels a timer started with set_timer() or run_after().
+    /// Returns `false` if the timer had already fired or been cancelled.
+    pub fn cancel_timer(&mut self, id: TimerId) -> bool {
+        let len = self.timers.len();
+        self.timers.retain(|t| t.id != id);
+        self.timers.len() != len
+    }
+
+    // Timers added before run() starts are armed by run()
+    fn add_timer(&mut self, delay: u64, repeat: bool, action: Option<Cmd>) -> TimerId {
+        let id = TimerId(self.next_timer);
+        self.next_timer += 1;
+        self.timers.push(Timer{ id: id, delay: delay, repeat: repeat, action: action });
+        self.after(delay, proc(conn: &mut Conn) { conn.fire_timer(id) });
+        id
+    }
+
+    fn fire_timer(&mut self, id: TimerId) {
+        let idx = match self.timers.iter().position(|t| t.id == id) {
+            None => return, // cancelled
+            Some(idx) => idx
+        };
+        if self.timers[idx].repeat {
+            let delay = self.timers[idx].delay;
+            self.after(delay, proc(conn: &mut Conn) { conn.fire_timer(id) });
+            self.events.push(TimerFired(id));
+            return;
+        }
+        let action = self.timers[idx].action.take();
+        self.timers.retain(|t| t.id != id);
+        match action {
+            None => self.events.push(TimerFired(id)),
+            Some(cmd) => cmd(self)
+        }
+    }
+
+    // runs the proc on the connection's task after the delay, in milliseconds
+    fn after(&mut self, delay: u64, cmd: Cmd) {
+        let chan = match self.timer_chan {
+            None => return,
+            Some(ref chan) => chan.clone()
+        };
+        let mut timer_task = task::task();
+        timer_task.unwatched();
+        timer_task.name("libirc timer");
+        timer_task.spawn(proc() {
+            timer::sleep(delay);
+            chan.try_send(cmd);
+        });
+    }
+
+    /// Requests the bans, exceptions, invite exceptions and quiets of a channel,
+    /// for those list modes the server supports. The results are tracked in the
+    /// channel's State.
+    pub fn fetch_lists(&mut self, chan: &[u8]) {
+        for &mode in bytes!("beIq").iter() {
+            if self.state.is_list_mode(mode) {
+                self.send_command(IRCCmd(~"MODE"), [chan, [mode].as_slice()], false);
+            }
+        }
+    }
+
     /// Sends a PART
     /// Pass [] for the message to use the default.
     pub fn part(&mut self, room: &[u8], msg: &[u8]) {
+        for chan in room.split(|&b| b == ',' as u8) {
+            self.forget_channel(chan);
+        }
         if msg.is_empty() {
             self.send_command(IRCCmd(~"PART"), [room], false);
         } else {
@@ -513,6 +1258,21 @@ fn chomp_owned(s: &mut ~[u8]) -> bool {
     } else { false }
 }
 
+// returns a sent line as the MessageSent event reports it, if it is a message
+fn sent_message(me: &User, raw: &[u8]) -> Option<Line> {
+    let mut line = match Line::parse(chomp(raw)) {
+        None => return None,
+        Some(line) => line
+    };
+    match line.command {
+        IRCCmd(ref cmd) if *cmd != ~"PRIVMSG" && *cmd != ~"NOTICE" => return None,
+        IRCCode(_) => return None,
+        _ => ()
+    }
+    line.prefix = Some(me.clone());
+    Some(line)
+}
+
 fn chomp<'a>(s: &'a [u8]) -> &'a [u8] {
     if s.len() > 0 {
         match s[s.len()-1] as char {
@@ -529,6 +1289,150 @@ fn chomp<'a>(s: &'a [u8]) -> &'a [u8] {
     } else { s }
 }
 
+/// Formats a command into a line suitable for sending, including the \r\n terminator.
+/// See Conn::send_command() for the interpretation of the arguments.
+fn format_command<V: Vector<u8>>(cmd: Command, args: &[V], add_colon: bool) -> ~[u8] {
+    let mut line = [0u8, ..512];
+    let len = {
+        let mut buf = line.mut_slice_to(510);
+
+        fn append(buf: &mut &mut [u8], v: &[u8]) {
+            let len = buf.copy_from(v);
+            // this should work:
+            //   *buf = buf.mut_slice_from(len);
+            // but I'm getting weird borrowck issues (see mozilla/rust#11361)
+            *buf = unsafe { ::std::cast::transmute(buf.mut_slice_from(len)) };
+        }
+
+        let is_ctcp = cmd.is_ctcp();
+        match cmd {
+            IRCCmd(cmd) => {
+                append(&mut buf, cmd.as_bytes());
+            }
+            IRCCode(code) => {
+                uint::to_str_bytes(code, 10, |v| {
+                    append(&mut buf, v);
+                });
+            }
+            IRCAction(ref dst) | IRCCTCP(_,ref dst) => {
+                append(&mut buf, bytes!("PRIVMSG "));
+                append(&mut buf, *dst);
+                append(&mut buf, bytes!(" :\x01"));
+                let action = match cmd {
+                    IRCAction(_) => { static b: &'static [u8] = bytes!("ACTION"); b }
+                    IRCCTCP(ref action,_) => action.as_slice(),
+                    _ => unreachable!()
+                };
+                append(&mut buf, action);
+            }
+            IRCCTCPReply(action, dst) => {
+                append(&mut buf, bytes!("NOTICE "));
+                append(&mut buf, dst);
+                append(&mut buf, bytes!(" :\x01"));
+                append(&mut buf, action);
+            }
+        }
+        if !args.is_empty() {
+            for arg in args.init().iter() {
+                append(&mut buf, bytes!(" "));
+                append(&mut buf, arg.as_slice());
+            }
+            if add_colon {
+                append(&mut buf, bytes!(" :"));
+            } else {
+                append(&mut buf, bytes!(" "));
+            }
+            append(&mut buf, args.last().unwrap().as_slice());
+        }
+        if is_ctcp {
+            append(&mut buf, bytes!("\x01"));
+        }
+        510 - buf.len()
+    };
+    debug!("[DEBUG] Sent line: {}", str::from_utf8_lossy(line.slice_to(len)));
+    line.mut_slice_from(len).copy_from(bytes!("\r\n"));
+    line.slice_to(len+2).to_owned()
+}
+
+/// Formats a raw line for sending, truncating to 510 bytes and appending \r\n.
+/// Returns None if the line is empty.
+fn format_raw(raw: &[u8]) -> Option<~[u8]> {
+    let raw = chomp(raw);
+    if raw.is_empty() { return None }
+    let mut line = [0u8, ..512];
+    let len = line.mut_slice_to(510).copy_from(raw);
+    debug!("[DEBUG] Sent line: {}", str::from_utf8_lossy(line.slice_to(len)));
+    line.mut_slice_from(len).copy_from(bytes!("\r\n"));
+    Some(line.slice_to(len+2).to_owned())
+}
+
+/// A handle for sending commands to a Conn from any task.
+///
+/// Handles are cloneable and sendable. Once the connection terminates, anything
+/// sent through a Handle is discarded.
+#[deriving(Clone)]
+pub struct Handle {
+    priv chan: Chan<~[u8]>,
+    priv alive: UnsafeArc<AtomicBool>
+}
+
+impl Handle {
+    /// Returns `true` if the connection is still active.
+    pub fn is_alive(&self) -> bool {
+        unsafe { (*self.alive.get()).load(SeqCst) }
+    }
+
+    /// Sends a command to the server.
+    /// See Conn::send_command() for the interpretation of the arguments.
+    ///
+    /// Returns `false` if the connection is no longer active.
+    pub fn send_command<V: Vector<u8>>(&self, cmd: Command, args: &[V], add_colon: bool) -> bool {
+        if !self.is_alive() { return false }
+        self.send_line(format_command(cmd, args, add_colon))
+    }
+
+    /// Sends a raw command to the server.
+    /// See Conn::send_raw() for details.
+    ///
+    /// Returns `false` if the connection is no longer active.
+    pub fn send_raw(&self, raw: &[u8]) -> bool {
+        if !self.is_alive() { return false }
+        match format_raw(raw) {
+            None => true,
+            Some(line) => self.send_line(line)
+        }
+    }
+
+    /// Sends a PRIVMSG
+    ///
+    /// Returns `false` if the connection is no longer active.
+    pub fn privmsg(&self, dst: &[u8], msg: &[u8]) -> bool {
+        self.send_command(IRCCmd(~"PRIVMSG"), [dst.as_slice(), msg.as_slice()], true)
+    }
+
+    /// Quits the connection
+    /// Pass [] for the message to use the default.
+    ///
+    /// Returns `false` if the connection is no longer active.
+    pub fn quit(&self, msg: &[u8]) -> bool {
+        if msg.is_empty() {
+            let args: &[&[u8]] = [];
+            self.send_command(IRCCmd(~"QUIT"), args, false)
+        } else {
+            self.send_command(IRCCmd(~"QUIT"), [msg], true)
+        }
+    }
+
+    fn send_line(&self, line: ~[u8]) -> bool {
+        if self.chan.try_send(line) {
+            true
+        } else {
+            unsafe { (*self.alive.get()).store(false, SeqCst) }
+            false
+        }
+    }
+}
+
 /// An IRC command
 #[deriving(Eq,Clone)]
 pub enum Command {
@@ -557,6 +1461,9 @@ impl Command {
 /// A parsed line
 #[deriving(Eq,Clone)]
 pub struct Line {
+    /// IRCv3 message tags, as (key, value) pairs with the values unescaped.
+    /// Tags without a value have an empty value.
+    tags: ~[(~[u8], ~[u8])],
     /// The optional prefix
     prefix: Option<User>,
     /// The command
@@ -568,6 +1475,22 @@ pub struct Line {
 impl Line {
     /// Parse a line into a Line struct
     pub fn parse(mut v: &[u8]) -> Option<Line> {
+        let mut tags = ~[];
+        if v.starts_with(bytes!("@")) {
+            let idx = match v.position_elem(&(' ' as u8)) {
+                None => return None,
+                Some(idx) => idx
+            };
+            for tag in v.slice(1, idx).split(|&b| b == ';' as u8).filter(|t| !t.is_empty()) {
+                match tag.position_elem(&('=' as u8)) {
+                    None => tags.push((tag.to_owned(), ~[])),
+                    Some(i) => {
+                        tags.push((tag.slice_to(i).to_owned(), unescape_tag(tag.slice_from(i+1))))
+                    }
+                }
+            }
+            v = v.slice_from(idx+1);
+        }
         let mut prefix = None;
         if v.starts_with(bytes!(":")) {
             let idx = match v.position_elem(&(' ' as u8)) {
@@ -649,12 +1572,23 @@ impl Line {
             }
         }
         Some(Line{
+            tags: tags,
             prefix: prefix,
             command: command,
             args: args
         })
     }
 
+    /// Returns the value of a message tag, if present
+    pub fn tag<'a>(&'a self, key: &[u8]) -> Option<&'a [u8]> {
+        self.tags.iter().find(|&&(ref k, _)| k.as_slice() == key).map(|&(_, ref v)| v.as_slice())
+    }
+
+    /// Returns the time from the server-time tag, if present and valid
+    pub fn server_time(&self) -> Option<Timespec> {
+        self.tag(bytes!("time")).and_then(parse_time)
+    }
+
     /// Converts into the "raw" representation :prefix cmd args
     pub fn to_raw(&self) -> ~[u8] {
         let mut cap = self.prefix.as_ref().map_or(0, |s| 1+s.raw().len()+1);
@@ -692,6 +1626,20 @@ impl Line {
             }
         }
         let mut res = vec::with_capacity(cap);
+        if !self.tags.is_empty() {
+            res.push('@' as u8);
+            for (i, &(ref key, ref value)) in self.tags.iter().enumerate() {
+                if i > 0 {
+                    res.push(';' as u8);
+                }
+                res.push_all(*key);
+                if !value.is_empty() {
+                    res.push('=' as u8);
+                    escape_tag(*value, &mut res);
+                }
+            }
+            res.push(' ' as u8);
+        }
         if self.prefix.is_some() {
             res.push(':' as u8);
             res.push_all(self.prefix.as_ref().unwrap().raw());
@@ -748,11 +1696,224 @@ impl Line {
     }
 }
 
+fn unescape_tag(v: &[u8]) -> ~[u8] {
+    let mut res = vec::with_capacity(v.len());
+    let mut iter = v.iter();
+    loop {
+        match iter.next() {
+            None => break,
+            Some(&b) if b == '\\' as u8 => {
+                match iter.next() {
+                    None => break,
+                    Some(&b) => res.push(match b as char {
+                        ':' => ';' as u8,
+                        's' => ' ' as u8,
+                        'r' => '\r' as u8,
+                        'n' => '\n' as u8,
+                        _ => b
+                    })
+                }
+            }
+            Some(&b) => res.push(b)
+        }
+    }
+    res
+}
+
+fn escape_tag(v: &[u8], res: &mut ~[u8]) {
+    for &b in v.iter() {
+        match b as char {
+            ';' => res.push_all(bytes!("\\:")),
+            ' ' => res.push_all(bytes!("\\s")),
+            '\\' => res.push_all(bytes!("\\\\")),
+            '\r' => res.push_all(bytes!("\\r")),
+            '\n' => res.push_all(bytes!("\\n")),
+            _ => res.push(b)
+        }
+    }
+}
+
+/// Parses a timestamp in the format used by the server-time capability,
+/// e.g. `2014-02-14T09:30:00.000Z`. The fractional seconds are optional.
+pub fn parse_time(v: &[u8]) -> Option<Timespec> {
+    fn num(v: &[u8]) -> Option<i64> {
+        if v.is_empty() || !v.iter().all(|&b| b >= '0' as u8 && b <= '9' as u8) {
+            return None;
+        }
+        Some(v.iter().fold(0i64, |n, &b| n * 10 + (b - '0' as u8) as i64))
+    }
+    if v.len() < 20 || v[4] != '-' as u8 || v[7] != '-' as u8 || v[10] != 'T' as u8
+        || v[13] != ':' as u8 || v[16] != ':' as u8 || v[v.len()-1] != 'Z' as u8 {
+        return None;
+    }
+    let (y, m, d) = match (num(v.slice(0, 4)), num(v.slice(5, 7)), num(v.slice(8, 10))) {
+        (Some(y), Some(m), Some(d)) if m >= 1 && m <= 12 && d >= 1 && d <= 31 => (y, m, d),
+        _ => return None
+    };
+    let secs = match (num(v.slice(11, 13)), num(v.slice(14, 16)), num(v.slice(17, 19))) {
+        (Some(h), Some(min), Some(s)) if h < 24 && min < 60 && s <= 60 => h * 3600 + min * 60 + s,
+        _ => return None
+    };
+    let nsec = match v.slice(19, v.len()-1) {
+        [] => 0,
+        frac if frac[0] == '.' as u8 && frac.len() <= 10 => {
+            let digits = frac.slice_from(1);
+            match num(digits) {
+                None => return None,
+                Some(n) => range(digits.len(), 9).fold(n, |n, _| n * 10)
+            }
+        }
+        _ => return None
+    };
+    // days since the epoch, from Howard Hinnant's days_from_civil
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some(Timespec::new(days * 86400 + secs, nsec as i32))
+}
+
+/// Formats a timestamp in the format used by the server-time capability
+pub fn format_time(ts: Timespec) -> ~str {
+    let tm = time::at_utc(ts);
+    format!("{:04d}-{:02d}-{:02d}T{:02d}:{:02d}:{:02d}.{:03d}Z", tm.tm_year + 1900, tm.tm_mon + 1,
+            tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec, tm.tm_nsec / 1000000)
+}
+
 #[cfg(test)]
 mod tests {
     use super::{Line,IRCCmd,IRCCode,IRCAction,IRCCTCP,IRCCTCPReply};
+    use super::{sort_addrs, connect, connect_any, Options, Conn, Connected, LineReceived};
+    use super::TimerFired;
+    use super::MessageSent;
+    use super::{parse_time, format_time, now_ms, Throttle, RateLimit, format_command};
+    use std::io::net::ip::{Ipv4Addr, Ipv6Addr};
+    use std::str;
+    use mock;
+    use mock::{MockServer, Expect, ExpectEventually, Close};
     use User;
 
+    #[test]
+    fn test_sort_addrs() {
+        let a6 = Ipv6Addr(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let b6 = Ipv6Addr(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+        let a4 = Ipv4Addr(192, 0, 2, 1);
+        let b4 = Ipv4Addr(192, 0, 2, 2);
+        let c4 = Ipv4Addr(192, 0, 2, 3);
+        assert_eq!(sort_addrs(~[a6, a6, b6, a4, b4, c4, a4]), ~[a6, a4, b6, b4, c4]);
+        assert_eq!(sort_addrs(~[a4, b4, a6]), ~[a4, a6, b4]);
+        assert_eq!(sort_addrs(~[a4]), ~[a4]);
+    }
+
+    #[test]
+    fn test_connect_any() {
+        let server = MockServer::start(~[Close]).unwrap();
+        let port = server.port();
+        let localhost = Ipv4Addr(127, 0, 0, 1);
+        assert!(connect_any(~[localhost], port, Some(1000)).is_ok());
+        server.finish().assert_ok();
+        // nothing is listening any more, so every attempt fails
+        assert!(connect_any(~[localhost, localhost], port, Some(1000)).is_err());
+    }
+
+    #[test]
+    fn test_timers() {
+        let server = MockServer::start(~[
+            ExpectEventually(~"USER *"),
+            mock::Send(~":irc.test 001 ircnick :Welcome"),
+            Expect(~"PRIVMSG #chan :tick 1"),
+            Expect(~"PRIVMSG #chan :tick 2"),
+            Expect(~"PRIVMSG #chan :tick 3"),
+            Expect(~"PRIVMSG #chan :later"),
+            Expect(~"QUIT"),
+            Close
+        ]).unwrap();
+
+        let mut ticks = 0;
+        let mut timer = None;
+        let res = connect(Options::new("127.0.0.1", server.port()), |conn, event| {
+            match event {
+                Connected => {
+                    // cancelled before it can fire
+                    let id = conn.set_timer(5, false);
+                    assert!(conn.cancel_timer(id));
+                    assert!(!conn.cancel_timer(id));
+                }
+                LineReceived(ref line) if line.command == IRCCode(1) => {
+                    timer = Some(conn.set_timer(10, true));
+                }
+                TimerFired(id) => {
+                    assert_eq!(Some(id), timer);
+                    ticks += 1;
+                    conn.privmsg(bytes!("#chan"), format!("tick {}", ticks).as_bytes());
+                    if ticks == 3 {
+                        assert!(conn.cancel_timer(id));
+                        conn.run_after(10, proc(conn: &mut Conn) {
+                            conn.privmsg(bytes!("#chan"), bytes!("later"));
+                            conn.quit([]);
+                        });
+                    }
+                }
+                _ => ()
+            }
+        });
+        assert!(res.is_ok());
+        assert_eq!(ticks, 3);
+        server.finish().assert_ok();
+    }
+
+    #[test]
+    fn test_throttle() {
+        let mut throttle = Throttle::new(RateLimit{ burst: 2, interval: 50 });
+        let start = now_ms();
+        for _ in range(0, 4) {
+            throttle.wait();
+        }
+        // two lines go at once, and the others wait for an interval each
+        assert!(now_ms() - start >= 100);
+    }
+
+    #[test]
+    fn test_message_sent() {
+        let server = MockServer::start(~[
+            ExpectEventually(~"USER *"),
+            mock::Send(~":irc.test 001 ircnick :Welcome"),
+            Expect(~"PRIVMSG #chan :hi"),
+            Expect(~"PRIVMSG #chan :\x01ACTION waves\x01"),
+            Expect(~"MODE ircnick +i"),
+            Expect(~"NOTICE bob :psst"),
+            Expect(~"QUIT"),
+            Close
+        ]).unwrap();
+
+        let mut sent = ~[];
+        let res = connect(Options::new("127.0.0.1", server.port()), |conn, event| {
+            match event {
+                LineReceived(ref line) if line.command == IRCCode(1) => {
+                    conn.privmsg(bytes!("#chan"), bytes!("hi"));
+                    conn.send_command(IRCAction(bytes!("#chan").to_owned()), [bytes!("waves")],
+                                      false);
+                    conn.send_raw(bytes!("MODE ircnick +i"));
+                    conn.send_raw(bytes!("NOTICE bob :psst"));
+                    conn.quit([]);
+                }
+                MessageSent(line) => sent.push(line),
+                _ => ()
+            }
+        });
+        assert!(res.is_ok());
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|l| l.prefix.as_ref().unwrap().nick() == bytes!("ircnick")));
+        assert_eq!(sent[0].command, IRCCmd(~"PRIVMSG"));
+        assert_eq!(sent[0].args, ~[bytes!("#chan").to_owned(), bytes!("hi").to_owned()]);
+        assert_eq!(sent[1].command, IRCAction(bytes!("#chan").to_owned()));
+        assert_eq!(sent[1].args, ~[bytes!("waves").to_owned()]);
+        assert_eq!(sent[2].command, IRCCmd(~"NOTICE"));
+        server.finish().assert_ok();
+    }
+
     #[test]
     fn parse_line() {
         macro_rules! b(
@@ -768,6 +1929,7 @@ mod tests {
                 let line = Line::parse(v);
                 assert!(line.is_some());
                 let line = line.unwrap();
+                assert_eq!(line.tags, exp.tags);
                 assert_eq!(line.prefix, exp.prefix);
                 assert_eq!(line.command, exp.command);
                 assert_eq!(line.args, exp.args);
@@ -781,6 +1943,7 @@ mod tests {
         t!(b!(":sendak.freenode.net 001 asldfkj :Welcome to the freenode Internet \
             Relay Chat Network asldfkj"),
             Some(Line{
+                tags: ~[],
                 prefix: Some(User::parse(b!("sendak.freenode.net"))),
                 command: IRCCode(1),
                 args: ~[b!("asldfkj"),
@@ -788,12 +1951,14 @@ mod tests {
             }));
         t!(b!("004 asdf :This is a test"),
             Some(Line{
+                tags: ~[],
                 prefix: None,
                 command: IRCCode(4),
                 args: ~[b!("asdf"), b!("This is a test")]
             }));
         t!(b!(":nick!user@host.com PRIVMSG #channel :Some message"),
             Some(Line{
+                tags: ~[],
                 prefix: Some(User::parse(b!("nick!user@host.com"))),
                 command: IRCCmd(~"PRIVMSG"),
                 args: ~[b!("#channel"), b!("Some message")]
@@ -802,12 +1967,14 @@ mod tests {
         t!(b!(":sendak  001 asdf :Test"), None);
         t!(b!("004"),
             Some(Line{
+                tags: ~[],
                 prefix: None,
                 command: IRCCode(4),
                 args: ~[]
             }));
         t!(b!(":bob!user@host.com PRIVMSG #channel :\x01ACTION does some stuff"),
             Some(Line{
+                tags: ~[],
                 prefix: Some(User::parse(b!("bob!user@host.com"))),
                 command: IRCAction(b!("#channel")),
                 args: ~[b!("does some stuff")]
@@ -815,17 +1982,64 @@ mod tests {
             b!(":bob!user@host.com PRIVMSG #channel :\x01ACTION does some stuff\x01"));
         t!(b!(":bob!user@host.com PRIVMSG #channel :\x01VERSION\x01"),
             Some(Line{
+                tags: ~[],
                 prefix: Some(User::parse(b!("bob!user@host.com"))),
                 command: IRCCTCP(b!("VERSION"), b!("#channel")),
                 args: ~[]
             }));
         t!(b!(":bob NOTICE #frobnitz :\x01RESPONSE to whatever\x01"),
             Some(Line{
+                tags: ~[],
                 prefix: Some(User::parse(b!("bob"))),
                 command: IRCCTCPReply(b!("RESPONSE"), b!("#frobnitz")),
                 args: ~[b!("to whatever")]
             }));
         t!(b!(":bob föo"), None);
         t!(b!(":bob f23"), None);
+        t!(b!("@time=2014-02-14T09:30:00.250Z;msgid=a\\sb\\:c;+draft :bob PRIVMSG #chan :hi"),
+            Some(Line{
+                tags: ~[(b!("time"), b!("2014-02-14T09:30:00.250Z")),
+                        (b!("msgid"), b!("a b;c")),
+                        (b!("+draft"), ~[])],
+                prefix: Some(User::parse(b!("bob"))),
+                command: IRCCmd(~"PRIVMSG"),
+                args: ~[b!("#chan"), b!("hi")]
+            }));
+        t!(b!("@time=x"), None);
+    }
+
+    #[test]
+    fn test_format_command() {
+        let t = |cmd, args: &[&[u8]], add_colon| {
+            str::from_utf8_owned(format_command(cmd, args, add_colon)).unwrap()
+        };
+        assert_eq!(t(IRCCmd(~"PRIVMSG"), &[bytes!("#chan"), bytes!("hi there")], true),
+                   ~"PRIVMSG #chan :hi there\r\n");
+        assert_eq!(t(IRCCode(1), &[bytes!("me")], false), ~"1 me\r\n");
+        assert_eq!(t(IRCAction(bytes!("#chan").to_owned()), &[bytes!("waves")], false),
+                   ~"PRIVMSG #chan :\x01ACTION waves\x01\r\n");
+        // the CTCP command comes first and the destination second, as in Line::parse()
+        assert_eq!(t(IRCCTCP(bytes!("VERSION").to_owned(), bytes!("bob").to_owned()), &[], false),
+                   ~"PRIVMSG bob :\x01VERSION\x01\r\n");
+        assert_eq!(t(IRCCTCPReply(bytes!("VERSION").to_owned(), bytes!("bob").to_owned()),
+                     &[bytes!("bot 1.0")], false),
+                   ~"NOTICE bob :\x01VERSION bot 1.0\x01\r\n");
+        let line = Line::parse(bytes!("PRIVMSG bob :\x01PING 123\x01")).unwrap();
+        let args: ~[&[u8]] = line.args.iter().map(|a| a.as_slice()).collect();
+        assert_eq!(t(line.command.clone(), args.as_slice(), false),
+                   ~"PRIVMSG bob :\x01PING 123\x01\r\n");
+    }
+
+    #[test]
+    fn test_server_time() {
+        let line = Line::parse(bytes!("@time=2014-02-14T09:30:00.25Z PING :x")).unwrap();
+        let ts = line.server_time().unwrap();
+        assert_eq!((ts.sec, ts.nsec), (1392370200, 250000000));
+        assert_eq!(format_time(ts), ~"2014-02-14T09:30:00.250Z");
+        assert_eq!(parse_time(bytes!("1970-01-01T00:00:00Z")).map(|t| t.sec), Some(0));
+        assert_eq!(parse_time(bytes!("2000-03-01T00:00:00Z")).map(|t| t.sec), Some(951868800));
+        assert!(parse_time(bytes!("2014-13-01T00:00:00Z")).is_none());
+        assert!(parse_time(bytes!("2014-02-14 09:30:00Z")).is_none());
+        assert!(Line::parse(bytes!("PING :x")).unwrap().server_time().is_none());
     }
 }
@@ -0,0 +1,348 @@
+//! Dispatching of connection events to registered handlers
+//!
+//! A Dispatcher can be used in place of a single monolithic callback:
+//!
+//!     let mut dispatcher = Dispatcher::new();
+//!     dispatcher.register_fn(OnCmd(~"PRIVMSG"), 0, handle_privmsg);
+//!     irc::conn::connect(opts, |conn, event| { dispatcher.dispatch(conn, &event); })
+
+use conn;
+use conn::{Conn, Event, Line, LineReceived, IRCCmd, IRCCode, IRCAction, IRCCTCP, IRCCTCPReply};
+use CaseMapAscii;
+
+/// Filters select which events a handler is invoked for
+pub enum Filter {
+    /// Matches every event
+    OnAny,
+    /// Matches the Connected event
+    OnConnect,
+    /// Matches the Disconnected event
+    OnDisconnect,
+    /// Matches TimerFired events
+    OnTimer,
+    /// Matches the NickFallback event
+    OnNickFallback,
+    /// Matches NetSplit events
+    OnNetSplit,
+    /// Matches NetJoin events
+    OnNetJoin,
+    /// Matches JoinFailed events
+    OnJoinFailed,
+    /// Matches MessageSent events
+    OnMessageSent,
+    /// Matches lines with the given command, e.g. "PRIVMSG". Case-insensitive.
+    OnCmd(~str),
+    /// Matches lines with the given numeric code
+    OnCode(uint),
+    /// Matches CTCP actions
+    OnAction,
+    /// Matches CTCP commands of the given type, e.g. "VERSION". Case-insensitive.
+    OnCTCP(~[u8]),
+    /// Matches CTCP replies of the given type. Case-insensitive.
+    OnCTCPReply(~[u8]),
+    /// Matches lines for which the predicate returns `true`
+    OnPredicate(fn(&Line) -> bool)
+}
+
+impl Filter {
+    /// Returns `true` if the filter matches the given event
+    pub fn matches(&self, event: &Event) -> bool {
+        match (self, event) {
+            (&OnAny, _) => true,
+            (&OnConnect, &conn::Connected) => true,
+            (&OnDisconnect, &conn::Disconnected) => true,
+            (&OnTimer, &conn::TimerFired(_)) => true,
+            (&OnNickFallback, &conn::NickFallback(..)) => true,
+            (&OnNetSplit, &conn::NetSplit(_)) => true,
+            (&OnNetJoin, &conn::NetJoin(_)) => true,
+            (&OnJoinFailed, &conn::JoinFailed(..)) => true,
+            (&OnMessageSent, &conn::MessageSent(_)) => true,
+            (_, &LineReceived(ref line)) => self.matches_line(line),
+            _ => false
+        }
+    }
+
+    /// Returns `true` if the filter matches the given line
+    pub fn matches_line(&self, line: &Line) -> bool {
+        // command and CTCP names are ASCII whatever the server's casemapping is
+        match (self, &line.command) {
+            (&OnAny, _) => true,
+            (&OnCmd(ref a), &IRCCmd(ref b)) => CaseMapAscii.equiv(a.as_bytes(), b.as_bytes()),
+            (&OnCode(a), &IRCCode(b)) => a == b,
+            (&OnAction, &IRCAction(_)) => true,
+            (&OnCTCP(ref a), &IRCCTCP(ref b, _)) => CaseMapAscii.equiv(*a, *b),
+            (&OnCTCPReply(ref a), &IRCCTCPReply(ref b, _)) => CaseMapAscii.equiv(*a, *b),
+            (&OnPredicate(f), _) => f(line),
+            _ => false
+        }
+    }
+}
+
+/// Returned by handlers to indicate whether the event should be passed on
+/// to lower-priority handlers.
+#[deriving(Eq)]
+pub enum Propagation {
+    /// Continue dispatching the event
+    Continue,
+    /// The event has been consumed. No further handlers will see it.
+    Consume
+}
+
+/// A handler that can be registered with a Dispatcher
+pub trait Handler {
+    /// Handles an event that matched the filter the handler was registered with
+    fn handle(&mut self, conn: &mut Conn, event: &Event) -> Propagation;
+}
+
+/// A Handler that wraps a plain function
+pub struct FnHandler(fn(&mut Conn, &Event) -> Propagation);
+
+impl Handler for FnHandler {
+    fn handle(&mut self, conn: &mut Conn, event: &Event) -> Propagation {
+        let FnHandler(f) = *self;
+        f(conn, event)
+    }
+}
+
+/// Identifies a registered handler
+#[deriving(Eq,Clone)]
+pub struct HandlerId(uint);
+
+struct Entry {
+    id: HandlerId,
+    filter: Filter,
+    priority: int,
+    handler: ~Handler
+}
+
+/// Dispatcher delivers events to every registered handler whose filter matches,
+/// in order of priority.
+pub struct Dispatcher {
+    priv entries: ~[Entry],
+    priv next_id: uint
+}
+
+impl Dispatcher {
+    /// Returns a new Dispatcher with no handlers
+    pub fn new() -> Dispatcher {
+        Dispatcher {
+            entries: ~[],
+            next_id: 0
+        }
+    }
+
+    /// Registers a handler for events matching the filter.
+    ///
+    /// Handlers with a higher priority are run first. Handlers with equal priority
+    /// are run in the order they were registered.
+    pub fn register(&mut self, filter: Filter, priority: int, handler: ~Handler) -> HandlerId {
+        let id = HandlerId(self.next_id);
+        self.next_id += 1;
+        let idx = self.entries.iter().position(|e| e.priority < priority)
+                              .unwrap_or(self.entries.len());
+        self.entries.insert(idx, Entry{
+            id: id,
+            filter: filter,
+            priority: priority,
+            handler: handler
+        });
+        id
+    }
+
+    /// Registers a plain function as a handler.
+    /// See register() for details.
+    pub fn register_fn(&mut self, filter: Filter, priority: int,
+                       f: fn(&mut Conn, &Event) -> Propagation) -> HandlerId {
+        self.register(filter, priority, ~FnHandler(f) as ~Handler)
+    }
+
+    /// Removes a previously-registered handler.
+    /// Returns `false` if the handler was not found.
+    pub fn unregister(&mut self, id: HandlerId) -> bool {
+        match self.entries.iter().position(|e| e.id == id) {
+            None => false,
+            Some(idx) => {
+                self.entries.remove(idx);
+                true
+            }
+        }
+    }
+
+    /// Delivers the event to all matching handlers.
+    /// Returns `true` if a handler consumed the event.
+    pub fn dispatch(&mut self, conn: &mut Conn, event: &Event) -> bool {
+        for entry in self.entries.mut_iter() {
+            if entry.filter.matches(event) {
+                if entry.handler.handle(conn, event) == Consume {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dispatcher, Handler, Propagation, Continue, Consume};
+    use super::{OnCmd, OnCode, OnCTCP, OnAction, OnPredicate, OnConnect, OnTimer};
+    use super::{OnNickFallback, OnNetSplit, OnNetJoin, OnJoinFailed, OnMessageSent};
+    use conn;
+    use conn::{Conn, Event, Options, Line, LineReceived, IRCCmd};
+    use netsplit::Split;
+    use mock;
+    use mock::{MockServer, Expect, ExpectEventually, Close};
+    use std::comm;
+
+    fn has_args(line: &Line) -> bool {
+        !line.args.is_empty()
+    }
+
+    fn says_stop(line: &Line) -> bool {
+        line.args.len() > 1 && line.args[1] == bytes!("stop").to_owned()
+    }
+
+    // Reports its name each time it handles an event
+    struct Record {
+        name: &'static str,
+        result: Propagation,
+        chan: Chan<&'static str>
+    }
+
+    impl Handler for Record {
+        fn handle(&mut self, _: &mut Conn, _: &Event) -> Propagation {
+            self.chan.send(self.name);
+            self.result
+        }
+    }
+
+    fn record(name: &'static str, result: Propagation, chan: &Chan<&'static str>) -> ~Handler {
+        ~Record{ name: name, result: result, chan: chan.clone() } as ~Handler
+    }
+
+    fn recorded(port: &Port<&'static str>) -> ~[&'static str] {
+        let mut names = ~[];
+        loop {
+            match port.try_recv() {
+                comm::Data(name) => names.push(name),
+                _ => return names
+            }
+        }
+    }
+
+    // Dispatches every event from a connection to a MockServer that sends the lines.
+    // Returns whether each PRIVMSG was consumed.
+    fn dispatch_lines(dispatcher: &mut Dispatcher, lines: &[&str]) -> ~[bool] {
+        let mut script = ~[
+            ExpectEventually(~"USER *"),
+            mock::Send(~":irc.test 001 ircnick :Welcome")
+        ];
+        for &line in lines.iter() {
+            script.push(mock::Send(line.to_owned()));
+        }
+        script.push_all([mock::Send(~"PING :done"), Expect(~"PONG done"), Close]);
+        let server = MockServer::start(script).unwrap();
+
+        let mut consumed = ~[];
+        let opts = Options::new("127.0.0.1", server.port());
+        let res = conn::connect(opts, |conn, event| {
+            let handled = dispatcher.dispatch(conn, &event);
+            match event {
+                LineReceived(ref line) if line.command == IRCCmd(~"PRIVMSG") => {
+                    consumed.push(handled);
+                }
+                _ => ()
+            }
+        });
+        assert!(res.is_ok());
+        server.finish().assert_ok();
+        consumed
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let line = Line::parse(bytes!(":bob!user@host PRIVMSG #chan :hi")).unwrap();
+        assert!(OnCmd(~"PRIVMSG").matches_line(&line));
+        assert!(OnCmd(~"privmsg").matches_line(&line));
+        assert!(!OnCmd(~"NOTICE").matches_line(&line));
+        assert!(!OnCode(1).matches_line(&line));
+        assert!(OnPredicate(has_args).matches_line(&line));
+        assert!(OnMessageSent.matches(&conn::MessageSent(line.clone())));
+        assert!(!OnMessageSent.matches(&LineReceived(line.clone())));
+        assert!(!OnCmd(~"PRIVMSG").matches(&conn::MessageSent(line)));
+
+        let line = Line::parse(bytes!(":bob PRIVMSG #chan :\x01version\x01")).unwrap();
+        assert!(OnCTCP(bytes!("VERSION").to_owned()).matches_line(&line));
+        assert!(!OnAction.matches_line(&line));
+        assert!(!OnPredicate(has_args).matches_line(&line));
+
+        let line = Line::parse(bytes!(":server 001 nick :Welcome")).unwrap();
+        assert!(OnCode(1).matches(&LineReceived(line)));
+        assert!(OnConnect.matches(&conn::Connected));
+        assert!(!OnConnect.matches(&conn::Disconnected));
+        assert!(OnTimer.matches(&conn::TimerFired(conn::TimerId(0))));
+        assert!(!OnTimer.matches(&conn::Connected));
+
+        let fallback = conn::NickFallback(bytes!("nick").to_owned(), bytes!("nick_").to_owned());
+        assert!(OnNickFallback.matches(&fallback));
+        assert!(!OnConnect.matches(&fallback));
+        let split = Split{ server1: ~[], server2: ~[], users: ~[], channels: ~[], time: 0 };
+        assert!(OnNetSplit.matches(&conn::NetSplit(split.clone())));
+        assert!(!OnNetSplit.matches(&conn::NetJoin(split.clone())));
+        assert!(OnNetJoin.matches(&conn::NetJoin(split)));
+        let failed = conn::JoinFailed(bytes!("#chan").to_owned(), conn::Banned);
+        assert!(OnJoinFailed.matches(&failed));
+        assert!(!OnNickFallback.matches(&failed));
+    }
+
+    #[test]
+    fn test_priority() {
+        let (port, chan) = Chan::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(OnCmd(~"PRIVMSG"), 0, record("b", Continue, &chan));
+        dispatcher.register(OnCmd(~"PRIVMSG"), -5, record("d", Continue, &chan));
+        dispatcher.register(OnCmd(~"PRIVMSG"), 5, record("a", Continue, &chan));
+        // equal priorities run in the order they were registered
+        dispatcher.register(OnCmd(~"PRIVMSG"), 0, record("c", Continue, &chan));
+        dispatcher.register(OnCmd(~"NOTICE"), 10, record("notice", Continue, &chan));
+
+        let consumed = dispatch_lines(&mut dispatcher, [":bob!b@host PRIVMSG #chan :hi"]);
+        assert_eq!(consumed, ~[false]);
+        assert_eq!(recorded(&port), ~["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn test_consume() {
+        let (port, chan) = Chan::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(OnCmd(~"PRIVMSG"), 0, record("next", Continue, &chan));
+        dispatcher.register(OnPredicate(says_stop), 5, record("stop", Consume, &chan));
+        dispatcher.register(OnCmd(~"PRIVMSG"), -5, record("last", Continue, &chan));
+
+        let consumed = dispatch_lines(&mut dispatcher, [":bob!b@host PRIVMSG #chan :go",
+                                                        ":bob!b@host PRIVMSG #chan :stop"]);
+        assert_eq!(consumed, ~[false, true]);
+        // nothing after the consuming handler sees the second line
+        assert_eq!(recorded(&port), ~["next", "last", "stop"]);
+    }
+
+    #[test]
+    fn test_unregister() {
+        let (port, chan) = Chan::new();
+        let mut dispatcher = Dispatcher::new();
+        let one = dispatcher.register(OnCmd(~"PRIVMSG"), 0, record("one", Consume, &chan));
+        let two = dispatcher.register(OnCmd(~"PRIVMSG"), 0, record("two", Continue, &chan));
+        assert!(one != two);
+        assert!(dispatcher.unregister(one));
+        assert!(!dispatcher.unregister(one));
+
+        let consumed = dispatch_lines(&mut dispatcher, [":bob!b@host PRIVMSG #chan :hi"]);
+        assert_eq!(consumed, ~[false]);
+        assert_eq!(recorded(&port), ~["two"]);
+
+        assert!(dispatcher.unregister(two));
+        let consumed = dispatch_lines(&mut dispatcher, [":bob!b@host PRIVMSG #chan :hi"]);
+        assert_eq!(consumed, ~[false]);
+        assert!(recorded(&port).is_empty());
+    }
+}
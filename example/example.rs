@@ -1,12 +1,14 @@
 /*! Example IRC bot
 
     This sample bot uses the library to connect to Freenode, under the nickname "rustirclib###"
-    (where ### is a random number). It then joins the channel ##rustirclib, says hello, and prints
-    to standard output any messages sent to the channel.
+    (where ### is a random number). It automatically joins the channel ##rustirclib, says hello,
+    and prints to standard output any messages sent to the channel.
 
-    Any incoming message of the form "rustirclib###: some message" will elicit a generic response.
+    The bot answers the commands "!hello" and "!quit", which can also be given by addressing
+    the bot ("rustirclib###: hello") or in a private message. "!help" lists the commands.
 
-    Any incoming message of the form "!rustirclib### quit" will cause the bot to shut down.
+    Only the bot's owner may use "!quit". Pass the owner's hostmask on the command line,
+    e.g. `ircbot 'me!*@my.host'`. Without one, nobody can make the bot quit.
  */
 
 #[crate_id = "github.com/kballard/rust-irclib#ircbot:0.1"];
@@ -15,8 +17,10 @@
 extern crate irc;
 
 use irc::conn::{Conn, Line, Event, IRCCmd, IRCCode, IRCAction};
+use irc::bot::{Bot, Context};
+use irc::acl::{Acl, ByMask};
 
-use std::{rand, str};
+use std::{os, rand, str};
 use std::rand::Rng;
 
 fn main() {
@@ -24,7 +28,27 @@ fn main() {
 
     let nick = format!("rustirclib{}", rand::task_rng().gen_range(100u, 1000u));
     opts.nick = nick.as_slice();
-    match irc::conn::connect(opts, handler) {
+    opts.autojoin = &[("##rustirclib", "")];
+
+    let mut bot = Bot::new();
+    bot.register_fn("hello", "hello - says hello", hello);
+    bot.register_fn("quit", "quit - shuts down the bot", quit);
+    bot.alias("hi", "hello");
+
+    let mut acl = Acl::new();
+    acl.add_role("owner", ["*"]);
+    acl.require("quit", "owner");
+    let args = os::args();
+    if args.len() > 1 {
+        acl.grant(ByMask(args[1].as_bytes().to_owned()), "owner");
+    }
+    bot.acl = Some(acl);
+
+    match irc::conn::connect(opts, |conn, event| {
+        if !bot.handle_event(conn, &event) {
+            handler(conn, event)
+        }
+    }) {
         Ok(()) => println!("Exiting..."),
         Err(err) => println!("Connection error: {}", err)
     }
@@ -37,16 +61,16 @@ fn handler(conn: &mut Conn, event: Event) {
         irc::conn::LineReceived(line) => {
             match line {
                 Line{command: IRCCode(1), ..} => {
+                    // we've logged in. The channel is joined automatically.
                     println!("Logged in");
-                    // we've logged in
-                    conn.join(bytes!("##rustirclib"), [])
                 }
-                Line{command: IRCCmd(~"JOIN"), args, prefix: Some(prefix) } => {
+                Line{command: IRCCmd(~"JOIN"), args, prefix: Some(prefix), .. } => {
                     if prefix.nick() != conn.me().nick() {
                         return;
                     }
                     if args.is_empty() {
-                        let line = Line{command: IRCCmd(~"JOIN"), args: args, prefix: Some(prefix)};
+                        let line = Line{command: IRCCmd(~"JOIN"), args: args, prefix: Some(prefix),
+                                        tags: ~[]};
                         println!("ERROR: Invalid JOIN message received: {}", line_desc(&line));
                         return;
                     }
@@ -55,15 +79,16 @@ fn handler(conn: &mut Conn, event: Event) {
                     let chan = str::from_utf8(chan).unwrap_or("(invalid utf8)");
                     println!("JOINED: {}", chan);
                 }
-                Line{command: IRCCmd(cmd@~"PRIVMSG"), args, prefix } |
-                Line{command: IRCCmd(cmd@~"NOTICE"), args, prefix } => {
+                Line{command: IRCCmd(cmd@~"PRIVMSG"), args, prefix, .. } |
+                Line{command: IRCCmd(cmd@~"NOTICE"), args, prefix, .. } => {
                     let (src, dst, msg) = match (args, prefix.is_some()) {
                         ([dst, msg], true) => {
                             (prefix.as_ref().unwrap().nick(), dst, msg)
                         }
                         (args, _) => {
                             print!("ERROR: Unexpected {} line: ", cmd);
-                            let line = Line{command: IRCCmd(cmd), args: args, prefix: prefix};
+                            let line = Line{command: IRCCmd(cmd), args: args, prefix: prefix,
+                                            tags: ~[]};
                             println!("{}", line_desc(&line));
                             return;
                         }
@@ -72,15 +97,15 @@ fn handler(conn: &mut Conn, event: Event) {
                     let srcs = str::from_utf8(src).unwrap_or("(invalid utf8)");
                     let msgs = str::from_utf8(msg).unwrap_or("(invalid utf8)");
                     println!("<-- {}({}) {}: {}", cmd, dsts, srcs, msgs);
-                    handle_privmsg(conn, msg, src, dst)
                 }
-                Line{command: IRCAction(dst), args, prefix } => {
+                Line{command: IRCAction(dst), args, prefix, .. } => {
                     let (src, msg) = match (args, prefix.is_some()) {
                         ([msg], true) => {
                             (prefix.as_ref().unwrap().nick(), msg)
                         }
                         (args, _) => {
-                            let line = Line{command: IRCAction(dst), args: args, prefix: prefix};
+                            let line = Line{command: IRCAction(dst), args: args, prefix: prefix,
+                                            tags: ~[]};
                             println!("ERROR: Unexpected ACTION line: {}", line_desc(&line));
                             return;
                         }
@@ -93,48 +118,21 @@ fn handler(conn: &mut Conn, event: Event) {
                 _ => ()
             }
         }
+        _ => ()
     }
 }
 
-fn handle_privmsg(conn: &mut Conn, msg: &[u8], src: &[u8], dst: &[u8]) {
-    enum MsgType<'a> {
-        DirectedMessage,
-        CommandMessage(&'a [u8]),
-        NormalMessage
-    }
-    let msgtype = {
-        let nick = conn.me().nick();
-        if msg.starts_with(nick) && msg.slice_from(nick.len()).starts_with(bytes!(": ")) &&
-        msg.len() > nick.len() + 2 {
-            DirectedMessage
-        } else if msg.starts_with(bytes!("!")) && msg.slice_from(1).starts_with(nick) &&
-                msg.slice_from(nick.len()+1).starts_with(bytes!(" ")) {
-            let args = msg.slice_from(conn.me().nick().len()+2);
-            let mut argit = args.split(|&b| b == ' ' as u8).skip_while(|v| v.is_empty());
-            match argit.next() {
-                Some(arg) => CommandMessage(arg),
-                None => CommandMessage(bytes!(""))
-            }
-        } else {
-            NormalMessage
-        }
-    };
-    match msgtype {
-        DirectedMessage => {
-            let reply = if dst == conn.me().nick() { src } else { dst };
-            let msg = src + bytes!(": Hello");
-            conn.privmsg(reply, msg);
-            let src = str::from_utf8(conn.me().nick()).unwrap_or("(invalid utf8)");
-            let reply = str::from_utf8(reply).unwrap_or("(invalid utf8)");
-            let msg = str::from_utf8(msg).unwrap_or("(invalid utf8)");
-            println!("--> PRIVMSG({}) {}: {}", reply, src, msg);
-        }
-        CommandMessage(cmd) if cmd == bytes!("quit") => {
-            println!("Quitting...");
-            conn.quit([]);
-        }
-        _ => ()
-    }
+fn hello(ctx: &mut Context) {
+    let msg = ctx.sender.nick() + bytes!(": Hello");
+    ctx.reply(msg);
+    let reply = str::from_utf8(ctx.reply_to).unwrap_or("(invalid utf8)");
+    let msg = str::from_utf8(msg).unwrap_or("(invalid utf8)");
+    println!("--> PRIVMSG({}) {}", reply, msg);
+}
+
+fn quit(ctx: &mut Context) {
+    println!("Quitting...");
+    ctx.conn.quit([]);
 }
 
 fn line_desc(line: &Line) -> ~str {
@@ -1,8 +1,23 @@
 //! Built-in IRC message handlers
 
-use conn::{IRCCode, IRCCmd, Conn, Line};
+use conn::{IRCCode, IRCCmd, IRCCTCP, Conn, Line};
+use conn::{ChannelIsFull, InviteOnly, Banned, BadKey, NeedRegistered};
 
 pub fn handle_line(conn: &mut Conn, line: &Line) {
+    // the state needs to see NICK lines before we update our own nick
+    let me = conn.user.nick().to_owned();
+    conn.state.handle_line(me, line);
+
+    // the server echoing one of our own lines back tells us our real user@host
+    match line.prefix {
+        Some(ref user) if user.host().is_some() && user.nick() == conn.user.nick() => {
+            if user.raw() != conn.user.raw() {
+                conn.user = user.clone();
+            }
+        }
+        _ => ()
+    }
+
     if !conn.logged_in {
         match line.command {
             IRCCode(001) => handshake::RPL_WELCOME(conn, line),
@@ -11,19 +26,58 @@ pub fn handle_line(conn: &mut Conn, line: &Line) {
             IRCCode(436) => handshake::ERR_NICKCOLLISION(conn, line),
             IRCCode(437) => handshake::ERR_UNAVAILRESOURCE(conn, line),
             IRCCmd(~"PING") => normal::PING(conn, line),
+            IRCCmd(~"CAP") => cap::CAP(conn, line),
+            IRCCmd(~"AUTHENTICATE") => cap::AUTHENTICATE(conn, line),
+            IRCCode(902) | IRCCode(903) | IRCCode(904) | IRCCode(905) | IRCCode(906) |
+            IRCCode(907) => cap::sasl_done(conn, line),
             _ => ()
         }
     } else {
         match line.command {
-            IRCCmd(~"PING") => normal::PING(conn, line),
-            IRCCmd(~"NICK") => normal::NICK(conn, line),
+            IRCCmd(~"PING") => {
+                normal::PING(conn, line);
+                regain::PING(conn, line);
+            }
+            IRCCmd(~"NICK") => {
+                regain::NICK(conn, line);
+                normal::NICK(conn, line);
+            }
+            IRCCmd(~"QUIT") => regain::QUIT(conn, line),
+            IRCCmd(~"JOIN") => normal::JOIN(conn, line),
+            IRCCmd(~"PART") => normal::PART(conn, line),
+            IRCCmd(~"KICK") => normal::KICK(conn, line),
+            IRCCode(471) => normal::join_failed(conn, line, ChannelIsFull),
+            IRCCode(473) => normal::join_failed(conn, line, InviteOnly),
+            IRCCode(474) => normal::join_failed(conn, line, Banned),
+            IRCCode(475) => normal::join_failed(conn, line, BadKey),
+            IRCCode(477) => normal::join_failed(conn, line, NeedRegistered),
+            IRCCmd(~"CAP") => cap::CAP(conn, line),
+            IRCCode(005) => {
+                normal::RPL_ISUPPORT(conn, line);
+                regain::RPL_ISUPPORT(conn, line);
+            }
+            IRCCmd(~"MODE") => normal::MODE(conn, line),
+            IRCCode(376) | IRCCode(422) => normal::perform(conn),
+            IRCCode(221) => normal::RPL_UMODEIS(conn, line),
+            IRCCode(305) => normal::RPL_UNAWAY(conn, line),
+            IRCCode(306) => normal::RPL_NOWAWAY(conn, line),
+            IRCCode(381) => normal::RPL_YOUREOPER(conn, line),
+            IRCCode(396) => normal::RPL_HOSTHIDDEN(conn, line),
+            IRCCTCP(..) => normal::CTCP(conn, line),
+            IRCCode(303) => regain::RPL_ISON(conn, line),
+            IRCCode(731) => regain::RPL_MONOFFLINE(conn, line),
             _ => ()
         }
     }
 }
 
 mod handshake {
-    use conn::{Conn, Line};
+    use User;
+    use std::cmp::min;
+    use std::{rand,vec};
+    use std::rand::Rng;
+    use conn::{Conn, Line, NickFallback};
+    use conn::{NickStrategy, AppendUnderscore, AppendDigits, RandomSuffix, NickGenerator};
 
     // 001
     pub fn RPL_WELCOME(conn: &mut Conn, line: &Line) {
@@ -31,67 +85,346 @@ mod handshake {
         if !line.args.is_empty() {
             conn.user = conn.user.with_nick(line.args[0]);
         }
+        // most servers end the welcome text with our full nick!user@host
+        if line.args.len() > 1 {
+            match line.args[1].rsplit(|&b| b == ' ' as u8).next() {
+                Some(mask) => {
+                    let user = User::parse(mask);
+                    if user.nick() == conn.user.nick() && user.host().is_some() {
+                        conn.user = user;
+                    }
+                }
+                None => ()
+            }
+        }
+        if conn.user.nick() != conn.nick.requested.as_slice() {
+            let (requested, actual) = (conn.nick.requested.clone(), conn.user.nick().to_owned());
+            conn.events.push(NickFallback(requested, actual));
+            super::regain::start(conn);
+        }
+        if !conn.wait_for_motd {
+            super::normal::perform(conn);
+        }
     }
 
     // 433
     pub fn ERR_NICKNAMEINUSE(conn: &mut Conn, line: &Line) {
-        if !line.args.is_empty() {
-            let nick = line.args[0].as_slice();
-            if nick == conn.user.nick() {
-                conn.set_nick(nick + bytes!("_"));
-                return;
-            }
-        }
-        // nick was truncated? Fall back to generic _-replacement behavior
-        bad_nick(conn, line);
+        learn_nicklen(conn, line);
+        next_nick(conn);
     }
 
     // 432
     pub fn ERR_ERRONEUSNICKNAME(conn: &mut Conn, line: &Line) {
-        bad_nick(conn, line);
+        learn_nicklen(conn, line);
+        // the nick may have been rejected for being too long. Servers that don't truncate
+        // long nicks don't tell us NICKLEN until after registration, so assume the
+        // RFC 2812 limit.
+        if conn.user.nick().len() > DefaultNickLen && conn.nick.maxlen.is_none() {
+            conn.nick.maxlen = Some(DefaultNickLen);
+        }
+        next_nick(conn);
     }
 
     // 436
     pub fn ERR_NICKCOLLISION(conn: &mut Conn, line: &Line) {
-        bad_nick(conn, line);
+        learn_nicklen(conn, line);
+        next_nick(conn);
     }
 
     // 437
     pub fn ERR_UNAVAILRESOURCE(conn: &mut Conn, line: &Line) {
-        bad_nick(conn, line);
+        learn_nicklen(conn, line);
+        next_nick(conn);
     }
 
-    fn bad_nick(conn: &mut Conn, line: &Line) {
-        let mut nick;
-        if !line.args.is_empty() {
-            nick = line.args[0].clone();
+    // give up after this many generated nicks
+    static MaxNickAttempts: uint = 10;
+
+    // the maximum nick length in RFC 2812
+    static DefaultNickLen: uint = 9;
+
+    // The server echoes the nick it rejected, as `me nick :reason`. If that's a truncated
+    // copy of the nick we sent, the server cut it to its NICKLEN.
+    fn learn_nicklen(conn: &mut Conn, line: &Line) {
+        if line.args.len() < 2 {
+            return;
+        }
+        let echoed = line.args[line.args.len()-2].as_slice();
+        let truncated = {
+            let sent = conn.user.nick();
+            !echoed.is_empty() && echoed.len() < sent.len() && sent.starts_with(echoed)
+        };
+        if truncated {
+            let len = conn.nick.maxlen.map_or(echoed.len(), |n| min(n, echoed.len()));
+            conn.nick.maxlen = Some(len);
+        }
+    }
+
+    fn next_nick(conn: &mut Conn) {
+        let nicklen = match (conn.nicklen(), conn.nick.maxlen) {
+            (Some(a), Some(b)) => Some(min(a, b)),
+            (a, b) => a.or(b)
+        };
+        let nick = if conn.nick.next_alt < conn.nick.alts.len() {
+            conn.nick.next_alt += 1;
+            let nick = conn.nick.alts[conn.nick.next_alt-1].as_slice();
+            Some(nick.slice_to(min(nick.len(), nicklen.unwrap_or(nick.len()))).to_owned())
+        } else if conn.nick.attempts < MaxNickAttempts {
+            conn.nick.attempts += 1;
+            generate_nick(conn.nick.strategy, conn.nick.requested.as_slice(), conn.nick.attempts,
+                          nicklen)
         } else {
-            nick = conn.user.nick().to_owned();
+            None
+        };
+        match nick {
+            Some(nick) => conn.set_nick(nick),
+            None => conn.quit([])
         }
+    }
 
-        let mut modified = false;
-        for b in nick.mut_rev_iter() {
-            if *b != '_' as u8 {
-                *b = '_' as u8;
-                modified = true;
-                break;
+    fn generate_nick(strategy: NickStrategy, nick: &[u8], attempt: uint,
+                     nicklen: Option<uint>) -> Option<~[u8]> {
+        let suffix = match strategy {
+            AppendUnderscore => vec::from_elem(attempt, '_' as u8),
+            AppendDigits => attempt.to_str().into_bytes(),
+            RandomSuffix(len) => {
+                let mut rng = rand::task_rng();
+                vec::from_fn(len, |_| rng.gen_range('0' as u8, '9' as u8 + 1))
             }
+            NickGenerator(f) => {
+                return f(nick, attempt).map(|n| {
+                    let len = min(n.len(), nicklen.unwrap_or(n.len()));
+                    n.slice_to(len).to_owned()
+                })
+            }
+        };
+        // truncate the nick if necessary to make room for the suffix
+        let len = match nicklen {
+            None => nick.len(),
+            Some(nicklen) if nicklen > suffix.len() => min(nick.len(), nicklen - suffix.len()),
+            Some(_) => return None
+        };
+        Some(nick.slice_to(len) + suffix)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::generate_nick;
+        use conn;
+        use conn::{Options, NickFallback, AppendUnderscore, AppendDigits, RandomSuffix};
+        use mock;
+        use mock::{MockServer, Expect, ExpectEventually, Close};
+
+        #[test]
+        fn test_generate_nick() {
+            assert_eq!(generate_nick(AppendUnderscore, bytes!("nick"), 2, None),
+                       Some(bytes!("nick__").to_owned()));
+            assert_eq!(generate_nick(AppendUnderscore, bytes!("nickname"), 1, Some(8)),
+                       Some(bytes!("nicknam_").to_owned()));
+            assert_eq!(generate_nick(AppendDigits, bytes!("nickname"), 12, Some(9)),
+                       Some(bytes!("nicknam12").to_owned()));
+            assert_eq!(generate_nick(AppendDigits, bytes!("nick"), 3, Some(1)), None);
+            let nick = generate_nick(RandomSuffix(3), bytes!("nick"), 1, None).unwrap();
+            assert_eq!(nick.len(), 7);
+            assert!(nick.starts_with(bytes!("nick")));
         }
-        if modified {
-            conn.set_nick(nick);
-        } else {
-            conn.quit([]);
+
+        // registers as `nick`, has the server reject it with `reply`,
+        // and checks that we retry as `retry`
+        fn check_retry(nick: &str, reply: ~str, retry: ~str) {
+            let server = MockServer::start(~[
+                ExpectEventually(~"USER *"),
+                mock::Send(reply),
+                Expect(format!("NICK {}", retry)),
+                mock::Send(format!(":irc.test 001 {} :Welcome", retry)),
+                Close
+            ]).unwrap();
+            let mut opts = Options::new("127.0.0.1", server.port());
+            opts.nick = nick;
+            let mut fallback = None;
+            conn::connect(opts, |_, event| {
+                match event {
+                    NickFallback(_, actual) => fallback = Some(actual),
+                    _ => ()
+                }
+            });
+            assert_eq!(fallback, Some(retry.into_bytes()));
+            server.finish().assert_ok();
+        }
+
+        #[test]
+        fn test_long_nick() {
+            // the server truncated the nick to its NICKLEN
+            check_retry("averylongnickname", ~":irc.test 433 * averylongn :Nickname is in use",
+                        ~"averylong_");
+            // the server rejected the nick outright
+            check_retry("averylongnickname",
+                        ~":irc.test 432 * averylongnickname :Erroneous nickname",
+                        ~"averylon_");
+            check_retry("nick", ~":irc.test 433 * nick :Nickname is in use", ~"nick_");
         }
     }
 }
 
 mod normal {
-    use conn::{IRCCmd, Conn, Line};
+    use {User, CaseMapAscii};
+    use conn::{IRCCmd, IRCCTCP, IRCCTCPReply, Conn, Line, JoinError, JoinFailed};
+
+    // 001, or 376/422 if waiting for the end of the MOTD.
+    // Sends the perform commands and joins the autojoin channels.
+    pub fn perform(conn: &mut Conn) {
+        if conn.performed {
+            return;
+        }
+        conn.performed = true;
+        let perform = ::std::mem::replace(&mut conn.perform, ~[]);
+        for line in perform.iter() {
+            conn.send_raw(*line);
+        }
+        let channels = conn.channels.clone();
+        for &(ref chan, ref key) in channels.iter() {
+            conn.join(*chan, *key);
+        }
+    }
+
+    pub fn PART(conn: &mut Conn, line: &Line) {
+        match line.prefix {
+            Some(ref user) if user.nick() == conn.user.nick() && !line.args.is_empty() => {
+                conn.forget_channel(line.args[0]);
+            }
+            _ => ()
+        }
+    }
+
+    pub fn KICK(conn: &mut Conn, line: &Line) {
+        // chan victim [:reason]
+        if line.args.len() < 2 ||
+           !conn.state.casemapping().equiv(line.args[1].as_slice(), conn.user.nick()) {
+            return;
+        }
+        let chan = line.args[0].as_slice();
+        if !conn.schedule_rejoin(chan) {
+            conn.forget_channel(chan);
+        }
+    }
+
+    // 471, 473, 474, 475, 477
+    pub fn join_failed(conn: &mut Conn, line: &Line, err: JoinError) {
+        // me chan :reason
+        if line.args.len() < 2 {
+            return;
+        }
+        let chan = line.args[1].as_slice();
+        let casemap = conn.state.casemapping();
+        // keep retrying channels we were kicked from, but forget anything else
+        let rejoining = conn.rejoining.iter().any(|&(ref c, _)| casemap.equiv(*c, chan));
+        if !rejoining || !conn.schedule_rejoin(chan) {
+            conn.forget_channel(chan);
+        }
+        conn.events.push(JoinFailed(chan.to_owned(), err));
+    }
+
+    // 221
+    pub fn RPL_UMODEIS(conn: &mut Conn, line: &Line) {
+        if line.args.len() < 2 {
+            return;
+        }
+        conn.modes = line.args[1].iter().map(|&b| b).filter(|&b| b != '+' as u8).collect();
+        conn.oper = conn.has_mode('o') || conn.has_mode('O');
+    }
+
+    pub fn MODE(conn: &mut Conn, line: &Line) {
+        // we only care about our own user modes here
+        if line.args.len() < 2 || line.args[0].as_slice() != conn.user.nick() {
+            return;
+        }
+        let mut set = true;
+        for &b in line.args[1].iter() {
+            match b as char {
+                '+' => set = true,
+                '-' => set = false,
+                _ => {
+                    conn.modes.retain(|&m| m != b);
+                    if set {
+                        conn.modes.push(b);
+                    }
+                    if b == 'o' as u8 || b == 'O' as u8 {
+                        conn.oper = set;
+                    }
+                }
+            }
+        }
+    }
+
+    // 305
+    pub fn RPL_UNAWAY(conn: &mut Conn, _line: &Line) {
+        conn.away = false;
+    }
+
+    // 306
+    pub fn RPL_NOWAWAY(conn: &mut Conn, _line: &Line) {
+        conn.away = true;
+    }
+
+    // 381
+    pub fn RPL_YOUREOPER(conn: &mut Conn, _line: &Line) {
+        conn.oper = true;
+    }
+
+    // 396
+    pub fn RPL_HOSTHIDDEN(conn: &mut Conn, line: &Line) {
+        // me host :is now your displayed host
+        if line.args.len() < 2 {
+            return;
+        }
+        // some servers send user@host
+        let host = line.args[1].as_slice();
+        let (user, host) = match host.position_elem(&('@' as u8)) {
+            None => (conn.user.user().map(|u| u.to_owned()), host),
+            Some(idx) => (Some(host.slice_to(idx).to_owned()), host.slice_from(idx+1))
+        };
+        conn.user = User::new(conn.user.nick(), user.as_ref().map(|u| u.as_slice()), Some(host));
+    }
+
+    pub fn JOIN(conn: &mut Conn, line: &Line) {
+        let is_me = match line.prefix {
+            Some(ref user) => user.nick() == conn.user.nick(),
+            None => false
+        };
+        if !is_me || line.args.is_empty() {
+            return;
+        }
+        let chan = line.args[0].as_slice();
+        conn.remember_channel(chan, None);
+        let casemap = conn.state.casemapping();
+        conn.rejoining.retain(|&(ref c, _)| !casemap.equiv(*c, chan));
+        // ask for details on everyone in the channel, using WHOX if possible
+        // so we learn accounts too. The token 743 identifies our WHOX replies.
+        if conn.isupport(bytes!("WHOX")).is_some() {
+            conn.send_command(IRCCmd(~"WHO"), [chan, bytes!("%tcuhnfar,743")], false);
+        } else {
+            conn.send_command(IRCCmd(~"WHO"), [chan], false);
+        }
+    }
 
     pub fn PING(conn: &mut Conn, line: &Line) {
         conn.send_command(IRCCmd(~"PONG"), line.args, false);
     }
 
+    // answers CTCP requests that have a configured reply
+    pub fn CTCP(conn: &mut Conn, line: &Line) {
+        let (cmd, nick) = match (&line.command, &line.prefix) {
+            (&IRCCTCP(ref cmd, _), &Some(ref user)) => (cmd.as_slice(), user.nick()),
+            _ => return
+        };
+        let reply = conn.ctcp_replies.iter().find(|&&(ref c, _)| CaseMapAscii.equiv(*c, cmd));
+        let (cmd, reply) = match reply {
+            None => return,
+            Some(&(ref cmd, ref reply)) => (cmd.clone(), reply.clone())
+        };
+        conn.send_command(IRCCTCPReply(cmd, nick.to_owned()), [reply], false);
+    }
+
     pub fn NICK(conn: &mut Conn, line: &Line) {
         if line.args.is_empty() {
             // where's my arg?
@@ -106,4 +439,341 @@ mod normal {
             None => ()
         }
     }
+
+    // 005
+    pub fn RPL_ISUPPORT(conn: &mut Conn, line: &Line) {
+        // the first arg is our nick, and the last is the "are supported by this server" text
+        if line.args.len() < 3 {
+            return;
+        }
+        for param in line.args.slice(1, line.args.len()-1).iter() {
+            let (negated, param) = if param.starts_with(bytes!("-")) {
+                (true, param.slice_from(1))
+            } else {
+                (false, param.as_slice())
+            };
+            let (key, value) = match param.position_elem(&('=' as u8)) {
+                None => (param, &[]),
+                Some(idx) => (param.slice_to(idx), param.slice_from(idx+1))
+            };
+            conn.isupport.retain(|&(ref k, _)| k.as_slice() != key);
+            if !negated {
+                conn.isupport.push((key.to_owned(), value.to_owned()));
+            }
+        }
+    }
+}
+
+mod regain {
+    use conn::{IRCCmd, Conn, Line, NickServRegain, NickServGhost};
+
+    fn wanted(conn: &Conn) -> bool {
+        conn.nick.regain && conn.user.nick() != conn.nick.requested.as_slice()
+    }
+
+    fn reclaim(conn: &mut Conn) {
+        let nick = conn.nick.requested.clone();
+        conn.set_nick(nick);
+    }
+
+    // called when registration completes under a fallback nick
+    pub fn start(conn: &mut Conn) {
+        if !wanted(conn) {
+            return;
+        }
+        match conn.nick.password.clone() {
+            None => (),
+            Some(pass) => {
+                let cmd = match conn.nick.regain_cmd {
+                    NickServRegain => bytes!("REGAIN "),
+                    NickServGhost => bytes!("GHOST ")
+                };
+                let msg = cmd + conn.nick.requested + bytes!(" ") + pass;
+                conn.privmsg(bytes!("NickServ"), msg);
+            }
+        }
+        if conn.isupport(bytes!("MONITOR")).is_some() {
+            monitor(conn);
+        }
+    }
+
+    fn monitor(conn: &mut Conn) {
+        conn.nick.monitoring = true;
+        let nick = conn.nick.requested.clone();
+        conn.send_command(IRCCmd(~"MONITOR"), [bytes!("+"), nick.as_slice()], false);
+    }
+
+    fn done(conn: &mut Conn) {
+        if conn.nick.monitoring {
+            conn.nick.monitoring = false;
+            let nick = conn.nick.requested.clone();
+            conn.send_command(IRCCmd(~"MONITOR"), [bytes!("-"), nick.as_slice()], false);
+        }
+    }
+
+    pub fn RPL_ISUPPORT(conn: &mut Conn, _line: &Line) {
+        // ISUPPORT usually arrives after 001, so MONITOR may only be known now
+        if wanted(conn) && !conn.nick.monitoring && conn.isupport(bytes!("MONITOR")).is_some() {
+            monitor(conn);
+        }
+    }
+
+    // without MONITOR we poll with ISON every time the server pings us
+    pub fn PING(conn: &mut Conn, _line: &Line) {
+        if wanted(conn) && !conn.nick.monitoring {
+            let nick = conn.nick.requested.clone();
+            conn.send_command(IRCCmd(~"ISON"), [nick], false);
+        }
+    }
+
+    // 303
+    pub fn RPL_ISON(conn: &mut Conn, line: &Line) {
+        if !wanted(conn) || line.args.len() < 2 {
+            return;
+        }
+        let online = line.args[1].split(|&b| b == ' ' as u8)
+                                 .any(|n| n == conn.nick.requested.as_slice());
+        if !online {
+            reclaim(conn);
+        }
+    }
+
+    // 731
+    pub fn RPL_MONOFFLINE(conn: &mut Conn, line: &Line) {
+        if !wanted(conn) || line.args.len() < 2 {
+            return;
+        }
+        // targets may be nicks or full hostmasks
+        let offline = line.args[1].split(|&b| b == ',' as u8)
+                                  .any(|t| t.split(|&b| b == '!' as u8).next() ==
+                                           Some(conn.nick.requested.as_slice()));
+        if offline {
+            reclaim(conn);
+        }
+    }
+
+    pub fn QUIT(conn: &mut Conn, line: &Line) {
+        match line.prefix {
+            Some(ref user) if wanted(conn) && user.nick() == conn.nick.requested.as_slice() => {
+                reclaim(conn);
+            }
+            _ => ()
+        }
+    }
+
+    pub fn NICK(conn: &mut Conn, line: &Line) {
+        if line.args.is_empty() || !conn.nick.regain {
+            return;
+        }
+        let user = match line.prefix {
+            None => return,
+            Some(ref user) => user
+        };
+        let newnick = line.args[0].as_slice();
+        if user.nick() == conn.user.nick() {
+            // our own nick change. Stop watching if we've got the nick back.
+            if newnick == conn.nick.requested.as_slice() {
+                done(conn);
+            }
+        } else if wanted(conn) && user.nick() == conn.nick.requested.as_slice() {
+            reclaim(conn);
+        }
+    }
+}
+
+mod cap {
+    use conn::{IRCCmd, Conn, Line};
+    use serialize::base64::{ToBase64, STANDARD};
+
+    // capabilities we know how to make use of
+    static Wanted: &'static [&'static str] = &["multi-prefix", "extended-join", "account-notify",
+                                               "away-notify", "userhost-in-names", "chghost",
+                                               "server-time"];
+
+    fn end(conn: &mut Conn) {
+        if !conn.logged_in {
+            conn.send_command(IRCCmd(~"CAP"), [bytes!("END")], false);
+        }
+    }
+
+    pub fn CAP(conn: &mut Conn, line: &Line) {
+        // target subcommand [*] :caps
+        if line.args.len() < 3 {
+            return;
+        }
+        let caps = line.args.last().unwrap().split(|&b| b == ' ' as u8).filter(|c| !c.is_empty());
+        match line.args[1].as_slice() {
+            b if b == bytes!("LS") => {
+                for cap in caps {
+                    // 302 LS values look like name=value
+                    let (name, value) = match cap.position_elem(&('=' as u8)) {
+                        None => (cap, None),
+                        Some(i) => (cap.slice_to(i), Some(cap.slice_from(i+1)))
+                    };
+                    let wanted = if name == bytes!("sasl") {
+                        // the value lists the mechanisms, if the server says
+                        conn.sasl.is_some() && value.map_or(true, |v| {
+                            v.split(|&b| b == ',' as u8).any(|m| m == bytes!("PLAIN"))
+                        })
+                    } else {
+                        Wanted.iter().any(|w| w.as_bytes() == name)
+                    };
+                    if wanted {
+                        conn.cap_req.push(name.to_owned());
+                    }
+                }
+                if line.args.len() > 3 && line.args[2].as_slice() == bytes!("*") {
+                    // more LS lines to come
+                    return;
+                }
+                if conn.cap_req.is_empty() {
+                    end(conn);
+                } else {
+                    let req = conn.cap_req.connect_vec(&(' ' as u8));
+                    conn.cap_req.clear();
+                    conn.send_command(IRCCmd(~"CAP"), [bytes!("REQ"), req.as_slice()], true);
+                }
+            }
+            b if b == bytes!("ACK") => {
+                let mut sasl = false;
+                for cap in caps {
+                    if cap.starts_with(bytes!("-")) {
+                        conn.caps.retain(|c| c.as_slice() != cap.slice_from(1));
+                    } else if !conn.has_cap(cap) {
+                        conn.caps.push(cap.to_owned());
+                    }
+                    sasl = sasl || cap == bytes!("sasl");
+                }
+                if sasl && conn.sasl.is_some() && !conn.logged_in {
+                    // registration ends once authentication does
+                    conn.send_command(IRCCmd(~"AUTHENTICATE"), [bytes!("PLAIN")], false);
+                } else {
+                    end(conn);
+                }
+            }
+            b if b == bytes!("NAK") => end(conn),
+            b if b == bytes!("DEL") => {
+                for cap in caps {
+                    conn.caps.retain(|c| c.as_slice() != cap);
+                }
+            }
+            _ => ()
+        }
+    }
+
+    pub fn AUTHENTICATE(conn: &mut Conn, line: &Line) {
+        if line.args.len() != 1 || line.args[0].as_slice() != bytes!("+") {
+            return;
+        }
+        let payload = match conn.sasl {
+            None => return,
+            Some((ref account, ref password)) => {
+                // authzid NUL authcid NUL password
+                let mut plain = account.clone();
+                plain.push(0);
+                plain.push_all(*account);
+                plain.push(0);
+                plain.push_all(*password);
+                plain.to_base64(STANDARD).into_bytes()
+            }
+        };
+        // the payload goes in chunks of 400 bytes, and a full last chunk is followed by "+"
+        for chunk in payload.chunks(400) {
+            conn.send_command(IRCCmd(~"AUTHENTICATE"), [chunk], false);
+        }
+        if payload.len() % 400 == 0 {
+            conn.send_command(IRCCmd(~"AUTHENTICATE"), [bytes!("+")], false);
+        }
+    }
+
+    // 902-907: authentication succeeded, failed, or was aborted. Either way we're done.
+    pub fn sasl_done(conn: &mut Conn, _line: &Line) {
+        end(conn);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use conn;
+    use conn::{Options, Sasl, Rejoin};
+    use mock;
+    use mock::{MockServer, Expect, ExpectEventually, Close};
+
+    #[test]
+    fn test_ctcp_replies() {
+        let server = MockServer::start(~[
+            ExpectEventually(~"USER *"),
+            mock::Send(~":irc.test 001 ircnick :Welcome"),
+            mock::Send(~":bob!b@host PRIVMSG ircnick :\x01version\x01"),
+            Expect(~"NOTICE bob :\x01VERSION bot 1.0\x01"),
+            // no reply configured
+            mock::Send(~":bob!b@host PRIVMSG ircnick :\x01TIME\x01"),
+            mock::Send(~"PING :done"),
+            Expect(~"PONG done"),
+            Close
+        ]).unwrap();
+
+        let mut opts = Options::new("127.0.0.1", server.port());
+        opts.ctcp_replies = &[("VERSION", "bot 1.0")];
+        assert!(conn::connect(opts, |_, _| ()).is_ok());
+        server.finish().assert_ok();
+    }
+
+    #[test]
+    fn test_sasl() {
+        let server = MockServer::start(~[
+            ExpectEventually(~"USER *"),
+            mock::Send(~":irc.test CAP * LS :multi-prefix sasl=EXTERNAL,PLAIN"),
+            Expect(~"CAP REQ :multi-prefix sasl"),
+            mock::Send(~":irc.test CAP * ACK :multi-prefix sasl"),
+            Expect(~"AUTHENTICATE PLAIN"),
+            mock::Send(~"AUTHENTICATE +"),
+            Expect(~"AUTHENTICATE Ym90AGJvdABodW50ZXIy"),
+            mock::Send(~":irc.test 900 ircnick ircnick!u@h bot :You are now logged in as bot"),
+            mock::Send(~":irc.test 903 ircnick :SASL authentication successful"),
+            Expect(~"CAP END"),
+            Close
+        ]).unwrap();
+
+        let mut opts = Options::new("127.0.0.1", server.port());
+        opts.sasl = Some(Sasl{ account: "bot", password: "hunter2" });
+        assert!(conn::connect(opts, |_, _| ()).is_ok());
+        server.finish().assert_ok();
+    }
+
+    #[test]
+    fn test_sasl_unsupported() {
+        let server = MockServer::start(~[
+            ExpectEventually(~"USER *"),
+            // only EXTERNAL is offered
+            mock::Send(~":irc.test CAP * LS :sasl=EXTERNAL"),
+            Expect(~"CAP END"),
+            Close
+        ]).unwrap();
+
+        let mut opts = Options::new("127.0.0.1", server.port());
+        opts.sasl = Some(Sasl{ account: "bot", password: "hunter2" });
+        assert!(conn::connect(opts, |_, _| ()).is_ok());
+        server.finish().assert_ok();
+    }
+
+    #[test]
+    fn test_kick_rejoin() {
+        let server = MockServer::start(~[
+            ExpectEventually(~"USER *"),
+            mock::Send(~":irc.test 001 ircnick :Welcome"),
+            Expect(~"JOIN #chan"),
+            mock::Send(~":ircnick!ircuser@host JOIN #chan"),
+            // the kick names us in a different case
+            mock::Send(~":op!op@host KICK #chan IRCNick :bye"),
+            Expect(~"JOIN #chan"),
+            Close
+        ]).unwrap();
+
+        let mut opts = Options::new("127.0.0.1", server.port());
+        opts.autojoin = &[("#chan", "")];
+        opts.rejoin_on_kick = Some(Rejoin{ delay: 10, attempts: 1 });
+        assert!(conn::connect(opts, |_, _| ()).is_ok());
+        server.finish().assert_ok();
+    }
 }
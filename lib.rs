@@ -3,12 +3,29 @@
 
 //! Library for communicating with IRC servers
 
-#[feature(macro_rules)]; // for tests
+#[feature(macro_rules)];
 #[warn(missing_doc)];
 
+extern crate time;
+extern crate serialize;
+
 use std::vec;
 
 pub mod conn;
+pub mod dispatch;
+pub mod manager;
+pub mod state;
+pub mod mask;
+pub mod netsplit;
+pub mod proxy;
+pub mod mock;
+pub mod server;
+pub mod record;
+pub mod bot;
+pub mod acl;
+pub mod plugin;
+pub mod config;
+pub mod logger;
 
 /// Representation of an IRC user
 #[deriving(Clone)]
@@ -91,6 +108,15 @@ impl User {
     pub fn with_nick(&self, nick: &[u8]) -> User {
         User::new(nick, self.user(), self.host())
     }
+
+    /// Returns `true` if the User matches the given hostmask, such as
+    /// `*!*@*.example.com`. Partial masks like `nick` or `*@host` are expanded first.
+    ///
+    /// See mask::glob_match() for the wildcard syntax. For extended bans,
+    /// use mask::Mask instead.
+    pub fn matches(&self, mask: &[u8], casemap: CaseMapping) -> bool {
+        mask::glob_match(mask::normalize(mask), self.raw(), casemap)
+    }
 }
 
 impl Eq for User {
@@ -99,9 +125,63 @@ impl Eq for User {
     }
 }
 
+/// Case mappings used by IRC servers to compare nicknames and channel names,
+/// as advertised by the CASEMAPPING ISUPPORT parameter.
+#[deriving(Eq,Clone)]
+pub enum CaseMapping {
+    /// Only A-Z are folded to a-z
+    CaseMapAscii,
+    /// A-Z and []\~ are folded to a-z and {}|^
+    CaseMapRfc1459,
+    /// A-Z and []\ are folded to a-z and {}|
+    CaseMapStrictRfc1459
+}
+
+impl CaseMapping {
+    /// Returns the CaseMapping named by a CASEMAPPING value.
+    /// Unknown or missing values use rfc1459, which is the protocol default.
+    pub fn from_name(name: Option<&[u8]>) -> CaseMapping {
+        match name {
+            Some(v) if v == bytes!("ascii") => CaseMapAscii,
+            Some(v) if v == bytes!("strict-rfc1459") => CaseMapStrictRfc1459,
+            _ => CaseMapRfc1459
+        }
+    }
+
+    /// Folds a single byte to lowercase
+    pub fn to_lower_byte(&self, b: u8) -> u8 {
+        match (*self, b as char) {
+            (_, 'A'..'Z') => b + 32,
+            (CaseMapRfc1459, '[') | (CaseMapStrictRfc1459, '[') => '{' as u8,
+            (CaseMapRfc1459, ']') | (CaseMapStrictRfc1459, ']') => '}' as u8,
+            (CaseMapRfc1459, '\\') | (CaseMapStrictRfc1459, '\\') => '|' as u8,
+            (CaseMapRfc1459, '~') => '^' as u8,
+            _ => b
+        }
+    }
+
+    /// Folds a byte-vector to lowercase
+    pub fn to_lower(&self, v: &[u8]) -> ~[u8] {
+        v.iter().map(|&b| self.to_lower_byte(b)).collect()
+    }
+
+    /// Folds a string to lowercase. Only ASCII characters are folded, so the result
+    /// is still valid UTF-8.
+    pub fn to_lower_str(&self, s: &str) -> ~str {
+        s.chars().map(|c| if (c as u32) < 0x80 { self.to_lower_byte(c as u8) as char } else { c })
+                 .collect()
+    }
+
+    /// Compares two byte-vectors case-insensitively
+    pub fn equiv(&self, a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() &&
+            a.iter().zip(b.iter()).all(|(&a, &b)| self.to_lower_byte(a) == self.to_lower_byte(b))
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::User;
+    use super::{User, CaseMapAscii, CaseMapRfc1459, CaseMapStrictRfc1459};
 
     macro_rules! b(
         ($args:tt) => (
@@ -163,4 +243,16 @@ mod tests {
         assert_eq!(user.user(), None);
         assert_eq!(user.host(), None);
     }
+
+    #[test]
+    fn test_casemapping() {
+        assert!(CaseMapAscii.equiv(b!("Nick[a]"), b!("nICK[A]")));
+        assert!(!CaseMapAscii.equiv(b!("nick[a]"), b!("nick{a}")));
+        assert!(CaseMapRfc1459.equiv(b!("Nick[a]~"), b!("nick{a}^")));
+        assert!(CaseMapStrictRfc1459.equiv(b!("Nick[a]\\"), b!("nick{a}|")));
+        assert!(!CaseMapStrictRfc1459.equiv(b!("nick~"), b!("nick^")));
+        assert_eq!(CaseMapRfc1459.to_lower(b!("#Rust[IRC]")), b!("#rust{irc}").to_owned());
+        assert_eq!(CaseMapRfc1459.to_lower_str("Ünï[Code]"), ~"Ünï{code}");
+        assert_eq!(CaseMapAscii.to_lower_str("Ünï[Code]"), ~"Ünï[code]");
+    }
 }
@@ -0,0 +1,1111 @@
+//! Channel logging in common log formats
+//!
+//! A Logger writes one file per channel (or private query) per day, in the format
+//! of irssi, WeeChat or ZNC logs, or as JSON lines:
+//!
+//!     let mut logger = Logger::new(Path::new("logs/freenode"), Irssi);
+//!     irc::conn::connect(opts, |conn, event| { logger.handle_event(conn, &event); })
+//!
+//! Messages, notices, actions, joins, parts, quits, kicks, nick changes, mode
+//! changes and topic changes are logged. Lines are timestamped with the server-time
+//! tag if the server sent one, or else the time they were received.
+//!
+//! Our own messages are logged from the Conn's MessageSent events, so messages sent
+//! through a Handle are not logged. The quits and joins of a netsplit are logged from
+//! the NetSplit and NetJoin events when Options.netsplits is set.
+//!
+//! Logs can be read back into entries with read_log(), or line by line with a
+//! LogParser. Besides the files written by Logger, this reads irssi logs with its
+//! default `HH:MM` timestamps and nick mode prefixes like `<@nick>`.
+
+use std::io;
+use std::io::{IoResult, File};
+use std::io::fs;
+use std::str;
+use serialize::json;
+use time;
+use time::{Timespec, Tm};
+use conn::{Conn, Event, Line, LineReceived, MessageSent, NetSplit, NetJoin, Disconnected};
+use conn::{IRCCmd, IRCAction, format_time, parse_time};
+use netsplit::Split;
+use state::State;
+use {User, CaseMapping, CaseMapRfc1459};
+
+macro_rules! opt(
+    ($e:expr) => (match $e { Some(v) => v, None => return None })
+)
+
+/// The supported log formats
+#[deriving(Eq,Clone)]
+pub enum Format {
+    /// irssi's default format, with seconds: `12:00:00 <nick> message`
+    Irssi,
+    /// WeeChat's format: `2014-02-14 12:00:00\tnick\tmessage`
+    Weechat,
+    /// ZNC's log module format: `[12:00:00] <nick> message`
+    Znc,
+    /// One JSON object per line, with `time` (as in server-time), `target`, and the
+    /// raw IRC `line`
+    JsonLines
+}
+
+/// How often a new log file is started for each channel
+#[deriving(Eq,Clone)]
+pub enum Rotation {
+    /// One file per day, named like `2014-02-14.log`
+    RotateDaily,
+    /// One file per month, named like `2014-02.log`
+    RotateMonthly,
+    /// A single file, named after the channel
+    RotateNever
+}
+
+/// A logged line, with the time and the channel or query it belongs to
+#[deriving(Eq,Clone)]
+pub struct LogEntry {
+    /// When the line was received, or the server-time of the line
+    time: Timespec,
+    /// The channel, or the other user's nickname for private messages
+    target: ~[u8],
+    /// The line. QUIT and NICK lines are logged to every channel the user was in.
+    line: Line
+}
+
+/// Writes log files for a connection
+pub struct Logger {
+    /// The log format
+    format: Format,
+    /// How often to start a new file
+    rotation: Rotation,
+    /// If `true`, timestamps and file dates are in UTC instead of local time
+    utc: bool,
+    priv dir: Path,
+    priv casemap: CaseMapping,
+    // (target key, path, file) for each open file
+    priv files: ~[(~[u8], Path, File)]
+}
+
+impl Logger {
+    /// Returns a Logger that writes files under `dir`, one subdirectory per channel.
+    /// Each network should be given its own directory.
+    pub fn new(dir: Path, format: Format) -> Logger {
+        Logger {
+            format: format,
+            rotation: RotateDaily,
+            utc: false,
+            dir: dir,
+            casemap: CaseMapRfc1459,
+            files: ~[]
+        }
+    }
+
+    /// Logs the lines received and the messages sent by the connection, and closes the
+    /// files when the connection terminates.
+    pub fn handle_event(&mut self, conn: &Conn, event: &Event) -> IoResult<()> {
+        match *event {
+            LineReceived(ref line) => {
+                let time = line.server_time().unwrap_or_else(|| time::get_time());
+                self.log_line(conn.state(), conn.me().nick(), line, time)
+            }
+            MessageSent(ref line) => {
+                self.log_line(conn.state(), conn.me().nick(), line, time::get_time())
+            }
+            NetSplit(ref split) => {
+                self.casemap = conn.state().casemapping();
+                let logged = split_entries(split, false, self.casemap);
+                self.write_all(logged)
+            }
+            NetJoin(ref split) => {
+                self.casemap = conn.state().casemapping();
+                let logged = split_entries(split, true, self.casemap);
+                self.write_all(logged)
+            }
+            Disconnected => {
+                self.close();
+                Ok(())
+            }
+            _ => Ok(())
+        }
+    }
+
+    /// Logs a line. `state` is the connection's state after it handled the line, as
+    /// returned by Conn.state(), and `me` is our current nickname.
+    pub fn log_line(&mut self, state: &State, me: &[u8], line: &Line,
+                    time: Timespec) -> IoResult<()> {
+        self.casemap = state.casemapping();
+        self.write_all(entries(state, me, line, time))
+    }
+
+    fn write_all(&mut self, entries: ~[LogEntry]) -> IoResult<()> {
+        for entry in entries.iter() {
+            match self.write(entry) {
+                Err(e) => return Err(e),
+                Ok(()) => ()
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a single entry to the file for its target and time
+    pub fn write(&mut self, entry: &LogEntry) -> IoResult<()> {
+        let text = match format_entry(self.format, entry, self.utc) {
+            None => return Ok(()),
+            Some(text) => text
+        };
+        let key = self.casemap.to_lower(entry.target);
+        let path = self.path_for(entry.target, entry.time);
+        let idx = match self.files.iter().position(|&(ref k, ref p, _)| *k == key && *p == path) {
+            Some(idx) => idx,
+            None => {
+                // a different path for the same target means it's time to rotate
+                self.files.retain(|&(ref k, _, _)| *k != key);
+                let dir = path.dir_path();
+                if !dir.exists() {
+                    match fs::mkdir_recursive(&dir, io::UserRWX) {
+                        Err(e) => return Err(e),
+                        Ok(()) => ()
+                    }
+                }
+                let file = match File::open_mode(&path, io::Append, io::Write) {
+                    Err(e) => return Err(e),
+                    Ok(file) => file
+                };
+                self.files.push((key, path, file));
+                self.files.len() - 1
+            }
+        };
+        match self.files[idx] {
+            (_, _, ref mut file) => {
+                match file.write(text) {
+                    Err(e) => Err(e),
+                    Ok(()) => file.flush()
+                }
+            }
+        }
+    }
+
+    /// Returns the path of the log file for the target at the given time
+    pub fn path_for(&self, target: &[u8], time: Timespec) -> Path {
+        let name: ~[u8] = self.casemap.to_lower(target).move_iter().map(|b| {
+            match b as char {
+                '/' | '\\' | '\0' => '_' as u8,
+                _ => b
+            }
+        }).collect();
+        let name = if name.is_empty() || name[0] == '.' as u8 { bytes!("_") + name } else { name };
+        let ext = if self.format == JsonLines { "jsonl" } else { "log" };
+        let tm = to_tm(time, self.utc);
+        match self.rotation {
+            RotateDaily => self.dir.join(name).join(tm.strftime("%Y-%m-%d.") + ext),
+            RotateMonthly => self.dir.join(name).join(tm.strftime("%Y-%m.") + ext),
+            RotateNever => self.dir.join(name + bytes!(".") + ext.as_bytes())
+        }
+    }
+
+    /// Closes all open log files
+    pub fn close(&mut self) {
+        self.files = ~[];
+    }
+}
+
+fn to_tm(time: Timespec, utc: bool) -> Tm {
+    if utc { time::at_utc(time) } else { time::at(time) }
+}
+
+/// Returns the entries a received or sent line should be logged as.
+/// `state` is the connection's state after it handled the line.
+pub fn entries(state: &State, me: &[u8], line: &Line, time: Timespec) -> ~[LogEntry] {
+    let casemap = state.casemapping();
+    let nick = match line.prefix {
+        None => return ~[],
+        Some(ref prefix) => prefix.nick()
+    };
+    // private messages are logged under the other user's nick
+    let query = |dst: &~[u8]| if casemap.equiv(*dst, me) { nick.to_owned() } else { dst.clone() };
+    let targets: ~[~[u8]] = match line.command {
+        IRCAction(ref dst) => ~[query(dst)],
+        IRCCmd(ref cmd) => match (cmd.as_slice(), line.args.as_slice()) {
+            ("PRIVMSG", [ref dst, _]) | ("NOTICE", [ref dst, _]) => ~[query(dst)],
+            ("JOIN", [ref chans, ..]) | ("PART", [ref chans, ..]) |
+            ("KICK", [ref chans, ..]) | ("TOPIC", [ref chans, ..]) => {
+                chans.split(|&b| b == ',' as u8).map(|c| c.to_owned()).collect()
+            }
+            ("MODE", [ref chan, _, ..]) if state.is_channel(*chan) => ~[chan.clone()],
+            ("QUIT", _) => match state.last_quit() {
+                Some(info) if casemap.equiv(info.user.nick(), nick) => info.channels.clone(),
+                _ => ~[]
+            },
+            ("NICK", [ref new, ..]) => {
+                state.user(*new).map_or(~[], |info| info.channels.clone())
+            }
+            _ => ~[]
+        },
+        _ => ~[]
+    };
+    let mut line = line.clone();
+    line.tags = ~[];
+    targets.move_iter().map(|target| {
+        LogEntry{ time: time, target: target, line: line.clone() }
+    }).collect()
+}
+
+/// Returns the entries a netsplit or netjoin should be logged as: a QUIT from each
+/// channel the users split from, or a JOIN to each channel they rejoined.
+pub fn split_entries(split: &Split, rejoined: bool, casemap: CaseMapping) -> ~[LogEntry] {
+    let time = Timespec::new(split.time, 0);
+    let reason = split.server1 + bytes!(" ") + split.server2;
+    let mut out = ~[];
+    for &(ref chan, ref nicks) in split.channels.iter() {
+        for nick in nicks.iter() {
+            let user = match split.users.iter().find(|u| casemap.equiv(u.nick(), *nick)) {
+                None => continue,
+                Some(user) => user.with_nick(*nick)
+            };
+            let line = if rejoined {
+                line(user, "JOIN", [chan.as_slice()])
+            } else {
+                line(user, "QUIT", [reason.as_slice()])
+            };
+            out.push(LogEntry{ time: time, target: chan.clone(), line: line });
+        }
+    }
+    out
+}
+
+/// Formats an entry as a line of a log file, including the trailing newline.
+/// Returns None if the line is not one that is logged.
+pub fn format_entry(format: Format, entry: &LogEntry, utc: bool) -> Option<~[u8]> {
+    let tm = to_tm(entry.time, utc);
+    let prefix = match entry.line.prefix {
+        None => return None,
+        Some(ref prefix) => prefix
+    };
+    if format == JsonLines {
+        let target = str::from_utf8_lossy(entry.target).into_owned();
+        let line = str::from_utf8_lossy(entry.line.to_raw()).into_owned();
+        let text = format!("\\{\"time\":{},\"target\":{},\"line\":{}\\}\n",
+                           json::String(format_time(entry.time)).to_str(),
+                           json::String(target).to_str(),
+                           json::String(line).to_str());
+        return Some(text.into_bytes());
+    }
+
+    let kind = match Kind::from_line(&entry.line) {
+        None => return None,
+        Some(kind) => kind
+    };
+    let mut out = match format {
+        Irssi => tm.strftime("%H:%M:%S ").into_bytes(),
+        Weechat => tm.strftime("%Y-%m-%d %H:%M:%S\t").into_bytes(),
+        Znc => tm.strftime("[%H:%M:%S] ").into_bytes(),
+        JsonLines => unreachable!()
+    };
+    let nick = prefix.nick();
+    let chan = entry.target.as_slice();
+    let userhost = userhost(prefix);
+    let parts: ~[&[u8]] = match (format, &kind) {
+        (Irssi, &KindMessage(text)) => ~[b("<"), nick, b("> "), text],
+        (Irssi, &KindNotice(text)) if chan == nick => ~[b("-"), nick, b("- "), text],
+        (Irssi, &KindNotice(text)) => ~[b("-"), nick, b(":"), chan, b("- "), text],
+        (Irssi, &KindAction(text)) => ~[b(" * "), nick, b(" "), text],
+        (Irssi, &KindJoin) => ~[b("-!- "), nick, b(" ["), userhost, b("] has joined "), chan],
+        (Irssi, &KindPart(reason)) => {
+            ~[b("-!- "), nick, b(" ["), userhost, b("] has left "), chan, b(" ["), reason, b("]")]
+        }
+        (Irssi, &KindQuit(reason)) => {
+            ~[b("-!- "), nick, b(" ["), userhost, b("] has quit ["), reason, b("]")]
+        }
+        (Irssi, &KindKick(victim, reason)) => {
+            ~[b("-!- "), victim, b(" was kicked from "), chan, b(" by "), nick, b(" ["), reason,
+              b("]")]
+        }
+        (Irssi, &KindNick(new)) => ~[b("-!- "), nick, b(" is now known as "), new],
+        (Irssi, &KindMode(ref modes)) => {
+            ~[b("-!- mode/"), chan, b(" ["), modes.as_slice(), b("] by "), nick]
+        }
+        (Irssi, &KindTopic(topic)) => {
+            ~[b("-!- "), nick, b(" changed the topic of "), chan, b(" to: "), topic]
+        }
+
+        (Weechat, &KindMessage(text)) => ~[nick, b("\t"), text],
+        (Weechat, &KindNotice(text)) => ~[b("--\tNotice("), nick, b(") -> "), chan, b(": "), text],
+        (Weechat, &KindAction(text)) => ~[b(" *\t"), nick, b(" "), text],
+        (Weechat, &KindJoin) => ~[b("-->\t"), nick, b(" ("), userhost, b(") has joined "), chan],
+        (Weechat, &KindPart(reason)) => {
+            ~[b("<--\t"), nick, b(" ("), userhost, b(") has left "), chan, b(" ("), reason,
+              b(")")]
+        }
+        (Weechat, &KindQuit(reason)) => {
+            ~[b("<--\t"), nick, b(" ("), userhost, b(") has quit ("), reason, b(")")]
+        }
+        (Weechat, &KindKick(victim, reason)) => {
+            ~[b("<--\t"), nick, b(" has kicked "), victim, b(" ("), reason, b(")")]
+        }
+        (Weechat, &KindNick(new)) => ~[b("--\t"), nick, b(" is now known as "), new],
+        (Weechat, &KindMode(ref modes)) => {
+            ~[b("--\tMode "), chan, b(" ["), modes.as_slice(), b("] by "), nick]
+        }
+        (Weechat, &KindTopic(topic)) => {
+            ~[b("--\t"), nick, b(" has changed topic for "), chan, b(" to \""), topic, b("\"")]
+        }
+
+        (Znc, &KindMessage(text)) => ~[b("<"), nick, b("> "), text],
+        (Znc, &KindNotice(text)) => ~[b("-"), nick, b("- "), text],
+        (Znc, &KindAction(text)) => ~[b("* "), nick, b(" "), text],
+        (Znc, &KindJoin) => ~[b("*** Joins: "), nick, b(" ("), userhost, b(")")],
+        (Znc, &KindPart(reason)) => {
+            ~[b("*** Parts: "), nick, b(" ("), userhost, b(") ("), reason, b(")")]
+        }
+        (Znc, &KindQuit(reason)) => {
+            ~[b("*** Quits: "), nick, b(" ("), userhost, b(") ("), reason, b(")")]
+        }
+        (Znc, &KindKick(victim, reason)) => {
+            ~[b("*** "), victim, b(" was kicked by "), nick, b(" ("), reason, b(")")]
+        }
+        (Znc, &KindNick(new)) => ~[b("*** "), nick, b(" is now known as "), new],
+        (Znc, &KindMode(ref modes)) => ~[b("*** "), nick, b(" sets mode: "), modes.as_slice()],
+        (Znc, &KindTopic(topic)) => ~[b("*** "), nick, b(" changes topic to '"), topic, b("'")],
+
+        (JsonLines, _) => unreachable!()
+    };
+    for part in parts.iter() {
+        out.push_all(*part);
+    }
+    out.push('\n' as u8);
+    Some(out)
+}
+
+// The kinds of line that are logged, with the parts each format needs
+enum Kind<'a> {
+    KindMessage(&'a [u8]),
+    KindNotice(&'a [u8]),
+    KindAction(&'a [u8]),
+    KindJoin,
+    KindPart(&'a [u8]),
+    KindQuit(&'a [u8]),
+    KindKick(&'a [u8], &'a [u8]),
+    KindNick(&'a [u8]),
+    KindMode(~[u8]),
+    KindTopic(&'a [u8])
+}
+
+impl<'a> Kind<'a> {
+    fn from_line(line: &'a Line) -> Option<Kind<'a>> {
+        let args = line.args.as_slice();
+        let last = args.last().map_or(b(""), |a| a.as_slice());
+        let cmd = match line.command {
+            IRCAction(_) => return Some(KindAction(last)),
+            IRCCmd(ref cmd) => cmd.as_slice(),
+            _ => return None
+        };
+        match (cmd, args.len()) {
+            ("PRIVMSG", 2) => Some(KindMessage(last)),
+            ("NOTICE", 2) => Some(KindNotice(last)),
+            ("JOIN", n) if n >= 1 => Some(KindJoin),
+            ("PART", 1) => Some(KindPart(b(""))),
+            ("PART", 2) => Some(KindPart(last)),
+            ("QUIT", 0) | ("QUIT", 1) => Some(KindQuit(last)),
+            ("KICK", 2) => Some(KindKick(args[1].as_slice(), b(""))),
+            ("KICK", 3) => Some(KindKick(args[1].as_slice(), last)),
+            ("NICK", 1) => Some(KindNick(last)),
+            ("MODE", n) if n >= 2 => Some(KindMode(args.slice_from(1).connect_vec(&(' ' as u8)))),
+            ("TOPIC", 2) => Some(KindTopic(last)),
+            _ => None
+        }
+    }
+}
+
+/// Parses the lines of a log file back into entries.
+///
+/// Lines that are not messages, notices, actions, joins, parts, quits, kicks, nick
+/// changes, mode changes or topic changes are skipped. Messages in logs don't record
+/// the sender's user@host, so their prefix is just the nickname.
+pub struct LogParser {
+    /// The log format
+    format: Format,
+    /// If `true`, timestamps are read as UTC instead of local time
+    utc: bool,
+    /// The channel or query the log belongs to.
+    /// JSON lines record this themselves.
+    target: ~[u8],
+    /// The date of lines that only record the time, as YYYY-MM-DD.
+    /// irssi's "Day changed" and "Log opened" lines update it.
+    date: Option<~str>,
+    /// Our own nickname. In a query log, messages from the other user are addressed
+    /// to it, as they were when received. If None, they are addressed to the target.
+    me: Option<~[u8]>
+}
+
+impl LogParser {
+    /// Returns a LogParser for the log of `target` that starts on `date` (YYYY-MM-DD)
+    pub fn new(format: Format, target: &[u8], date: Option<&str>) -> LogParser {
+        LogParser {
+            format: format,
+            utc: false,
+            target: target.to_owned(),
+            date: date.map(|d| d.to_owned()),
+            me: None
+        }
+    }
+
+    /// Parses a single line of the log, without the line terminator.
+    /// Returns None if the line isn't one that is logged, or can't be parsed.
+    pub fn parse_line(&mut self, v: &[u8]) -> Option<LogEntry> {
+        let v = match v { [..init, b] if b == '\r' as u8 => init, v => v };
+        let (time, mut line) = match self.format {
+            JsonLines => return parse_json(v),
+            Irssi => {
+                match strip(v, "--- ").and_then(irssi_date) {
+                    Some(date) => {
+                        self.date = Some(date);
+                        return None;
+                    }
+                    None => ()
+                }
+                // the seconds are optional
+                let (time, rest) = opt!(split(v, " "));
+                let time = match time.len() {
+                    5 => time + bytes!(":00"),
+                    8 => time.to_owned(),
+                    _ => return None
+                };
+                (opt!(self.timestamp(time.as_slice())), opt!(parse_irssi(self.target, rest)))
+            }
+            Weechat => {
+                if v.len() < 20 || v[10] != ' ' as u8 || v[19] != '\t' as u8 {
+                    return None;
+                }
+                self.date = Some(str::from_utf8_lossy(v.slice_to(10)).into_owned());
+                let time = opt!(self.timestamp(v.slice(11, 19)));
+                (time, opt!(parse_weechat(self.target, v.slice_from(20))))
+            }
+            Znc => {
+                let (time, rest) = opt!(split(opt!(strip(v, "[")), "] "));
+                (opt!(self.timestamp(time)), opt!(parse_znc(self.target, rest)))
+            }
+        };
+        self.address(&mut line);
+        Some(LogEntry{ time: time, target: self.target.clone(), line: line })
+    }
+
+    // Addresses a message the other user sent in a query to us
+    fn address(&self, line: &mut Line) {
+        let me = match self.me {
+            None => return,
+            Some(ref me) => me
+        };
+        let from_target = line.prefix.as_ref().map_or(false, |p| {
+            CaseMapRfc1459.equiv(p.nick(), self.target)
+        });
+        if !from_target {
+            return;
+        }
+        match line.command {
+            IRCAction(ref mut dst) => *dst = me.clone(),
+            IRCCmd(~"PRIVMSG") | IRCCmd(~"NOTICE") if !line.args.is_empty() => {
+                line.args[0] = me.clone();
+            }
+            _ => ()
+        }
+    }
+
+    // Combines the current date with a HH:MM:SS time
+    fn timestamp(&self, time: &[u8]) -> Option<Timespec> {
+        let date = opt!(self.date.as_ref());
+        let text = date.as_bytes() + bytes!("T") + time + bytes!("Z");
+        let ts = opt!(parse_time(text));
+        if self.utc {
+            return Some(ts);
+        }
+        // the offset is looked up for the UTC reading of the time, which is off by
+        // the offset itself. That only matters around DST changes.
+        let offset = time::at(ts).tm_gmtoff as i64;
+        Some(Timespec::new(ts.sec - offset, 0))
+    }
+}
+
+/// Reads a log file written by Logger. `me` is our own nickname, see LogParser.me.
+///
+/// The target and date are taken from the path, which should look like
+/// `#chan/2014-02-14.log` or `#chan.log`. In the latter case irssi logs need
+/// "Log opened" lines for the dates.
+pub fn read_log(path: &Path, format: Format, utc: bool,
+                me: Option<&[u8]>) -> IoResult<~[LogEntry]> {
+    let text = match File::open(path).and_then(|mut f| f.read_to_end()) {
+        Err(e) => return Err(e),
+        Ok(text) => text
+    };
+    let stem = path.filestem().unwrap_or(b("")).to_owned();
+    let is_date = |v: &[u8]| {
+        (v.len() == 10 || v.len() == 7) && v.iter().enumerate().all(|(i, &c)| {
+            if i == 4 || i == 7 { c == '-' as u8 } else { c >= '0' as u8 && c <= '9' as u8 }
+        })
+    };
+    let (target, date) = if is_date(stem.as_slice()) {
+        let dir = path.dir_path();
+        let target = dir.filename().unwrap_or(b("")).to_owned();
+        let date = if stem.len() == 10 {
+            Some(str::from_utf8_lossy(stem).into_owned())
+        } else {
+            None
+        };
+        (target, date)
+    } else {
+        (stem, None)
+    };
+
+    let mut parser = LogParser::new(format, target.as_slice(), date.as_ref().map(|d| d.as_slice()));
+    parser.utc = utc;
+    parser.me = me.map(|me| me.to_owned());
+    Ok(text.split(|&b| b == '\n' as u8).filter_map(|line| parser.parse_line(line)).collect())
+}
+
+// "--- Day changed Fri Feb 14 2014" or "--- Log opened Fri Feb 14 09:30:00 2014"
+fn irssi_date(v: &[u8]) -> Option<~str> {
+    static Months: &'static [&'static str] = &["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                                               "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let v = opt!(str::from_utf8(v));
+    let words: ~[&str] = v.words().collect();
+    let (mon, day, year) = match words.as_slice() {
+        ["Day", "changed", _, mon, day, year] => (mon, day, year),
+        ["Log", "opened", _, mon, day, _, year] => (mon, day, year),
+        _ => return None
+    };
+    let mon = opt!(Months.iter().position(|&m| m == mon)) + 1;
+    let day = opt!(from_str::<uint>(day));
+    let year = opt!(from_str::<uint>(year));
+    Some(format!("{:04u}-{:02u}-{:02u}", year, mon, day))
+}
+
+fn parse_json(v: &[u8]) -> Option<LogEntry> {
+    let value = match json::from_str(opt!(str::from_utf8(v))) {
+        Err(_) => return None,
+        Ok(value) => value
+    };
+    let field = |key: &str| {
+        match value {
+            json::Object(ref obj) => {
+                match obj.find(&key.to_owned()) {
+                    Some(&json::String(ref s)) => Some(s.as_bytes().to_owned()),
+                    _ => None
+                }
+            }
+            _ => None
+        }
+    };
+    let (time, target, line) = (opt!(field("time")), opt!(field("target")), opt!(field("line")));
+    let time = opt!(parse_time(time.as_slice()));
+    let line = opt!(Line::parse(line.as_slice()));
+    Some(LogEntry{ time: time, target: target, line: line })
+}
+
+fn parse_irssi(target: &[u8], v: &[u8]) -> Option<Line> {
+    match strip(v, "-!- ") {
+        None => (),
+        Some(rest) => {
+            match strip(rest, "mode/") {
+                None => (),
+                Some(rest) => {
+                    let (chan, rest) = opt!(split(rest, " ["));
+                    let (modes, nick) = opt!(split(rest, "] by "));
+                    return Some(mode_line(nick, chan, modes));
+                }
+            }
+            let (nick, rest) = opt!(split(rest, " "));
+            match strip(rest, "was kicked from ") {
+                None => (),
+                Some(rest) => {
+                    let (chan, rest) = opt!(split(rest, " by "));
+                    let (kicker, rest) = opt!(split(rest, " "));
+                    let reason = opt!(enclosed(rest, "[", "]"));
+                    return Some(kick_line(kicker, chan, nick, reason));
+                }
+            }
+            match strip(rest, "is now known as ") {
+                None => (),
+                Some(new) => return Some(line(user(nick, None), "NICK", [new]))
+            }
+            match strip(rest, "changed the topic of ") {
+                None => (),
+                Some(rest) => {
+                    let (chan, topic) = opt!(split(rest, " to: "));
+                    return Some(line(user(nick, None), "TOPIC", [chan, topic]));
+                }
+            }
+            let (uh, rest) = opt!(split(opt!(strip(rest, "[")), "] "));
+            let prefix = user(nick, Some(uh));
+            match strip(rest, "has joined ") {
+                None => (),
+                Some(chan) => return Some(line(prefix, "JOIN", [chan]))
+            }
+            match strip(rest, "has left ") {
+                None => (),
+                Some(rest) => {
+                    let (chan, reason) = opt!(split(rest, " "));
+                    return Some(part_line(prefix, chan, opt!(enclosed(reason, "[", "]"))));
+                }
+            }
+            let reason = opt!(strip(rest, "has quit ").and_then(|r| enclosed(r, "[", "]")));
+            return Some(quit_line(prefix, reason));
+        }
+    }
+    match strip(v, " * ") {
+        None => (),
+        Some(rest) => {
+            let (nick, text) = split(rest, " ").unwrap_or((rest, b("")));
+            return Some(action_line(nick, target, text));
+        }
+    }
+    match strip(v, "<") {
+        None => (),
+        Some(rest) => {
+            let (nick, text) = opt!(split(rest, "> "));
+            return Some(line(user(strip_mode(nick), None), "PRIVMSG", [target, text]));
+        }
+    }
+    let (from, text) = opt!(split(opt!(strip(v, "-")), "- "));
+    // -nick:#chan- for channel notices, -nick(user@host)- for private ones
+    let nick = match from.iter().position(|&c| c == ':' as u8 || c == '(' as u8) {
+        None => from,
+        Some(idx) => from.slice_to(idx)
+    };
+    Some(line(user(nick, None), "NOTICE", [target, text]))
+}
+
+fn parse_weechat(target: &[u8], v: &[u8]) -> Option<Line> {
+    let (prefix, msg) = opt!(split(v, "\t"));
+    match prefix {
+        p if p == b("-->") => {
+            let (nick, rest) = opt!(split(msg, " ("));
+            let (uh, chan) = opt!(split(rest, ") has joined "));
+            Some(line(user(nick, Some(uh)), "JOIN", [chan]))
+        }
+        p if p == b("<--") => {
+            let (nick, rest) = opt!(split(msg, " "));
+            match strip(rest, "has kicked ") {
+                None => (),
+                Some(rest) => {
+                    let (victim, reason) = opt!(split(rest, " ("));
+                    let reason = opt!(enclosed(reason, "", ")"));
+                    return Some(kick_line(nick, target, victim, reason));
+                }
+            }
+            let (uh, rest) = opt!(split(opt!(strip(rest, "(")), ") "));
+            let prefix = user(nick, Some(uh));
+            match strip(rest, "has left ") {
+                None => (),
+                Some(rest) => {
+                    let (chan, reason) = opt!(split(rest, " ("));
+                    return Some(part_line(prefix, chan, opt!(enclosed(reason, "", ")"))));
+                }
+            }
+            let reason = opt!(enclosed(rest, "has quit (", ")"));
+            Some(quit_line(prefix, reason))
+        }
+        p if p == b("--") => {
+            match strip(msg, "Notice(") {
+                None => (),
+                Some(rest) => {
+                    let (nick, rest) = opt!(split(rest, ") -> "));
+                    let (to, text) = opt!(split(rest, ": "));
+                    return Some(line(user(nick, None), "NOTICE", [to, text]));
+                }
+            }
+            match strip(msg, "Mode ") {
+                None => (),
+                Some(rest) => {
+                    let (chan, rest) = opt!(split(rest, " ["));
+                    let (modes, nick) = opt!(split(rest, "] by "));
+                    return Some(mode_line(nick, chan, modes));
+                }
+            }
+            let (nick, rest) = opt!(split(msg, " "));
+            match strip(rest, "is now known as ") {
+                None => (),
+                Some(new) => return Some(line(user(nick, None), "NICK", [new]))
+            }
+            let (chan, rest) = opt!(split(opt!(strip(rest, "has changed topic for ")), " to \""));
+            let topic = opt!(enclosed(rest, "", "\""));
+            Some(line(user(nick, None), "TOPIC", [chan, topic]))
+        }
+        p if p == b(" *") => {
+            let (nick, text) = split(msg, " ").unwrap_or((msg, b("")));
+            Some(action_line(nick, target, text))
+        }
+        nick => Some(line(user(strip_mode(nick), None), "PRIVMSG", [target, msg]))
+    }
+}
+
+fn parse_znc(target: &[u8], v: &[u8]) -> Option<Line> {
+    match strip(v, "*** ") {
+        None => (),
+        Some(rest) => {
+            match strip(rest, "Joins: ") {
+                None => (),
+                Some(rest) => {
+                    let (nick, uh) = opt!(split(rest, " ("));
+                    let uh = opt!(enclosed(uh, "", ")"));
+                    return Some(line(user(nick, Some(uh)), "JOIN", [target]));
+                }
+            }
+            for &(kind, cmd) in [("Parts: ", "PART"), ("Quits: ", "QUIT")].iter() {
+                match strip(rest, kind) {
+                    None => (),
+                    Some(rest) => {
+                        let (nick, rest) = opt!(split(rest, " ("));
+                        let (uh, reason) = opt!(split(rest, ") ("));
+                        let reason = opt!(enclosed(reason, "", ")"));
+                        let prefix = user(nick, Some(uh));
+                        return Some(if cmd == "PART" {
+                            part_line(prefix, target, reason)
+                        } else {
+                            quit_line(prefix, reason)
+                        });
+                    }
+                }
+            }
+            let (nick, rest) = opt!(split(rest, " "));
+            match strip(rest, "is now known as ") {
+                None => (),
+                Some(new) => return Some(line(user(nick, None), "NICK", [new]))
+            }
+            match strip(rest, "was kicked by ") {
+                None => (),
+                Some(rest) => {
+                    let (kicker, reason) = opt!(split(rest, " ("));
+                    let reason = opt!(enclosed(reason, "", ")"));
+                    return Some(kick_line(kicker, target, nick, reason));
+                }
+            }
+            match strip(rest, "sets mode: ") {
+                None => (),
+                Some(modes) => return Some(mode_line(nick, target, modes))
+            }
+            let topic = opt!(enclosed(rest, "changes topic to '", "'"));
+            return Some(line(user(nick, None), "TOPIC", [target, topic]));
+        }
+    }
+    match strip(v, "* ") {
+        None => (),
+        Some(rest) => {
+            let (nick, text) = split(rest, " ").unwrap_or((rest, b("")));
+            return Some(action_line(nick, target, text));
+        }
+    }
+    match strip(v, "<") {
+        None => (),
+        Some(rest) => {
+            let (nick, text) = opt!(split(rest, "> "));
+            return Some(line(user(strip_mode(nick), None), "PRIVMSG", [target, text]));
+        }
+    }
+    let (nick, text) = opt!(split(opt!(strip(v, "-")), "- "));
+    Some(line(user(nick, None), "NOTICE", [target, text]))
+}
+
+fn user(nick: &[u8], userhost: Option<&[u8]>) -> User {
+    match userhost {
+        Some(uh) if uh.contains(&('@' as u8)) => User::parse(nick + bytes!("!") + uh),
+        _ => User::parse(nick.to_owned())
+    }
+}
+
+fn line(prefix: User, cmd: &str, args: &[&[u8]]) -> Line {
+    Line {
+        tags: ~[],
+        prefix: Some(prefix),
+        command: IRCCmd(cmd.to_owned()),
+        args: args.iter().map(|a| a.to_owned()).collect()
+    }
+}
+
+fn action_line(nick: &[u8], target: &[u8], text: &[u8]) -> Line {
+    Line {
+        tags: ~[],
+        prefix: Some(user(nick, None)),
+        command: IRCAction(target.to_owned()),
+        args: ~[text.to_owned()]
+    }
+}
+
+// Empty reasons are left out, as they are when logged
+fn part_line(prefix: User, chan: &[u8], reason: &[u8]) -> Line {
+    if reason.is_empty() {
+        line(prefix, "PART", [chan])
+    } else {
+        line(prefix, "PART", [chan, reason])
+    }
+}
+
+fn quit_line(prefix: User, reason: &[u8]) -> Line {
+    if reason.is_empty() { line(prefix, "QUIT", []) } else { line(prefix, "QUIT", [reason]) }
+}
+
+fn kick_line(kicker: &[u8], chan: &[u8], nick: &[u8], reason: &[u8]) -> Line {
+    let kicker = user(kicker, None);
+    if reason.is_empty() {
+        line(kicker, "KICK", [chan, nick])
+    } else {
+        line(kicker, "KICK", [chan, nick, reason])
+    }
+}
+
+fn mode_line(nick: &[u8], chan: &[u8], modes: &[u8]) -> Line {
+    let mut args = ~[chan];
+    for m in modes.split(|&b| b == ' ' as u8).filter(|m| !m.is_empty()) {
+        args.push(m);
+    }
+    line(user(nick, None), "MODE", args)
+}
+
+// Strips a channel status symbol from a nick, as shown by irssi and WeeChat
+fn strip_mode<'a>(nick: &'a [u8]) -> &'a [u8] {
+    match nick {
+        [c, ..rest] if bytes!(" @+%&~!").contains(&c) && !rest.is_empty() => rest,
+        nick => nick
+    }
+}
+
+fn find(v: &[u8], pat: &str) -> Option<uint> {
+    let pat = pat.as_bytes();
+    if pat.len() > v.len() {
+        return None;
+    }
+    range(0, v.len() - pat.len() + 1).find(|&i| v.slice(i, i + pat.len()) == pat)
+}
+
+// Splits at the first occurrence of `sep`
+fn split<'a>(v: &'a [u8], sep: &str) -> Option<(&'a [u8], &'a [u8])> {
+    find(v, sep).map(|i| (v.slice_to(i), v.slice_from(i + sep.len())))
+}
+
+fn strip<'a>(v: &'a [u8], prefix: &str) -> Option<&'a [u8]> {
+    if v.starts_with(prefix.as_bytes()) { Some(v.slice_from(prefix.len())) } else { None }
+}
+
+fn enclosed<'a>(v: &'a [u8], open: &str, close: &str) -> Option<&'a [u8]> {
+    if v.len() >= open.len() + close.len() && v.starts_with(open.as_bytes())
+        && v.ends_with(close.as_bytes()) {
+        Some(v.slice(open.len(), v.len() - close.len()))
+    } else {
+        None
+    }
+}
+
+fn b<'a>(s: &'a str) -> &'a [u8] {
+    s.as_bytes()
+}
+
+fn userhost<'a>(user: &'a User) -> &'a [u8] {
+    // the host comes right after the username in the raw nick!user@host
+    match user.user() {
+        Some(_) => user.raw().slice_from(user.nick().len() + 1),
+        None => user.host().unwrap_or(b("*"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Logger, LogEntry, Format, Irssi, Weechat, Znc, JsonLines, entries, format_entry};
+    use super::{LogParser, read_log, split_entries};
+    use conn::Line;
+    use netsplit::Split;
+    use state::State;
+    use {User, CaseMapRfc1459};
+    use time::Timespec;
+    use std::io::{File, TempDir};
+    use std::str;
+
+    // 2014-02-14 09:30:00 UTC
+    static Time: i64 = 1392370200;
+
+    fn feed(lines: &[&[u8]]) -> ~[LogEntry] {
+        let mut state = State::new();
+        let mut out = ~[];
+        for &raw in lines.iter() {
+            let line = Line::parse(raw).unwrap();
+            state.handle_line(bytes!("me"), &line);
+            out.push_all_move(entries(&state, bytes!("me"), &line, Timespec::new(Time, 0)));
+        }
+        out
+    }
+
+    fn format_all(format: Format, entries: &[LogEntry]) -> ~str {
+        let text: ~[~[u8]] = entries.iter().filter_map(|e| format_entry(format, e, true)).collect();
+        str::from_utf8_owned(text.concat_vec()).unwrap()
+    }
+
+    static Lines: &'static [&'static [u8]] = &[
+        bytes!(":me!m@host JOIN #chan"),
+        bytes!(":bob!b@host JOIN #chan"),
+        bytes!("@time=2014-02-14T09:31:00.000Z :bob!b@host PRIVMSG #chan :hello there"),
+        bytes!(":bob!b@host PRIVMSG #chan :\x01ACTION waves\x01"),
+        bytes!(":bob!b@host PRIVMSG me :psst"),
+        bytes!(":bob!b@host NICK robert"),
+        bytes!(":robert!b@host MODE #chan +o me"),
+        bytes!(":robert!b@host TOPIC #chan :new topic"),
+        bytes!(":robert!b@host QUIT :bye"),
+        bytes!(":server PING :x")
+    ];
+
+    #[test]
+    fn test_entries() {
+        let entries = feed(Lines);
+        let targets: ~[&[u8]] = entries.iter().map(|e| e.target.as_slice()).collect();
+        assert_eq!(targets, ~[bytes!("#chan"), bytes!("#chan"), bytes!("#chan"),
+                              bytes!("#chan"), bytes!("bob"), bytes!("#chan"), bytes!("#chan"),
+                              bytes!("#chan"), bytes!("#chan")]);
+        assert!(entries.iter().all(|e| e.line.tags.is_empty()));
+    }
+
+    #[test]
+    fn test_split_entries() {
+        let split = Split{
+            server1: bytes!("irc.a.net").to_owned(),
+            server2: bytes!("irc.b.net").to_owned(),
+            users: ~[User::parse(bytes!("a!a@host")), User::parse(bytes!("b!b@host"))],
+            channels: ~[(bytes!("#one").to_owned(),
+                         ~[bytes!("a").to_owned(), bytes!("B").to_owned()]),
+                        (bytes!("#two").to_owned(), ~[bytes!("a").to_owned()])],
+            time: Time
+        };
+        let raws = |entries: ~[LogEntry]| -> ~[~str] {
+            entries.iter().map(|e| {
+                format!("{} {}", str::from_utf8(e.target).unwrap(),
+                        str::from_utf8_owned(e.line.to_raw()).unwrap())
+            }).collect()
+        };
+        assert_eq!(raws(split_entries(&split, false, CaseMapRfc1459)),
+                   ~[~"#one :a!a@host QUIT :irc.a.net irc.b.net",
+                     ~"#one :B!b@host QUIT :irc.a.net irc.b.net",
+                     ~"#two :a!a@host QUIT :irc.a.net irc.b.net"]);
+        assert_eq!(raws(split_entries(&split, true, CaseMapRfc1459)),
+                   ~[~"#one :a!a@host JOIN #one", ~"#one :B!b@host JOIN #one",
+                     ~"#two :a!a@host JOIN #two"]);
+    }
+
+    #[test]
+    fn test_formats() {
+        let entries = feed(Lines);
+        let entries = entries.slice(1, 9).to_owned();
+        assert_eq!(format_all(Irssi, entries), ~"\
+09:30:00 -!- bob [b@host] has joined #chan
+09:30:00 <bob> hello there
+09:30:00  * bob waves
+09:30:00 <bob> psst
+09:30:00 -!- bob is now known as robert
+09:30:00 -!- mode/#chan [+o me] by robert
+09:30:00 -!- robert changed the topic of #chan to: new topic
+09:30:00 -!- robert [b@host] has quit [bye]
+");
+        assert_eq!(format_all(Weechat, entries.slice(0, 2)), ~"\
+2014-02-14 09:30:00\t-->\tbob (b@host) has joined #chan
+2014-02-14 09:30:00\tbob\thello there
+");
+        assert_eq!(format_all(Znc, entries.slice(5, 8)), ~"\
+[09:30:00] *** robert sets mode: +o me
+[09:30:00] *** robert changes topic to 'new topic'
+[09:30:00] *** Quits: robert (b@host) (bye)
+");
+        assert_eq!(format_all(JsonLines, entries.slice(1, 2)),
+                   ~"{\"time\":\"2014-02-14T09:30:00.000Z\",\"target\":\"#chan\",\
+                     \"line\":\":bob!b@host PRIVMSG #chan :hello there\"}\n");
+    }
+
+    #[test]
+    fn test_logger() {
+        let dir = TempDir::new("irclog").unwrap();
+        let mut logger = Logger::new(dir.path().clone(), Znc);
+        logger.utc = true;
+        let mut state = State::new();
+        for &raw in Lines.iter() {
+            let line = Line::parse(raw).unwrap();
+            let time = line.server_time().unwrap_or(Timespec::new(Time, 0));
+            state.handle_line(bytes!("me"), &line);
+            logger.log_line(&state, bytes!("me"), &line, time).unwrap();
+        }
+        // the next day goes to a new file
+        let line = Line::parse(bytes!(":carol!c@host JOIN #Chan")).unwrap();
+        state.handle_line(bytes!("me"), &line);
+        logger.log_line(&state, bytes!("me"), &line, Timespec::new(Time + 86400, 0)).unwrap();
+        logger.close();
+
+        let read = |path: &str| {
+            let path = dir.path().join(path);
+            let text = File::open(&path).and_then(|mut f| f.read_to_end()).unwrap();
+            str::from_utf8_owned(text).unwrap()
+        };
+        assert_eq!(read("#chan/2014-02-14.log").lines().count(), 8);
+        assert!(read("#chan/2014-02-14.log").contains("[09:31:00] <bob> hello there\n"));
+        assert_eq!(read("bob/2014-02-14.log"), ~"[09:30:00] <bob> psst\n");
+        assert_eq!(read("#chan/2014-02-15.log"), ~"[09:30:00] *** Joins: carol (c@host)\n");
+
+        let path = dir.path().join("#chan/2014-02-14.log");
+        let entries = read_log(&path, Znc, true, Some(bytes!("me"))).unwrap();
+        assert_eq!(entries.len(), 8);
+        assert_eq!(entries[0].target.as_slice(), bytes!("#chan"));
+        assert_eq!(entries[2].time, Timespec::new(Time + 60, 0));
+        assert_eq!(entries[2].line.to_raw(), bytes!(":bob PRIVMSG #chan :hello there").to_owned());
+        let entries = read_log(&dir.path().join("bob/2014-02-14.log"), Znc, true,
+                               Some(bytes!("me"))).unwrap();
+        assert_eq!(entries[0].line.to_raw(), bytes!(":bob PRIVMSG me psst").to_owned());
+    }
+
+    #[test]
+    fn test_parse() {
+        let original = feed(Lines);
+        let original = original.slice(1, 9);
+        let expected = [":bob!b@host JOIN #chan",
+                        ":bob PRIVMSG #chan :hello there",
+                        ":bob PRIVMSG #chan :\x01ACTION waves\x01",
+                        ":bob PRIVMSG me psst",
+                        ":bob NICK robert",
+                        ":robert MODE #chan +o me",
+                        ":robert TOPIC #chan :new topic",
+                        ":robert!b@host QUIT bye"];
+        for &format in [Irssi, Weechat, Znc, JsonLines].iter() {
+            for (entry, &raw) in original.iter().zip(expected.iter()) {
+                let target = entry.target.as_slice();
+                let mut parser = LogParser::new(format, target, Some("2014-02-14"));
+                parser.utc = true;
+                parser.me = Some(bytes!("me").to_owned());
+                let text = format_entry(format, entry, true).unwrap();
+                let parsed = parser.parse_line(text.slice_to(text.len() - 1)).unwrap();
+                assert_eq!(parsed.time, entry.time);
+                assert_eq!(parsed.target, entry.target);
+                if format == JsonLines {
+                    assert_eq!(parsed.line, entry.line);
+                } else {
+                    assert_eq!(str::from_utf8_owned(parsed.line.to_raw()).unwrap(), raw.to_owned());
+                }
+            }
+        }
+
+        // our own messages in a query stay addressed to the other user
+        let mut parser = LogParser::new(Irssi, bytes!("bob"), Some("2014-02-14"));
+        parser.me = Some(bytes!("me").to_owned());
+        let parsed = parser.parse_line(bytes!("09:30:00 <me> hi")).unwrap();
+        assert_eq!(parsed.line.to_raw(), bytes!(":me PRIVMSG bob hi").to_owned());
+    }
+
+    #[test]
+    fn test_parse_irssi() {
+        let log = ["--- Log opened Fri Feb 14 09:00:00 2014",
+                   "09:30 <@bob> hi",
+                   "09:31 -!- carol [c@host] has joined #chan",
+                   "--- Day changed Sat Feb 15 2014",
+                   "00:01 < dave> late",
+                   "garbage"];
+        let mut parser = LogParser::new(Irssi, bytes!("#chan"), None);
+        parser.utc = true;
+        let entries: ~[LogEntry] = log.iter().filter_map(|l| parser.parse_line(l.as_bytes()))
+                                      .collect();
+        let times: ~[i64] = entries.iter().map(|e| e.time.sec).collect();
+        assert_eq!(times, ~[Time, Time + 60, Time + 52260]);
+        let raws: ~[~str] = entries.iter().map(|e| {
+            str::from_utf8_owned(e.line.to_raw()).unwrap()
+        }).collect();
+        assert_eq!(raws, ~[~":bob PRIVMSG #chan hi", ~":carol!c@host JOIN #chan",
+                           ~":dave PRIVMSG #chan late"]);
+    }
+}
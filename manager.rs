@@ -0,0 +1,416 @@
+//! Management of connections to multiple IRC networks
+
+use std::hashmap::HashMap;
+use std::io::timer;
+use std::task;
+use std::comm;
+use conn;
+use conn::{Conn, Event, Cmd, Error, IRCCode, Line, LineReceived};
+use config::OwnedOptions;
+
+/// Connection settings for a single network owned by a Manager.
+///
+/// The options are owned so the network can be run on its own task. A loaded
+/// config::Config's options can be used directly.
+pub struct Network {
+    /// The name used to identify this network
+    name: ~str,
+    /// The connection settings. Options.commands is set by the Manager.
+    options: OwnedOptions,
+    /// How to handle dropped connections
+    reconnect: Reconnect
+}
+
+impl Network {
+    /// Returns a new Network with default values
+    pub fn new(name: &str, host: &str, port: u16) -> Network {
+        Network {
+            name: name.to_owned(),
+            options: OwnedOptions::new(host, port),
+            reconnect: Reconnect::new()
+        }
+    }
+}
+
+/// Reconnect policy for a Network
+pub struct Reconnect {
+    /// The number of consecutive reconnect attempts to make before giving up.
+    /// A value of 0 disables reconnecting.
+    attempts: uint,
+    /// The delay in milliseconds before each reconnect attempt
+    delay: u64
+}
+
+impl Reconnect {
+    /// Returns a new Reconnect with default values
+    pub fn new() -> Reconnect {
+        Reconnect {
+            attempts: 5,
+            delay: 10000
+        }
+    }
+}
+
+/// Events that can be handled in the Manager callback
+pub enum NetEvent {
+    /// An event from the network's current connection
+    ConnEvent(Event),
+    /// The connection could not be established, or failed with an error
+    ConnError(Error),
+    /// A reconnect attempt is about to be made.
+    /// The argument is the attempt number, starting at 1.
+    Reconnecting(uint),
+    /// The network has shut down and will not reconnect
+    Stopped
+}
+
+enum Msg {
+    MsgEvent(~str, NetEvent),
+    MsgAttach(~str, Chan<Cmd>)
+}
+
+struct NetHandle {
+    cmds: Option<Chan<Cmd>>,
+    stop: Chan<()>
+}
+
+/// Manager owns connections to several networks, keyed by network name.
+///
+/// Each network is run on its own task. Events from all networks are delivered
+/// to a single callback by run(), tagged with the name of the originating network.
+pub struct Manager {
+    priv networks: HashMap<~str, NetHandle>,
+    priv port: Option<Port<Msg>>,
+    priv chan: Chan<Msg>
+}
+
+impl Manager {
+    /// Returns a new Manager with no networks
+    pub fn new() -> Manager {
+        let (port, chan) = Chan::new();
+        Manager {
+            networks: HashMap::new(),
+            port: Some(port),
+            chan: chan
+        }
+    }
+
+    /// Starts connecting to the given network.
+    /// Returns `false` if a network with the same name is already managed.
+    pub fn add(&mut self, net: Network) -> bool {
+        if self.networks.contains_key(&net.name) {
+            return false;
+        }
+        let (stop_port, stop_chan) = Chan::new();
+        self.networks.insert(net.name.clone(), NetHandle{ cmds: None, stop: stop_chan });
+
+        let mut net_task = task::task();
+        net_task.name(format!("libirc network {}", net.name));
+        let chan = self.chan.clone();
+        net_task.spawn(proc() {
+            run_network(net, stop_port, chan);
+        });
+        true
+    }
+
+    /// Returns the names of all managed networks
+    pub fn networks<'a>(&'a self) -> ~[&'a str] {
+        self.networks.keys().map(|k| k.as_slice()).collect()
+    }
+
+    /// Returns `true` if the named network is currently managed
+    pub fn has_network(&self, network: &str) -> bool {
+        self.networks.contains_key_equiv(&network)
+    }
+
+    /// Sends a proc to be executed on the named network's connection task.
+    /// Returns `false` if the network is unknown or not currently connected.
+    pub fn send(&mut self, network: &str, cmd: Cmd) -> bool {
+        match self.networks.find_equiv(&network) {
+            Some(&NetHandle{ cmds: Some(ref chan), .. }) => chan.try_send(cmd),
+            _ => false
+        }
+    }
+
+    /// Sends a raw command to the named network.
+    /// Returns `false` if the network is unknown or not currently connected.
+    pub fn send_raw(&mut self, network: &str, raw: &[u8]) -> bool {
+        let raw = raw.to_owned();
+        self.send(network, proc(conn: &mut Conn) { conn.send_raw(raw) })
+    }
+
+    /// Sends a PRIVMSG to the named network.
+    /// Returns `false` if the network is unknown or not currently connected.
+    pub fn privmsg(&mut self, network: &str, dst: &[u8], msg: &[u8]) -> bool {
+        let (dst, msg) = (dst.to_owned(), msg.to_owned());
+        self.send(network, proc(conn: &mut Conn) { conn.privmsg(dst, msg) })
+    }
+
+    /// Shuts down the named network. The connection, if any, is sent a QUIT
+    /// and will not be reconnected.
+    /// Pass [] for the message to use the default.
+    pub fn shutdown(&mut self, network: &str, msg: &[u8]) {
+        match self.networks.find_equiv(&network) {
+            None => return,
+            Some(handle) => handle.stop.try_send(())
+        };
+        let msg = msg.to_owned();
+        self.send(network, proc(conn: &mut Conn) { conn.quit(msg) });
+    }
+
+    /// Shuts down all networks.
+    /// Pass [] for the message to use the default.
+    pub fn shutdown_all(&mut self, msg: &[u8]) {
+        let names: ~[~str] = self.networks.keys().map(|k| k.clone()).collect();
+        for name in names.iter() {
+            self.shutdown(name.as_slice(), msg);
+        }
+    }
+
+    /// Runs the event loop, delivering events from every network to the callback.
+    /// This method will not return until every network has stopped.
+    pub fn run(&mut self, cb: |&mut Manager, &str, NetEvent|) {
+        let port = match self.port.take() {
+            None => fail!("Manager::run() called recursively"),
+            Some(port) => port
+        };
+        while !self.networks.is_empty() {
+            match port.recv() {
+                MsgAttach(name, chan) => {
+                    match self.networks.find_mut(&name) {
+                        None => (),
+                        Some(handle) => handle.cmds = Some(chan)
+                    }
+                }
+                MsgEvent(name, event) => {
+                    match event {
+                        ConnEvent(conn::Disconnected) | ConnError(_) => {
+                            match self.networks.find_mut(&name) {
+                                None => (),
+                                Some(handle) => handle.cmds = None
+                            }
+                        }
+                        Stopped => {
+                            self.networks.remove(&name);
+                        }
+                        _ => ()
+                    }
+                    cb(self, name.as_slice(), event);
+                }
+            }
+        }
+        self.port = Some(port);
+    }
+}
+
+fn run_network(net: Network, stop: Port<()>, chan: Chan<Msg>) {
+    fn stopped(stop: &Port<()>) -> bool {
+        match stop.try_recv() {
+            comm::Empty => false,
+            comm::Data(()) | comm::Disconnected => true
+        }
+    }
+
+    let name = net.name.as_slice();
+    let mut attempt = 0u;
+    // channels to rejoin after reconnecting
+    let mut channels: ~[(~[u8], ~[u8])] = ~[];
+    loop {
+        let (cmd_port, cmd_chan) = Chan::new();
+        chan.send(MsgAttach(name.to_owned(), cmd_chan));
+
+        let mut cmd_port = Some(cmd_port);
+        let mut logged_in = false;
+        let res = net.options.with_options(|opts| {
+            let mut opts = opts;
+            opts.commands = cmd_port.take();
+            conn::connect(opts, |conn, event| {
+                match event {
+                    LineReceived(Line{ command: IRCCode(1), .. }) => {
+                        // we've logged in, so start counting reconnect attempts over
+                        attempt = 0;
+                        logged_in = true;
+                        // the autojoin channels are joined by the Conn itself
+                        let casemap = conn.state().casemapping();
+                        let rejoin: ~[(~[u8], ~[u8])] = {
+                            let joined = conn.joined_channels();
+                            channels.iter().filter(|&&(ref c, _)| {
+                                !joined.iter().any(|&(ref j, _)| casemap.equiv(*j, *c))
+                            }).map(|c| c.clone()).collect()
+                        };
+                        for &(ref chan, ref key) in rejoin.iter() {
+                            conn.join(*chan, *key);
+                        }
+                    }
+                    conn::Disconnected => {
+                        // a connection that dropped before logging in never joined anything,
+                        // so keep the channels for the next attempt
+                        if logged_in {
+                            channels = conn.joined_channels().to_owned();
+                        }
+                    }
+                    _ => ()
+                }
+                chan.send(MsgEvent(name.to_owned(), ConnEvent(event)));
+            })
+        });
+        match res {
+            Ok(()) => (),
+            Err(e) => chan.send(MsgEvent(name.to_owned(), ConnError(e)))
+        }
+
+        if stopped(&stop) || attempt >= net.reconnect.attempts {
+            break;
+        }
+        attempt += 1;
+        chan.send(MsgEvent(name.to_owned(), Reconnecting(attempt)));
+        timer::sleep(net.reconnect.delay);
+        if stopped(&stop) {
+            break;
+        }
+    }
+    chan.send(MsgEvent(name.to_owned(), Stopped));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manager, Network, ConnEvent, ConnError, Reconnecting, Stopped};
+    use conn::{Conn, LineReceived, IRCCmd};
+    use mock;
+    use mock::{MockServer, Expect, ExpectEventually, Close};
+
+    #[test]
+    fn test_rejoin() {
+        let server = MockServer::start_sessions(~[
+            ~[
+                ExpectEventually(~"USER *"),
+                mock::Send(~":irc.test 001 ircnick :Welcome"),
+                Expect(~"JOIN #auto"),
+                mock::Send(~":ircnick!ircuser@host JOIN #auto"),
+                Expect(~"JOIN #extra key"),
+                Close
+            ],
+            // dropped before registration
+            ~[
+                ExpectEventually(~"USER *"),
+                Close
+            ],
+            ~[
+                ExpectEventually(~"USER *"),
+                mock::Send(~":irc.test 001 ircnick :Welcome"),
+                Expect(~"JOIN #auto"),
+                Expect(~"JOIN #extra key"),
+                mock::Send(~":bob!bob@host PRIVMSG ircnick :bye"),
+                Expect(~"QUIT"),
+                Close
+            ]
+        ]).unwrap();
+
+        let mut net = Network::new("test", "127.0.0.1", server.port());
+        net.options.autojoin = ~[(~"#auto", ~"")];
+        net.reconnect.delay = 10;
+        let mut manager = Manager::new();
+        assert!(manager.add(net));
+        let mut reconnects = 0;
+        manager.run(|manager, network, event| {
+            assert_eq!(network, "test");
+            match event {
+                ConnEvent(LineReceived(ref line)) if line.command == IRCCmd(~"JOIN") => {
+                    manager.send(network, proc(conn: &mut Conn) {
+                        conn.join(bytes!("#extra"), bytes!("key"));
+                    });
+                }
+                ConnEvent(LineReceived(ref line)) if line.command == IRCCmd(~"PRIVMSG") => {
+                    manager.shutdown(network, []);
+                }
+                Reconnecting(_) => reconnects += 1,
+                _ => ()
+            }
+        });
+        assert_eq!(reconnects, 2);
+        server.finish().assert_ok();
+    }
+
+    #[test]
+    fn test_networks() {
+        let mut scripts = ~[];
+        for name in ["one", "two"].iter() {
+            scripts.push(MockServer::start(~[
+                Expect(~"CAP LS 302"),
+                Expect(format!("PASS *{}-pass", *name)),
+                Expect(format!("NICK {}-nick", *name)),
+                ExpectEventually(~"USER *"),
+                // each server names itself, so the events can be checked for their network
+                mock::Send(format!(":irc.test 001 {}-nick :Welcome", *name)),
+                mock::Send(format!(":bob!bob@host PRIVMSG {}-nick :{}", *name, *name)),
+                Expect(format!("PRIVMSG bob :from {}", *name)),
+                Expect(~"QUIT :bye"),
+                Close
+            ]).unwrap());
+        }
+
+        let mut manager = Manager::new();
+        for (name, server) in ["one", "two"].iter().zip(scripts.iter()) {
+            let mut net = Network::new(*name, "127.0.0.1", server.port());
+            net.options.nick = format!("{}-nick", *name);
+            net.options.password = Some(format!("{}-pass", *name));
+            assert!(manager.add(net));
+        }
+        assert!(!manager.add(Network::new("one", "127.0.0.1", 6667)));
+        assert!(manager.has_network("two"));
+        assert!(!manager.has_network("three"));
+
+        let mut stopped = ~[];
+        manager.run(|manager, network, event| {
+            match event {
+                ConnEvent(LineReceived(ref line)) if line.command == IRCCmd(~"PRIVMSG") => {
+                    assert_eq!(line.args[1].as_slice(), network.as_bytes());
+                    let reply = format!("PRIVMSG bob :from {}", network);
+                    assert!(manager.send_raw(network, reply.as_bytes()));
+                    assert!(!manager.send_raw("three", reply.as_bytes()));
+                    manager.shutdown(network, bytes!("bye"));
+                }
+                ConnError(_) | Reconnecting(_) => fail!("network {} dropped", network),
+                Stopped => stopped.push(network.to_owned()),
+                _ => ()
+            }
+        });
+        stopped.sort();
+        assert_eq!(stopped, ~[~"one", ~"two"]);
+        assert!(manager.networks().is_empty());
+        for server in scripts.move_iter() {
+            server.finish().assert_ok();
+        }
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let server = MockServer::start(~[
+            ExpectEventually(~"USER *"),
+            mock::Send(~":irc.test 001 ircnick :Welcome"),
+            Close
+        ]).unwrap();
+
+        let mut net = Network::new("test", "127.0.0.1", server.port());
+        net.reconnect.delay = 200;
+        let mut manager = Manager::new();
+        assert!(manager.add(net));
+        let mut events = ~[];
+        manager.run(|manager, network, event| {
+            match event {
+                Reconnecting(n) => {
+                    events.push(format!("reconnecting {}", n));
+                    // there is no connection to send to, but the pending reconnect stops
+                    assert!(!manager.send_raw(network, bytes!("PING :nobody")));
+                    manager.shutdown(network, []);
+                }
+                ConnError(_) => events.push(~"error"),
+                Stopped => events.push(~"stopped"),
+                _ => ()
+            }
+        });
+        assert_eq!(events, ~[~"reconnecting 1", ~"stopped"]);
+        assert!(!manager.has_network("test"));
+        server.finish().assert_ok();
+    }
+}
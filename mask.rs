@@ -0,0 +1,187 @@
+//! Matching of users against hostmasks and extended ban masks
+
+use {User, CaseMapping};
+use state::UserInfo;
+
+/// Matches `text` against a glob pattern.
+///
+/// `*` matches any sequence of bytes and `?` matches any single byte. A backslash
+/// escapes the following byte, so `\*` only matches a literal `*`.
+/// Bytes are compared using the given case mapping.
+pub fn glob_match(pattern: &[u8], text: &[u8], casemap: CaseMapping) -> bool {
+    // position in the pattern just after the last *, and the text position it matched up to
+    let mut star: Option<(uint, uint)> = None;
+    let (mut p, mut t) = (0u, 0u);
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] as char {
+                '*' => {
+                    p += 1;
+                    star = Some((p, t));
+                    continue;
+                }
+                '?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                c => {
+                    let (lit, len) = if c == '\\' && p+1 < pattern.len() {
+                        (pattern[p+1], 2)
+                    } else {
+                        (pattern[p], 1)
+                    };
+                    if casemap.to_lower_byte(lit) == casemap.to_lower_byte(text[t]) {
+                        p += len;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        // mismatch. Backtrack to the last * and let it consume one more byte.
+        match star {
+            None => return false,
+            Some((sp, st)) => {
+                p = sp;
+                t = st + 1;
+                star = Some((sp, st + 1));
+            }
+        }
+    }
+    pattern.slice_from(p).iter().all(|&b| b == '*' as u8)
+}
+
+/// Expands a partial hostmask into a full nick!user@host mask,
+/// e.g. `nick` becomes `nick!*@*` and `*@host` becomes `*!*@host`.
+pub fn normalize(mask: &[u8]) -> ~[u8] {
+    let bang = mask.contains(&('!' as u8));
+    let at = mask.contains(&('@' as u8));
+    match (bang, at) {
+        (true, true) => mask.to_owned(),
+        (true, false) => mask + bytes!("@*"),
+        (false, true) => bytes!("*!") + mask,
+        (false, false) => mask + bytes!("!*@*")
+    }
+}
+
+/// A parsed ban, exception or invite mask
+#[deriving(Eq,Clone)]
+pub enum Mask {
+    /// A nick!user@host glob
+    HostMask(~[u8]),
+    /// `$a` or `$a:account` (also `~a:account`). Matches users logged in to services,
+    /// optionally only those whose account matches the given glob.
+    AccountMask(Option<~[u8]>),
+    /// `$r:realname` (also `~r:realname`). Matches users whose realname matches the glob.
+    RealnameMask(~[u8]),
+    /// `~q:mask` (also `$q:mask`). A quiet; matches the same users as the inner mask.
+    QuietMask(~Mask),
+    /// `$~a` and similar. Matches users the inner mask does not.
+    NotMask(~Mask),
+    /// An extended ban type that isn't understood. It never matches.
+    UnknownExtban(~[u8])
+}
+
+impl Mask {
+    /// Parses a mask, recognizing extended ban syntax
+    pub fn parse(mask: &[u8]) -> Mask {
+        if mask.len() < 2 || !(mask[0] == '$' as u8 || mask[0] == '~' as u8) ||
+           !(mask.len() == 2 || mask[2] == ':' as u8 || mask[1] == '~' as u8) {
+            return HostMask(normalize(mask));
+        }
+        if mask[0] == '$' as u8 && mask[1] == '~' as u8 {
+            return NotMask(~Mask::parse(bytes!("$") + mask.slice_from(2)));
+        }
+        let arg = if mask.len() > 3 { Some(mask.slice_from(3)) } else { None };
+        match (mask[1] as char, arg) {
+            ('a', arg) => AccountMask(arg.map(|a| a.to_owned())),
+            ('r', Some(arg)) => RealnameMask(arg.to_owned()),
+            ('q', Some(arg)) => QuietMask(~Mask::parse(arg)),
+            _ => UnknownExtban(mask.to_owned())
+        }
+    }
+
+    /// Returns `true` if the mask matches the user.
+    ///
+    /// Extended bans on accounts and realnames need the tracked UserInfo,
+    /// and never match if it isn't provided.
+    pub fn matches(&self, user: &User, info: Option<&UserInfo>, casemap: CaseMapping) -> bool {
+        match *self {
+            HostMask(ref mask) => glob_match(*mask, user.raw(), casemap),
+            AccountMask(ref mask) => {
+                match (info.and_then(|i| i.account.as_ref()), mask) {
+                    (None, _) => false,
+                    (Some(_), &None) => true,
+                    (Some(account), &Some(ref mask)) => glob_match(*mask, *account, casemap)
+                }
+            }
+            RealnameMask(ref mask) => {
+                info.and_then(|i| i.realname.as_ref())
+                    .map_or(false, |real| glob_match(*mask, *real, casemap))
+            }
+            QuietMask(ref mask) => mask.matches(user, info, casemap),
+            NotMask(ref mask) => !mask.matches(user, info, casemap),
+            UnknownExtban(_) => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_match, normalize, Mask, HostMask, AccountMask, RealnameMask, QuietMask,
+                NotMask};
+    use state::UserInfo;
+    use {User, CaseMapRfc1459, CaseMapAscii};
+
+    #[test]
+    fn test_glob_match() {
+        let t = |p: &[u8], s: &[u8]| glob_match(p, s, CaseMapRfc1459);
+        assert!(t(bytes!("*!*@*.example.com"), bytes!("nick!user@host.example.com")));
+        assert!(!t(bytes!("*!*@*.example.com"), bytes!("nick!user@example.com")));
+        assert!(t(bytes!("N?CK!*"), bytes!("nick!user@host")));
+        assert!(t(bytes!("nick[a]!*@*"), bytes!("NICK{A}!user@host")));
+        assert!(!glob_match(bytes!("nick[a]!*@*"), bytes!("NICK{A}!user@host"), CaseMapAscii));
+        assert!(t(bytes!("a\\*b"), bytes!("a*b")));
+        assert!(!t(bytes!("a\\*b"), bytes!("axb")));
+        assert!(t(bytes!("a\\?*"), bytes!("a?bc")));
+        assert!(t(bytes!("*a*b*"), bytes!("xxaxxbxx")));
+        assert!(!t(bytes!("*a*b"), bytes!("xxaxxbxx")));
+        assert!(t(bytes!("**"), bytes!("")));
+    }
+
+    #[test]
+    fn test_mask_parse() {
+        assert_eq!(normalize(bytes!("nick")), bytes!("nick!*@*").to_owned());
+        assert_eq!(normalize(bytes!("*@host")), bytes!("*!*@host").to_owned());
+        assert_eq!(Mask::parse(bytes!("$a")), AccountMask(None));
+        assert_eq!(Mask::parse(bytes!("~a:acct")), AccountMask(Some(bytes!("acct").to_owned())));
+        assert_eq!(Mask::parse(bytes!("$r:*bot*")), RealnameMask(bytes!("*bot*").to_owned()));
+        assert_eq!(Mask::parse(bytes!("~q:*!*@host")),
+                   QuietMask(~HostMask(bytes!("*!*@host").to_owned())));
+        assert_eq!(Mask::parse(bytes!("$~a")), NotMask(~AccountMask(None)));
+        assert_eq!(Mask::parse(bytes!("~nick!*@*")), HostMask(bytes!("~nick!*@*").to_owned()));
+    }
+
+    #[test]
+    fn test_mask_matches() {
+        let user = User::parse(bytes!("bob!bob@host.example.com"));
+        let info = UserInfo {
+            user: user.clone(),
+            realname: Some(bytes!("Bob the Bot").to_owned()),
+            account: Some(bytes!("Bobby").to_owned()),
+            away: None,
+            channels: ~[]
+        };
+        let m = |mask: &[u8], info: Option<&UserInfo>| {
+            Mask::parse(mask).matches(&user, info, CaseMapRfc1459)
+        };
+        assert!(m(bytes!("*@*.example.com"), None));
+        assert!(m(bytes!("$a:bobby"), Some(&info)));
+        assert!(!m(bytes!("$a:bobby"), None));
+        assert!(!m(bytes!("$~a"), Some(&info)));
+        assert!(m(bytes!("$r:*bot"), Some(&info)));
+        assert!(m(bytes!("~q:bob"), None));
+        assert!(!m(bytes!("$x:whatever"), Some(&info)));
+    }
+}
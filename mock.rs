@@ -0,0 +1,266 @@
+//! A scriptable IRC server for testing clients
+//!
+//! A MockServer listens on a local port and accepts a single connection, then plays
+//! through a script of lines to expect from the client and lines to send back to it.
+//! Once the script completes it keeps recording whatever the client sends until the
+//! client disconnects, and finish() returns everything the client sent.
+//! start_sessions() runs several scripts against successive connections, for testing
+//! clients that reconnect.
+//!
+//! Expected lines are glob patterns, as in mask::glob_match, so `USER * 8 * :*`
+//! matches any USER line requesting mode 8.
+
+use std::io::{IoResult, BufferedStream, Listener, Acceptor};
+use std::io::net::ip::{Ipv4Addr, SocketAddr};
+use std::io::net::tcp::TcpListener;
+use std::io::timer;
+use std::str;
+use mask::glob_match;
+use CaseMapAscii;
+
+/// A step in a MockServer script
+#[deriving(Clone)]
+pub enum Step {
+    /// Reads a line from the client, which must match the pattern
+    Expect(~str),
+    /// Reads lines from the client until one matches the pattern
+    ExpectEventually(~str),
+    /// Sends a line to the client. The line ending is added automatically.
+    Send(~str),
+    /// Waits the given number of milliseconds
+    Pause(u64),
+    /// Closes the connection, ending the script
+    Close
+}
+
+/// The outcome of a MockServer script
+pub struct Transcript {
+    /// Every line received from the client, without line endings
+    received: ~[~[u8]],
+    /// A description of the first step that failed, if any
+    error: Option<~str>
+}
+
+impl Transcript {
+    /// Returns `true` if the script ran to completion
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Fails the task if the script did not run to completion
+    pub fn assert_ok(&self) {
+        match self.error {
+            None => (),
+            Some(ref err) => fail!("mock server script failed: {}", *err)
+        }
+    }
+}
+
+/// A local IRC server that runs a script against the first client to connect
+pub struct MockServer {
+    priv port: u16,
+    priv result: Port<Transcript>
+}
+
+impl MockServer {
+    /// Starts a server on a free port on 127.0.0.1 that will run `script`
+    pub fn start(script: ~[Step]) -> IoResult<MockServer> {
+        MockServer::start_sessions(~[script])
+    }
+
+    /// Starts a server on a free port on 127.0.0.1 that runs each script in turn
+    /// against the next client to connect. A failed script ends the session.
+    pub fn start_sessions(scripts: ~[~[Step]]) -> IoResult<MockServer> {
+        let addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+        let mut listener = match TcpListener::bind(addr) {
+            Err(e) => return Err(e),
+            Ok(l) => l
+        };
+        let port = match listener.socket_name() {
+            Err(e) => return Err(e),
+            Ok(addr) => addr.port
+        };
+        let acceptor = match listener.listen() {
+            Err(e) => return Err(e),
+            Ok(a) => a
+        };
+        let (result_port, result_chan) = Chan::new();
+        spawn(proc() {
+            let mut acceptor = acceptor;
+            let sessions = scripts.len();
+            let mut received = ~[];
+            let mut error = None;
+            for (i, script) in scripts.move_iter().enumerate() {
+                let transcript = match acceptor.accept() {
+                    Err(e) => Transcript{ received: ~[], error: Some(e.to_str()) },
+                    Ok(stream) => run_script(BufferedStream::new(stream), script)
+                };
+                received.push_all_move(transcript.received);
+                match transcript.error {
+                    None => (),
+                    Some(err) => {
+                        error = Some(if sessions > 1 {
+                            format!("session {}: {}", i, err)
+                        } else {
+                            err
+                        });
+                        break;
+                    }
+                }
+            }
+            result_chan.send(Transcript{ received: received, error: error });
+        });
+        Ok(MockServer{ port: port, result: result_port })
+    }
+
+    /// Returns the port the server is listening on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for the script to complete and the client to disconnect,
+    /// and returns the transcript. With several sessions, the transcript holds
+    /// everything received over all of them.
+    pub fn finish(self) -> Transcript {
+        self.result.recv()
+    }
+}
+
+/// Escapes the glob characters in `line`, for expecting it exactly
+pub fn escape(line: &str) -> ~str {
+    let mut s = ~"";
+    for c in line.chars() {
+        if c == '*' || c == '?' || c == '\\' {
+            s.push_char('\\');
+        }
+        s.push_char(c);
+    }
+    s
+}
+
+fn run_script<S: Reader + Writer>(mut stream: BufferedStream<S>, script: ~[Step]) -> Transcript {
+    let mut received = ~[];
+    let mut error = None;
+    for (i, step) in script.move_iter().enumerate() {
+        let res = match step {
+            Expect(pattern) => {
+                match read_line(&mut stream) {
+                    None => Err(format!("step {}: expected `{}`, got EOF", i, pattern)),
+                    Some(line) => {
+                        let matched = glob_match(pattern.as_bytes(), line, CaseMapAscii);
+                        let res = if matched {
+                            Ok(())
+                        } else {
+                            Err(format!("step {}: expected `{}`, got `{}`", i, pattern,
+                                        str::from_utf8_lossy(line).as_slice()))
+                        };
+                        received.push(line);
+                        res
+                    }
+                }
+            }
+            ExpectEventually(pattern) => {
+                let mut res = Err(format!("step {}: expected `{}`, got EOF", i, pattern));
+                loop {
+                    match read_line(&mut stream) {
+                        None => break,
+                        Some(line) => {
+                            let matched = glob_match(pattern.as_bytes(), line, CaseMapAscii);
+                            received.push(line);
+                            if matched {
+                                res = Ok(());
+                                break;
+                            }
+                        }
+                    }
+                }
+                res
+            }
+            Send(line) => {
+                let res = stream.write(line.as_bytes()).and_then(|_| {
+                    stream.write(bytes!("\r\n"))
+                }).and_then(|_| stream.flush());
+                res.map_err(|e| format!("step {}: error sending: {}", i, e))
+            }
+            Pause(ms) => {
+                timer::sleep(ms);
+                Ok(())
+            }
+            Close => {
+                return Transcript{ received: received, error: None };
+            }
+        };
+        match res {
+            Ok(()) => (),
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        }
+    }
+    // record the rest of what the client sends
+    loop {
+        match read_line(&mut stream) {
+            None => break,
+            Some(line) => received.push(line)
+        }
+    }
+    Transcript{ received: received, error: error }
+}
+
+fn read_line<R: Buffer>(stream: &mut R) -> Option<~[u8]> {
+    match stream.read_until('\n' as u8) {
+        Ok(mut line) => {
+            while line.last().map_or(false, |&b| b == '\n' as u8 || b == '\r' as u8) {
+                line.pop();
+            }
+            Some(line)
+        }
+        // EOF, or the client went away
+        Err(_) => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MockServer, Expect, ExpectEventually, Send, Close};
+    use conn;
+    use conn::{Options, LineReceived, IRCCmd};
+
+    #[test]
+    fn test_handshake() {
+        let server = MockServer::start(~[
+            Expect(~"CAP LS 302"),
+            Expect(~"NICK ircnick"),
+            Expect(~"USER ircuser 8 * :rust-irclib user"),
+            Send(~":irc.test CAP * LS :multi-prefix sasl"),
+            Expect(~"CAP REQ :multi-prefix"),
+            Send(~":irc.test CAP * ACK :multi-prefix"),
+            Expect(~"CAP END"),
+            Send(~":irc.test 001 ircnick :Welcome ircnick!ircuser@host"),
+            Expect(~"JOIN #test"),
+            Send(~":ircnick!ircuser@host JOIN #test"),
+            ExpectEventually(~"QUIT :done"),
+            Close
+        ]).unwrap();
+
+        let mut opts = Options::new("127.0.0.1", server.port());
+        opts.autojoin = &[("#test", "")];
+        let mut joined = false;
+        let res = conn::connect(opts, |conn, event| {
+            match event {
+                LineReceived(ref line) if line.command == IRCCmd(~"JOIN") => {
+                    joined = true;
+                    conn.quit(bytes!("done"));
+                }
+                _ => ()
+            }
+        });
+        assert!(res.is_ok());
+        assert!(joined);
+
+        let transcript = server.finish();
+        transcript.assert_ok();
+        assert_eq!(transcript.received.last().map(|l| l.as_slice()), Some(bytes!("QUIT :done")));
+    }
+}
@@ -0,0 +1,324 @@
+//! Detection of netsplits and netjoins
+//!
+//! When a server link drops, every user on the far side quits with a reason of the form
+//! `server1 server2`, and when the link is restored they all rejoin. The Detector
+//! groups these bursts of lines into single events.
+
+use time;
+use conn::{Line, IRCCmd, Event, NetSplit, NetJoin};
+use state::State;
+use {User, CaseMapping, CaseMapRfc1459};
+
+// how long to remember a split while waiting for the users to rejoin, in seconds
+static SplitTimeout: i64 = 60 * 60;
+
+/// How long a Conn waits for more lines of a split or join before reporting it,
+/// in milliseconds
+pub static BurstTimeout: u64 = 1000;
+
+/// A group of users affected by a split or join between two servers
+#[deriving(Clone)]
+pub struct Split {
+    /// The server that stayed connected to us
+    server1: ~[u8],
+    /// The server that split off
+    server2: ~[u8],
+    /// The users that quit or rejoined
+    users: ~[User],
+    /// The channels the users quit from or rejoined, each with the nicks of the users
+    /// that were in it
+    channels: ~[(~[u8], ~[~[u8]])],
+    /// When the split or join was first seen, in seconds since the epoch
+    time: i64
+}
+
+/// Groups split QUITs and the following rejoins into NetSplit and NetJoin events.
+///
+/// Lines are fed to the Detector with handle_line(). A burst of split QUITs or rejoins
+/// is reported once it ends: when a burst for a different pair of servers starts, when
+/// a user of a split still being reported rejoins, or when flush() is called. Other
+/// lines, such as the MODEs that often accompany a netjoin, don't end a burst. Conn
+/// calls flush() once no lines of the burst have arrived for BurstTimeout, and when the
+/// connection terminates.
+pub struct Detector {
+    priv casemap: CaseMapping,
+    priv quitting: Option<Split>,
+    priv joining: Option<Split>,
+    priv splits: ~[Split]
+}
+
+/// Returns the pair of servers named by a QUIT reason, if it looks like a netsplit
+pub fn parse_split_reason<'a>(reason: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
+    fn is_server(s: &[u8]) -> bool {
+        !s.is_empty() && s.contains(&('.' as u8)) &&
+            !s.starts_with(bytes!(".")) && !s.ends_with(bytes!(".")) &&
+            s.iter().all(|&b| b > ' ' as u8 && b != ':' as u8 && b != '/' as u8) &&
+            !s.windows(2).any(|w| w == bytes!(".."))
+    }
+    let idx = match reason.position_elem(&(' ' as u8)) {
+        None => return None,
+        Some(idx) => idx
+    };
+    let (a, b) = (reason.slice_to(idx), reason.slice_from(idx+1));
+    if is_server(a) && is_server(b) && a != b {
+        Some((a, b))
+    } else {
+        None
+    }
+}
+
+// records that the user with the given nick quit from or rejoined the channel
+fn add_member(split: &mut Split, chan: &[u8], nick: &[u8], casemap: CaseMapping) {
+    let idx = split.channels.iter().position(|&(ref c, _)| casemap.equiv(*c, chan));
+    match idx {
+        None => split.channels.push((chan.to_owned(), ~[nick.to_owned()])),
+        Some(idx) => match split.channels[idx] {
+            (_, ref mut nicks) => {
+                if !nicks.iter().any(|n| casemap.equiv(*n, nick)) {
+                    nicks.push(nick.to_owned());
+                }
+            }
+        }
+    }
+}
+
+impl Detector {
+    /// Returns a new Detector
+    pub fn new() -> Detector {
+        Detector {
+            casemap: CaseMapRfc1459,
+            quitting: None,
+            joining: None,
+            splits: ~[]
+        }
+    }
+
+    /// Feeds a line to the detector. `state` must not have handled the line yet; it
+    /// provides the channels of quitting users and the case mapping for nicks.
+    ///
+    /// Any events that are now complete are appended to `events`. Returns `true` if
+    /// the line is part of a split or join, and so need not be shown on its own.
+    pub fn handle_line(&mut self, line: &Line, state: &State, events: &mut ~[Event]) -> bool {
+        let casemap = state.casemapping();
+        self.casemap = casemap;
+        let now = time::get_time().sec;
+        let user = match line.prefix {
+            Some(ref user) => user,
+            None => return false
+        };
+        match (&line.command, line.args.as_slice()) {
+            (&IRCCmd(~"QUIT"), [ref reason]) => {
+                match parse_split_reason(*reason) {
+                    None => (),
+                    Some((a, b)) => {
+                        self.flush_joining(events);
+                        let same = self.quitting.as_ref().map_or(false, |s| {
+                            s.server1.as_slice() == a && s.server2.as_slice() == b
+                        });
+                        if !same {
+                            self.flush_quitting(events);
+                            self.quitting = Some(Split{
+                                server1: a.to_owned(),
+                                server2: b.to_owned(),
+                                users: ~[],
+                                channels: ~[],
+                                time: now
+                            });
+                        }
+                        let quitting = self.quitting.get_mut_ref();
+                        quitting.users.push(user.clone());
+                        match state.user(user.nick()) {
+                            None => (),
+                            Some(info) => for chan in info.channels.iter() {
+                                add_member(quitting, *chan, user.nick(), casemap);
+                            }
+                        }
+                        return true;
+                    }
+                }
+            }
+            (&IRCCmd(~"JOIN"), [ref chan, ..]) => {
+                // a split has to be complete before its users can rejoin
+                let rejoining = self.quitting.as_ref().map_or(false, |s| {
+                    s.users.iter().any(|u| casemap.equiv(u.nick(), user.nick()))
+                });
+                if rejoining {
+                    self.flush_quitting(events);
+                }
+                self.splits.retain(|s| now - s.time < SplitTimeout);
+                let idx = self.splits.iter().position(|s| {
+                    s.users.iter().any(|u| casemap.equiv(u.nick(), user.nick()))
+                });
+                match idx {
+                    None => (),
+                    Some(idx) => {
+                        let same = self.joining.as_ref().map_or(false, |j| {
+                            j.server1 == self.splits[idx].server1 &&
+                                j.server2 == self.splits[idx].server2
+                        });
+                        if !same {
+                            self.flush_joining(events);
+                            self.joining = Some(Split{
+                                server1: self.splits[idx].server1.clone(),
+                                server2: self.splits[idx].server2.clone(),
+                                users: ~[],
+                                channels: ~[],
+                                time: now
+                            });
+                        }
+                        let joining = self.joining.get_mut_ref();
+                        // users rejoin each of their channels, but should only be listed once
+                        if !joining.users.iter().any(|u| casemap.equiv(u.nick(), user.nick())) {
+                            joining.users.push(user.clone());
+                        }
+                        add_member(joining, *chan, user.nick(), casemap);
+                        return true;
+                    }
+                }
+            }
+            _ => ()
+        }
+        false
+    }
+
+    /// Completes any split or join in progress, appending the events to `events`.
+    pub fn flush(&mut self, events: &mut ~[Event]) {
+        self.flush_quitting(events);
+        self.flush_joining(events);
+    }
+
+    fn flush_quitting(&mut self, events: &mut ~[Event]) {
+        match self.quitting.take() {
+            None => (),
+            Some(split) => {
+                self.splits.push(split.clone());
+                events.push(NetSplit(split));
+            }
+        }
+    }
+
+    fn flush_joining(&mut self, events: &mut ~[Event]) {
+        match self.joining.take() {
+            None => (),
+            Some(join) => {
+                // the rejoined users are no longer split
+                let casemap = self.casemap;
+                for split in self.splits.mut_iter() {
+                    split.users.retain(|u| {
+                        !join.users.iter().any(|j| casemap.equiv(j.nick(), u.nick()))
+                    });
+                }
+                self.splits.retain(|s| !s.users.is_empty());
+                events.push(NetJoin(join));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Detector, parse_split_reason};
+    use conn;
+    use conn::{Line, Options, NetSplit, NetJoin, Disconnected};
+    use mock;
+    use mock::{MockServer, Expect, ExpectEventually, Close};
+    use state::State;
+
+    #[test]
+    fn test_parse_split_reason() {
+        assert_eq!(parse_split_reason(bytes!("irc.a.net irc.b.net")),
+                   Some((bytes!("irc.a.net"), bytes!("irc.b.net"))));
+        assert_eq!(parse_split_reason(bytes!("*.net *.split")),
+                   Some((bytes!("*.net"), bytes!("*.split"))));
+        assert_eq!(parse_split_reason(bytes!("Quit: leaving")), None);
+        assert_eq!(parse_split_reason(bytes!("see you.. later.")), None);
+        assert_eq!(parse_split_reason(bytes!("http://a.com b.com")), None);
+        assert_eq!(parse_split_reason(bytes!("a.net a.net")), None);
+    }
+
+    #[test]
+    fn test_detector() {
+        let mut state = State::new();
+        for &line in [bytes!(":me!u@h JOIN #one"), bytes!(":me!u@h JOIN #two"),
+                      bytes!(":server 353 me = #one :me a B"),
+                      bytes!(":server 353 me = #two :me a")].iter() {
+            state.handle_line(bytes!("me"), &Line::parse(line).unwrap());
+        }
+        let mut detector = Detector::new();
+        let mut events = ~[];
+        {
+            let feed = |line: &[u8]| {
+                detector.handle_line(&Line::parse(line).unwrap(), &state, &mut events)
+            };
+            assert!(feed(bytes!(":a!a@a QUIT :irc.a.net irc.b.net")));
+            assert!(feed(bytes!(":b!b@b QUIT :irc.a.net irc.b.net")));
+            assert!(!feed(bytes!(":c!c@c QUIT :Quit: bye")));
+            assert!(feed(bytes!(":a!a@a JOIN #one")));
+            assert!(feed(bytes!(":A!a@a JOIN #two")));
+            assert!(!feed(bytes!(":c!c@c JOIN #one")));
+            assert!(!feed(bytes!("PING :server")));
+            assert!(!feed(bytes!(":irc.b.net MODE #one +o a")));
+            assert!(feed(bytes!(":b!b@b JOIN #one")));
+        }
+        // the split ended when its users started rejoining, but the rejoin is still going
+        assert_eq!(events.len(), 1);
+        detector.flush(&mut events);
+
+        assert_eq!(events.len(), 2);
+        match events[0] {
+            NetSplit(ref split) => {
+                assert_eq!(split.server1.as_slice(), bytes!("irc.a.net"));
+                assert_eq!(split.server2.as_slice(), bytes!("irc.b.net"));
+                assert_eq!(split.users.len(), 2);
+                let one = (bytes!("#one").to_owned(),
+                           ~[bytes!("a").to_owned(), bytes!("b").to_owned()]);
+                let two = (bytes!("#two").to_owned(), ~[bytes!("a").to_owned()]);
+                assert_eq!(split.channels, ~[one, two]);
+            }
+            _ => fail!("expected NetSplit")
+        }
+        match events[1] {
+            NetJoin(ref join) => {
+                let nicks: ~[&[u8]] = join.users.iter().map(|u| u.nick()).collect();
+                assert_eq!(nicks, ~[bytes!("a"), bytes!("b")]);
+                let one = (bytes!("#one").to_owned(),
+                           ~[bytes!("a").to_owned(), bytes!("b").to_owned()]);
+                let two = (bytes!("#two").to_owned(), ~[bytes!("A").to_owned()]);
+                assert_eq!(join.channels, ~[one, two]);
+            }
+            _ => fail!("expected NetJoin")
+        }
+    }
+
+    #[test]
+    fn test_flush() {
+        let server = MockServer::start(~[
+            ExpectEventually(~"USER *"),
+            mock::Send(~":irc.test 001 me :Welcome"),
+            mock::Send(~":a!a@a QUIT :irc.a.net irc.b.net"),
+            mock::Send(~":b!b@b QUIT :irc.a.net irc.b.net"),
+            // reported once no more lines arrive
+            Expect(~"PRIVMSG #chan :split 2"),
+            // reported when the connection terminates
+            mock::Send(~":c!c@c QUIT :irc.a.net irc.c.net"),
+            Close
+        ]).unwrap();
+
+        let mut opts = Options::new("127.0.0.1", server.port());
+        opts.netsplits = true;
+        let mut events = ~[];
+        conn::connect(opts, |conn, event| {
+            match event {
+                NetSplit(split) => {
+                    let msg = format!("split {}", split.users.len());
+                    conn.privmsg(bytes!("#chan"), msg.as_bytes());
+                    events.push(split.users.len());
+                }
+                Disconnected => events.push(0),
+                _ => ()
+            }
+        });
+        assert_eq!(events, ~[2, 1, 0]);
+        server.finish().assert_ok();
+    }
+}
@@ -0,0 +1,591 @@
+//! Plugins that run on their own tasks
+//!
+//! Each plugin is created from a factory function and runs on a separate task, so a
+//! plugin that fails only takes itself down. Plugins receive connection events,
+//! the commands they registered, and their own timers, and act on the connection
+//! through a Handle.
+//!
+//!     let mut host = PluginHost::new();
+//!     host.add("trivia", new_trivia, trivia_config);
+//!     irc::conn::connect(opts, |conn, event| { host.handle_event(conn, &event); })
+//!
+//! Commands are recognized by the host's Bot, so its prefixes and Acl apply to
+//! plugin commands too.
+
+use std::hashmap::HashMap;
+use std::io::timer;
+use conn::{Conn, Event, Handle, Command, Line, LineReceived, IRCCode, Connected, Disconnected};
+use bot::{Bot, Context, CommandHandler};
+use User;
+
+/// Configuration values for a single plugin
+#[deriving(Clone)]
+pub struct Config {
+    priv values: HashMap<~str, ~str>
+}
+
+impl Config {
+    /// Returns an empty Config
+    pub fn new() -> Config {
+        Config{ values: HashMap::new() }
+    }
+
+    /// Sets a value
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_owned(), value.to_owned());
+    }
+
+    /// Returns a value, if set
+    pub fn get<'a>(&'a self, key: &str) -> Option<&'a str> {
+        self.values.find_equiv(&key).map(|v| v.as_slice())
+    }
+
+    /// Returns the keys that are set
+    pub fn keys<'a>(&'a self) -> ~[&'a str] {
+        self.values.keys().map(|k| k.as_slice()).collect()
+    }
+}
+
+/// A command sent to a plugin
+#[deriving(Clone)]
+pub struct PluginCommand {
+    /// The name of the command, after alias resolution
+    name: ~str,
+    /// The command's arguments
+    args: ~[~[u8]],
+    /// The user who sent the command
+    sender: User,
+    /// Where replies should go: the channel, or the sender for a private message
+    reply_to: ~[u8]
+}
+
+/// Identifies a plugin's timer.
+///
+/// Plugin timers run on the plugin's task rather than the connection's, so unlike
+/// conn::TimerId timers they can be set before connecting and survive reconnects.
+#[deriving(Eq,Clone)]
+pub struct PluginTimer(uint);
+
+/// The interface plugins implement. Every method has a default that does nothing.
+///
+/// All methods run on the plugin's own task.
+pub trait Plugin {
+    /// Called when the plugin is loaded. Commands can only be registered here.
+    fn load(&mut self, _ctx: &mut PluginContext) {}
+    /// Called when the plugin is unloaded or reloaded
+    fn unload(&mut self, _ctx: &mut PluginContext) {}
+    /// Called when registration completes, before the 001 line is delivered to
+    /// on_event(). ctx.handle() is now available.
+    fn on_connect(&mut self, _ctx: &mut PluginContext) {}
+    /// Called when the connection terminates
+    fn on_disconnect(&mut self, _ctx: &mut PluginContext) {}
+    /// Called for every connection event other than Connected and Disconnected
+    fn on_event(&mut self, _ctx: &mut PluginContext, _event: &Event) {}
+    /// Called when one of the plugin's commands is used
+    fn on_command(&mut self, _ctx: &mut PluginContext, _cmd: &PluginCommand) {}
+    /// Called when one of the plugin's timers fires
+    fn on_timer(&mut self, _ctx: &mut PluginContext, _id: PluginTimer) {}
+}
+
+/// Typedef for functions that create plugins
+pub type Factory = fn(&Config) -> ~Plugin:Send;
+
+enum PluginMsg {
+    MsgLoad(Chan<~[(~str, ~str)]>),
+    MsgUnload,
+    MsgConnect(Handle),
+    MsgDisconnect,
+    MsgEvent(Event),
+    MsgCommand(PluginCommand),
+    MsgTimer(PluginTimer)
+}
+
+/// The plugin's view of the world, passed to every Plugin method
+pub struct PluginContext {
+    priv name: ~str,
+    priv config: Config,
+    priv handle: Option<Handle>,
+    priv chan: Chan<PluginMsg>,
+    // (id, interval) of the active timers. Repeating timers have an interval.
+    priv timers: ~[(PluginTimer, Option<u64>)],
+    priv next_timer: uint,
+    priv loading: bool,
+    priv commands: ~[(~str, ~str)]
+}
+
+impl PluginContext {
+    /// Returns the name the plugin was added under
+    pub fn name<'a>(&'a self) -> &'a str {
+        self.name.as_slice()
+    }
+
+    /// Returns the plugin's configuration
+    pub fn config<'a>(&'a self) -> &'a Config {
+        &self.config
+    }
+
+    /// Returns a Handle to the connection, if it is active
+    pub fn handle<'a>(&'a self) -> Option<&'a Handle> {
+        self.handle.as_ref()
+    }
+
+    /// Sends a command to the server.
+    /// Returns `false` if the connection isn't active.
+    pub fn send_command<V: Vector<u8>>(&self, cmd: Command, args: &[V], add_colon: bool) -> bool {
+        self.handle.as_ref().map_or(false, |h| h.send_command(cmd, args, add_colon))
+    }
+
+    /// Sends a PRIVMSG.
+    /// Returns `false` if the connection isn't active.
+    pub fn privmsg(&self, dst: &[u8], msg: &[u8]) -> bool {
+        self.handle.as_ref().map_or(false, |h| h.privmsg(dst, msg))
+    }
+
+    /// Replies to a command, in the channel or private message it came from
+    pub fn reply(&self, cmd: &PluginCommand, msg: &[u8]) -> bool {
+        self.privmsg(cmd.reply_to, msg)
+    }
+
+    /// Registers a command, which will be delivered to on_command().
+    /// Fails if called outside of Plugin::load().
+    ///
+    /// A command that is already registered with the host's Bot, by another plugin or
+    /// directly, is not taken over, and the plugin won't receive it.
+    pub fn register_command(&mut self, name: &str, help: &str) {
+        if !self.loading {
+            fail!("plugin commands can only be registered while loading");
+        }
+        self.commands.push((name.to_owned(), help.to_owned()));
+    }
+
+    /// Starts a timer that fires after `ms` milliseconds,
+    /// and then every `ms` milliseconds if `repeat` is set.
+    pub fn add_timer(&mut self, ms: u64, repeat: bool) -> PluginTimer {
+        let id = PluginTimer(self.next_timer);
+        self.next_timer += 1;
+        self.timers.push((id, if repeat { Some(ms) } else { None }));
+        self.schedule(id, ms);
+        id
+    }
+
+    /// Cancels a timer. Returns `false` if the timer had already fired or been cancelled.
+    pub fn cancel_timer(&mut self, id: PluginTimer) -> bool {
+        let len = self.timers.len();
+        self.timers.retain(|&(t, _)| t != id);
+        self.timers.len() != len
+    }
+
+    fn schedule(&self, id: PluginTimer, ms: u64) {
+        let chan = self.chan.clone();
+        spawn(proc() {
+            timer::sleep(ms);
+            chan.try_send(MsgTimer(id));
+        });
+    }
+}
+
+fn run_plugin(mut plugin: ~Plugin:Send, mut ctx: PluginContext, port: Port<PluginMsg>) {
+    loop {
+        let msg = match port.recv_opt() {
+            None => break,
+            Some(msg) => msg
+        };
+        match msg {
+            MsgLoad(reply) => {
+                ctx.loading = true;
+                ctx.commands = ~[];
+                plugin.load(&mut ctx);
+                ctx.loading = false;
+                reply.send(ctx.commands.clone());
+            }
+            MsgUnload => {
+                plugin.unload(&mut ctx);
+                break;
+            }
+            MsgConnect(handle) => {
+                ctx.handle = Some(handle);
+                plugin.on_connect(&mut ctx);
+            }
+            MsgDisconnect => {
+                plugin.on_disconnect(&mut ctx);
+                ctx.handle = None;
+            }
+            MsgEvent(event) => plugin.on_event(&mut ctx, &event),
+            MsgCommand(cmd) => plugin.on_command(&mut ctx, &cmd),
+            MsgTimer(id) => {
+                let timer = ctx.timers.iter().position(|&(t, _)| t == id);
+                match timer {
+                    None => (), // cancelled
+                    Some(idx) => {
+                        match ctx.timers[idx] {
+                            (_, Some(ms)) => ctx.schedule(id, ms),
+                            (_, None) => { ctx.timers.remove(idx); }
+                        }
+                        plugin.on_timer(&mut ctx, id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Forwards a plugin's command from the host's Bot to the plugin's task
+struct Forward {
+    chan: Chan<PluginMsg>,
+    // identifies the plugin instance, so its commands are only removed by it
+    owner: uint
+}
+
+impl CommandHandler for Forward {
+    fn owner(&self) -> Option<uint> {
+        Some(self.owner)
+    }
+
+    fn run(&mut self, ctx: &mut Context) {
+        self.chan.try_send(MsgCommand(PluginCommand{
+            name: ctx.command.clone(),
+            args: ctx.args.clone(),
+            sender: ctx.sender.clone(),
+            reply_to: ctx.reply_to.clone()
+        }));
+    }
+}
+
+struct Entry {
+    name: ~str,
+    factory: Factory,
+    config: Config,
+    chan: Option<Chan<PluginMsg>>,
+    owner: uint,
+    commands: ~[~str]
+}
+
+/// Runs plugins and delivers connection events to them
+pub struct PluginHost {
+    /// The Bot that recognizes commands. Its settings apply to plugin commands,
+    /// and commands can also be registered with it directly.
+    bot: Bot,
+    priv plugins: ~[Entry],
+    priv handle: Option<Handle>,
+    priv next_owner: uint
+}
+
+impl PluginHost {
+    /// Returns a new PluginHost with no plugins
+    pub fn new() -> PluginHost {
+        PluginHost {
+            bot: Bot::new(),
+            plugins: ~[],
+            handle: None,
+            next_owner: 0
+        }
+    }
+
+    /// Creates a plugin with the factory and loads it. Both happen on the plugin's
+    /// own task. Any plugin already added under the same name is unloaded first.
+    ///
+    /// Returns `false` if the factory or the plugin failed while loading.
+    pub fn add(&mut self, name: &str, factory: Factory, config: Config) -> bool {
+        self.remove(name);
+        self.plugins.push(Entry{
+            name: name.to_owned(),
+            factory: factory,
+            config: config,
+            chan: None,
+            owner: 0,
+            commands: ~[]
+        });
+        let idx = self.plugins.len() - 1;
+        self.start(idx)
+    }
+
+    /// Unloads a plugin and forgets it. Returns `false` if it was not found.
+    pub fn remove(&mut self, name: &str) -> bool {
+        match self.find(name) {
+            None => false,
+            Some(idx) => {
+                self.stop(idx);
+                self.plugins.remove(idx);
+                true
+            }
+        }
+    }
+
+    /// Unloads a plugin and loads a new instance of it, with the same config.
+    /// This also restarts a plugin that has failed.
+    ///
+    /// Returns `false` if the plugin was not found or failed while loading.
+    pub fn reload(&mut self, name: &str) -> bool {
+        match self.find(name) {
+            None => false,
+            Some(idx) => {
+                self.stop(idx);
+                self.start(idx)
+            }
+        }
+    }
+
+    /// Returns `true` if the plugin is loaded and hasn't failed
+    pub fn is_running(&self, name: &str) -> bool {
+        self.find(name).map_or(false, |idx| self.plugins[idx].chan.is_some())
+    }
+
+    /// Returns the names of all plugins, running or not
+    pub fn plugins(&self) -> ~[&str] {
+        self.plugins.iter().map(|p| p.name.as_slice()).collect()
+    }
+
+    /// Delivers an event to the plugins.
+    /// Returns `true` if the event was a command, which has been handled.
+    pub fn handle_event(&mut self, conn: &mut Conn, event: &Event) -> bool {
+        // the server won't accept anything but registration until 001,
+        // so that's when plugins are told they're connected
+        match *event {
+            LineReceived(Line{ command: IRCCode(1), .. }) => {
+                self.handle = conn.handle();
+                for idx in range(0, self.plugins.len()) {
+                    match self.handle.clone() {
+                        None => (),
+                        Some(handle) => self.send(idx, MsgConnect(handle))
+                    }
+                }
+            }
+            _ => ()
+        }
+        match *event {
+            Connected => false,
+            Disconnected => {
+                self.handle = None;
+                for idx in range(0, self.plugins.len()) {
+                    self.send(idx, MsgDisconnect);
+                }
+                false
+            }
+            _ => {
+                if self.bot.handle_event(conn, event) {
+                    return true;
+                }
+                for idx in range(0, self.plugins.len()) {
+                    self.send(idx, MsgEvent(event.clone()));
+                }
+                false
+            }
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<uint> {
+        self.plugins.iter().position(|p| p.name.as_slice() == name)
+    }
+
+    // Sends a message to a plugin, and notices if it has failed
+    fn send(&mut self, idx: uint, msg: PluginMsg) {
+        let ok = match self.plugins[idx].chan {
+            None => return,
+            Some(ref chan) => chan.try_send(msg)
+        };
+        if !ok {
+            self.stopped(idx);
+        }
+    }
+
+    fn start(&mut self, idx: uint) -> bool {
+        let (port, chan) = Chan::new();
+        let ctx = PluginContext {
+            name: self.plugins[idx].name.clone(),
+            config: self.plugins[idx].config.clone(),
+            handle: None,
+            chan: chan.clone(),
+            timers: ~[],
+            next_timer: 0,
+            loading: false,
+            commands: ~[]
+        };
+        let factory = self.plugins[idx].factory;
+        spawn(proc() {
+            let plugin = factory(&ctx.config);
+            run_plugin(plugin, ctx, port);
+        });
+
+        // the reply is lost if the factory or load() fails
+        let (reply_port, reply_chan) = Chan::new();
+        chan.try_send(MsgLoad(reply_chan));
+        let commands = match reply_port.recv_opt() {
+            None => return false,
+            Some(commands) => commands
+        };
+        let owner = self.next_owner;
+        self.next_owner += 1;
+        let mut registered = ~[];
+        for (name, help) in commands.move_iter() {
+            if self.bot.has_command(name.as_slice()) {
+                continue;
+            }
+            let forward = ~Forward{ chan: chan.clone(), owner: owner };
+            self.bot.register(name.as_slice(), help.as_slice(), forward as ~CommandHandler);
+            registered.push(name);
+        }
+        self.plugins[idx].owner = owner;
+        self.plugins[idx].commands = registered;
+        match self.handle {
+            None => (),
+            Some(ref handle) => { chan.try_send(MsgConnect(handle.clone())); }
+        }
+        self.plugins[idx].chan = Some(chan);
+        true
+    }
+
+    fn stop(&mut self, idx: uint) {
+        match self.plugins[idx].chan {
+            None => (),
+            Some(ref chan) => { chan.try_send(MsgUnload); }
+        }
+        self.stopped(idx);
+    }
+
+    // Forgets a plugin's task and commands
+    fn stopped(&mut self, idx: uint) {
+        self.plugins[idx].chan = None;
+        let owner = self.plugins[idx].owner;
+        let commands = ::std::mem::replace(&mut self.plugins[idx].commands, ~[]);
+        for name in commands.iter() {
+            self.bot.unregister_owned(*name, owner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PluginHost, Plugin, PluginContext, PluginCommand, Config, PluginTimer};
+    use conn;
+    use conn::Options;
+    use mock;
+    use mock::{MockServer, Expect, ExpectEventually, Close};
+
+    struct Echo {
+        greeting: ~str,
+        timer: Option<PluginTimer>
+    }
+
+    impl Plugin for Echo {
+        fn load(&mut self, ctx: &mut PluginContext) {
+            ctx.register_command("echo", "echo <text> - repeats the text");
+            ctx.register_command("crash", "");
+        }
+
+        fn on_connect(&mut self, ctx: &mut PluginContext) {
+            self.timer = Some(ctx.add_timer(10, false));
+        }
+
+        fn on_timer(&mut self, ctx: &mut PluginContext, id: PluginTimer) {
+            assert_eq!(Some(id), self.timer);
+            ctx.privmsg(bytes!("#chan"), self.greeting.as_bytes());
+        }
+
+        fn on_command(&mut self, ctx: &mut PluginContext, cmd: &PluginCommand) {
+            match cmd.name.as_slice() {
+                "crash" => fail!("crashed"),
+                _ => { ctx.reply(cmd, cmd.args.connect_vec(&(' ' as u8))); }
+            }
+        }
+    }
+
+    fn new_echo(config: &Config) -> ~Plugin:Send {
+        ~Echo{ greeting: config.get("greeting").unwrap_or("hi").to_owned(), timer: None }
+            as ~Plugin:Send
+    }
+
+    #[test]
+    fn test_plugins() {
+        let server = MockServer::start(~[
+            ExpectEventually(~"USER *"),
+            mock::Send(~":irc.test 001 ircnick :Welcome"),
+            Expect(~"PRIVMSG #chan :hello"),
+            mock::Send(~":bob!b@host PRIVMSG #chan :!echo one two"),
+            Expect(~"PRIVMSG #chan :one two"),
+            mock::Send(~":bob!b@host PRIVMSG #chan :!crash"),
+            mock::Send(~":bob!b@host PRIVMSG #chan :!echo three"),
+            mock::Send(~":irc.test PING :done"),
+            Expect(~"PONG done"),
+            Close
+        ]).unwrap();
+
+        let mut host = PluginHost::new();
+        let mut config = Config::new();
+        config.set("greeting", "hello");
+        assert!(host.add("echo", new_echo, config));
+        assert!(host.is_running("echo"));
+
+        let opts = Options::new("127.0.0.1", server.port());
+        let mut crashed = false;
+        let res = conn::connect(opts, |conn, event| {
+            host.handle_event(conn, &event);
+            if !host.is_running("echo") {
+                crashed = true;
+            }
+        });
+        assert!(res.is_ok());
+        // the crash is noticed when the next event is delivered to the plugin
+        assert!(crashed);
+        assert!(host.reload("echo"));
+        assert!(host.is_running("echo"));
+        server.finish().assert_ok();
+    }
+
+    struct Pong;
+
+    impl Plugin for Pong {
+        fn load(&mut self, ctx: &mut PluginContext) {
+            ctx.register_command("ping", "");
+            // already taken by the echo plugin
+            ctx.register_command("echo", "");
+        }
+
+        fn on_command(&mut self, ctx: &mut PluginContext, cmd: &PluginCommand) {
+            ctx.reply(cmd, bytes!("pong"));
+        }
+    }
+
+    fn new_pong(_: &Config) -> ~Plugin:Send {
+        ~Pong as ~Plugin:Send
+    }
+
+    fn new_broken(config: &Config) -> ~Plugin:Send {
+        let greeting = config.get("greeting").unwrap();
+        ~Echo{ greeting: greeting.to_owned(), timer: None } as ~Plugin:Send
+    }
+
+    #[test]
+    fn test_isolation() {
+        let server = MockServer::start(~[
+            ExpectEventually(~"USER *"),
+            mock::Send(~":irc.test 001 ircnick :Welcome"),
+            Expect(~"PRIVMSG #chan :hi"),
+            mock::Send(~":bob!b@host PRIVMSG #chan :!echo one"),
+            Expect(~"PRIVMSG #chan :one"),
+            mock::Send(~":bob!b@host PRIVMSG #chan :!crash"),
+            mock::Send(~":bob!b@host PRIVMSG #chan :!ping"),
+            Expect(~"PRIVMSG #chan :pong"),
+            mock::Send(~":irc.test PING :done"),
+            Expect(~"PONG done"),
+            mock::Send(~":bob!b@host PRIVMSG #chan :!ping"),
+            Expect(~"PRIVMSG #chan :pong"),
+            Close
+        ]).unwrap();
+
+        let mut host = PluginHost::new();
+        assert!(host.add("echo", new_echo, Config::new()));
+        assert!(host.add("pong", new_pong, Config::new()));
+        // a failing factory only takes down its own plugin
+        assert!(!host.add("broken", new_broken, Config::new()));
+        assert!(!host.is_running("broken"));
+
+        let opts = Options::new("127.0.0.1", server.port());
+        let res = conn::connect(opts, |conn, event| { host.handle_event(conn, &event); });
+        assert!(res.is_ok());
+        assert!(!host.is_running("echo"));
+        assert!(host.is_running("pong"));
+        // the crashed plugin's commands are gone, but not the other plugin's
+        assert_eq!(host.bot.commands(), ~["ping"]);
+        server.finish().assert_ok();
+    }
+}
@@ -0,0 +1,363 @@
+//! Connecting through SOCKS5 and HTTP CONNECT proxies
+//!
+//! The handshakes here work on any Reader + Writer, and leave the stream positioned
+//! at the start of the tunnelled connection.
+
+use std::fmt;
+use std::str;
+use std::io::IoError;
+use std::io::net::ip::{Ipv4Addr, Ipv6Addr};
+use conn::{OptionsHost, Host, Addr};
+
+/// The kinds of proxy supported
+#[deriving(Eq,Clone)]
+pub enum ProxyKind {
+    /// A SOCKS5 proxy (RFC 1928), with optional username/password auth (RFC 1929)
+    Socks5,
+    /// An HTTP proxy that supports the CONNECT method, with optional basic auth
+    HttpConnect
+}
+
+/// A proxy to connect through, set with Options.proxy
+pub struct Proxy<'a> {
+    /// The kind of proxy
+    kind: ProxyKind,
+    /// The proxy host
+    host: &'a str,
+    /// The proxy port
+    port: u16,
+    /// The username and password to authenticate with, if any
+    auth: Option<(&'a str, &'a str)>,
+    /// If `true`, the server hostname is sent to a SOCKS5 proxy to resolve,
+    /// instead of being resolved locally. HTTP proxies always resolve the hostname.
+    remote_dns: bool
+}
+
+impl<'a> Proxy<'a> {
+    /// Returns a new Proxy of the given kind, without auth and using remote DNS
+    pub fn new(kind: ProxyKind, host: &'a str, port: u16) -> Proxy<'a> {
+        Proxy {
+            kind: kind,
+            host: host,
+            port: port,
+            auth: None,
+            remote_dns: true
+        }
+    }
+}
+
+/// Errors that can occur while negotiating with a proxy
+pub enum ProxyError {
+    /// I/O error talking to the proxy
+    ProxyIO(IoError),
+    /// The proxy sent something that doesn't follow the protocol
+    ProxyBadReply,
+    /// The proxy requires authentication and none was given, or it rejected the credentials
+    ProxyAuthFailed,
+    /// The SOCKS5 proxy could not connect to the server. The arg is the reply code.
+    ProxySocksError(u8),
+    /// The HTTP proxy could not connect to the server. The args are the status code
+    /// and reason phrase.
+    ProxyHttpError(uint, ~str),
+    /// The server host can't be sent to the proxy, e.g. a hostname longer than 255 bytes
+    ProxyBadTarget
+}
+
+impl fmt::Show for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProxyIO(ref err) => err.fmt(f),
+            ProxyBadReply => write!(f.buf, "invalid reply from proxy"),
+            ProxyAuthFailed => write!(f.buf, "proxy authentication failed"),
+            ProxySocksError(code) => {
+                let desc = match code {
+                    1 => "general failure",
+                    2 => "connection not allowed by ruleset",
+                    3 => "network unreachable",
+                    4 => "host unreachable",
+                    5 => "connection refused",
+                    6 => "TTL expired",
+                    7 => "command not supported",
+                    8 => "address type not supported",
+                    _ => "unknown error"
+                };
+                write!(f.buf, "SOCKS5 proxy error {}: {}", code, desc)
+            }
+            ProxyHttpError(code, ref reason) => {
+                write!(f.buf, "HTTP proxy error {} {}", code, *reason)
+            }
+            ProxyBadTarget => write!(f.buf, "server host cannot be sent to proxy")
+        }
+    }
+}
+
+/// Typedef for proxy results
+pub type ProxyResult<T> = Result<T, ProxyError>;
+
+macro_rules! io(
+    ($e:expr) => (
+        match $e {
+            Ok(v) => v,
+            Err(e) => return Err(ProxyIO(e))
+        }
+    )
+)
+
+/// Performs the SOCKS5 handshake on `stream`, asking the proxy to connect to `host` on `port`.
+pub fn socks5_connect<S: Reader + Writer>(stream: &mut S, host: OptionsHost, port: u16,
+                                          auth: Option<(&str, &str)>) -> ProxyResult<()> {
+    // greeting, offering no auth and username/password if we have one
+    let greeting = match auth {
+        None => ~[5u8, 1, 0],
+        Some(_) => ~[5u8, 2, 0, 2]
+    };
+    io!(stream.write(greeting));
+    io!(stream.flush());
+    let reply = io!(stream.read_bytes(2));
+    if reply[0] != 5 {
+        return Err(ProxyBadReply);
+    }
+    match (reply[1], auth) {
+        (0, _) => (),
+        (2, Some((user, pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(ProxyAuthFailed);
+            }
+            let mut req = ~[1u8, user.len() as u8];
+            req.push_all(user.as_bytes());
+            req.push(pass.len() as u8);
+            req.push_all(pass.as_bytes());
+            io!(stream.write(req));
+            io!(stream.flush());
+            let reply = io!(stream.read_bytes(2));
+            if reply[1] != 0 {
+                return Err(ProxyAuthFailed);
+            }
+        }
+        (0xFF, _) | (2, None) => return Err(ProxyAuthFailed),
+        _ => return Err(ProxyBadReply)
+    }
+
+    let mut req = ~[5u8, 1, 0];
+    match host {
+        Addr(Ipv4Addr(a, b, c, d)) => {
+            req.push_all([1u8, a, b, c, d]);
+        }
+        Addr(Ipv6Addr(a, b, c, d, e, f, g, h)) => {
+            req.push(4);
+            for &seg in [a, b, c, d, e, f, g, h].iter() {
+                req.push_all([(seg >> 8) as u8, seg as u8]);
+            }
+        }
+        Host(name) => {
+            if name.len() > 255 {
+                return Err(ProxyBadTarget);
+            }
+            req.push_all([3u8, name.len() as u8]);
+            req.push_all(name.as_bytes());
+        }
+    }
+    req.push_all([(port >> 8) as u8, port as u8]);
+    io!(stream.write(req));
+    io!(stream.flush());
+
+    let reply = io!(stream.read_bytes(4));
+    if reply[0] != 5 {
+        return Err(ProxyBadReply);
+    }
+    if reply[1] != 0 {
+        return Err(ProxySocksError(reply[1]));
+    }
+    // skip the bound address and port
+    let len = match reply[3] {
+        1 => 4,
+        3 => io!(stream.read_u8()) as uint,
+        4 => 16,
+        _ => return Err(ProxyBadReply)
+    };
+    io!(stream.read_bytes(len + 2));
+    Ok(())
+}
+
+/// Sends an HTTP CONNECT request on `stream` for `host` on `port`, and reads the response.
+pub fn http_connect<S: Reader + Writer>(stream: &mut S, host: OptionsHost, port: u16,
+                                        auth: Option<(&str, &str)>) -> ProxyResult<()> {
+    let target = match host {
+        Host(name) => format!("{}:{}", name, port),
+        Addr(ip@Ipv6Addr(..)) => format!("[{}]:{}", ip.to_str(), port),
+        Addr(ip) => format!("{}:{}", ip.to_str(), port)
+    };
+    let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    match auth {
+        None => (),
+        Some((user, pass)) => {
+            let creds = base64(format!("{}:{}", user, pass).as_bytes());
+            req.push_str(format!("Proxy-Authorization: Basic {}\r\n", creds));
+        }
+    }
+    req.push_str("\r\n");
+    io!(stream.write(req.as_bytes()));
+    io!(stream.flush());
+
+    let status = io!(read_http_line(stream));
+    // HTTP/1.x <code> <reason>
+    let (code, reason) = {
+        let mut parts = status.splitn(' ', 1);
+        match (parts.next(), parts.next()) {
+            (Some(version), Some(rest)) if version.starts_with("HTTP/1.") => {
+                let (code, reason) = match rest.find(' ') {
+                    None => (rest, ""),
+                    Some(idx) => (rest.slice_to(idx), rest.slice_from(idx+1))
+                };
+                match from_str::<uint>(code) {
+                    None => return Err(ProxyBadReply),
+                    Some(code) => (code, reason.to_owned())
+                }
+            }
+            _ => return Err(ProxyBadReply)
+        }
+    };
+    // skip the headers. The tunnel starts after the blank line.
+    loop {
+        if io!(read_http_line(stream)).is_empty() {
+            break;
+        }
+    }
+    match code {
+        200..299 => Ok(()),
+        407 => Err(ProxyAuthFailed),
+        _ => Err(ProxyHttpError(code, reason))
+    }
+}
+
+// Reads a single CRLF-terminated line, a byte at a time so none of the tunnelled
+// data is consumed.
+fn read_http_line<R: Reader>(stream: &mut R) -> ::std::io::IoResult<~str> {
+    let mut line = ~[];
+    loop {
+        let b = match stream.read_byte() {
+            Ok(b) => b,
+            Err(e) => return Err(e)
+        };
+        if b == '\n' as u8 {
+            break;
+        }
+        line.push(b);
+        if line.len() > 8192 {
+            break;
+        }
+    }
+    if line.last() == Some(&('\r' as u8)) {
+        line.pop();
+    }
+    Ok(str::from_utf8_lossy(line).into_owned())
+}
+
+// Encodes bytes as base64, for HTTP basic auth
+fn base64(data: &[u8]) -> ~str {
+    static Chars: &'static [u8] =
+        bytes!("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/");
+    let mut out = ~[];
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| {
+            n | (b as u32 << (16 - 8 * i))
+        });
+        for i in range(0u, 4) {
+            if i <= chunk.len() {
+                out.push(Chars[(n >> (18 - 6 * i)) as uint & 0x3F]);
+            } else {
+                out.push('=' as u8);
+            }
+        }
+    }
+    str::from_utf8_owned(out).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64, socks5_connect, http_connect, ProxyAuthFailed, ProxyHttpError};
+    use conn::{Host, Addr};
+    use std::io::net::ip::{Ipv4Addr, SocketAddr};
+    use std::io::net::tcp::{TcpListener, TcpStream};
+    use std::io::{Listener, Acceptor};
+
+    // Runs `server` on one end of a local TCP connection and returns the other end
+    fn stand_in(server: proc(TcpStream)) -> TcpStream {
+        let addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+        let mut listener = TcpListener::bind(addr).unwrap();
+        let addr = listener.socket_name().unwrap();
+        let mut acceptor = listener.listen().unwrap();
+        spawn(proc() {
+            server(acceptor.accept().unwrap());
+        });
+        TcpStream::connect(addr).unwrap()
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(bytes!("")), ~"");
+        assert_eq!(base64(bytes!("f")), ~"Zg==");
+        assert_eq!(base64(bytes!("fo")), ~"Zm8=");
+        assert_eq!(base64(bytes!("foo")), ~"Zm9v");
+        assert_eq!(base64(bytes!("user:pass")), ~"dXNlcjpwYXNz");
+    }
+
+    #[test]
+    fn test_socks5() {
+        let mut stream = stand_in(proc(mut s) {
+            assert_eq!(s.read_bytes(4).unwrap(), ~[5u8, 2, 0, 2]);
+            s.write([5u8, 2]).unwrap();
+            assert_eq!(s.read_bytes(11).unwrap(), ~[1u8, 4, 'u' as u8, 's' as u8, 'e' as u8,
+                                                    'r' as u8, 3, 'p' as u8, 'w' as u8,
+                                                    'd' as u8, 5]);
+            s.write([1u8, 0]).unwrap();
+            let req = s.read_bytes(5 + 12 + 2).unwrap();
+            assert_eq!(req.slice_to(5), &[5u8, 1, 0, 3, 12]);
+            assert_eq!(req.slice(5, 17), bytes!("irc.test.net"));
+            assert_eq!(req.slice_from(17), &[0x1Au8, 0x0B]);
+            s.write([5u8, 0, 0, 1, 10, 0, 0, 1, 0x1A, 0x0B]).unwrap();
+            s.write(bytes!("hello")).unwrap();
+        });
+        assert!(socks5_connect(&mut stream, Host("irc.test.net"), 6667,
+                               Some(("user", "pwd"))).is_ok());
+        assert_eq!(stream.read_bytes(5).unwrap(), bytes!("hello").to_owned());
+    }
+
+    #[test]
+    fn test_socks5_refused() {
+        let mut stream = stand_in(proc(mut s) {
+            s.read_bytes(3).unwrap();
+            s.write([5u8, 0xFF]).unwrap();
+        });
+        match socks5_connect(&mut stream, Addr(Ipv4Addr(10, 0, 0, 1)), 6667, None) {
+            Err(ProxyAuthFailed) => (),
+            _ => fail!("expected ProxyAuthFailed")
+        }
+    }
+
+    #[test]
+    fn test_http_connect() {
+        let mut stream = stand_in(proc(mut s) {
+            let expected = bytes!("CONNECT irc.test.net:6667 HTTP/1.1\r\n",
+                                  "Host: irc.test.net:6667\r\n",
+                                  "Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n");
+            assert_eq!(s.read_bytes(expected.len()).unwrap(), expected.to_owned());
+            s.write(bytes!("HTTP/1.1 200 Connection established\r\n",
+                           "Proxy-Agent: test\r\n\r\nhello")).unwrap();
+        });
+        assert!(http_connect(&mut stream, Host("irc.test.net"), 6667,
+                             Some(("user", "pass"))).is_ok());
+        assert_eq!(stream.read_bytes(5).unwrap(), bytes!("hello").to_owned());
+
+        let mut stream = stand_in(proc(mut s) {
+            let expected = bytes!("CONNECT irc.test.net:6667 HTTP/1.1\r\n",
+                                  "Host: irc.test.net:6667\r\n\r\n");
+            s.read_bytes(expected.len()).unwrap();
+            s.write(bytes!("HTTP/1.0 403 Forbidden\r\n\r\n")).unwrap();
+        });
+        match http_connect(&mut stream, Host("irc.test.net"), 6667, None) {
+            Err(ProxyHttpError(403, ref reason)) if reason.as_slice() == "Forbidden" => (),
+            _ => fail!("expected ProxyHttpError")
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Recording and replaying IRC sessions
+//!
+//! A Recorder set in Options.recorder logs every line the connection sends and
+//! receives. Each entry is written on its own line as `<time> <dir> <line>`, where
+//! `<time>` is in milliseconds since the epoch and `<dir>` is `<` for lines received
+//! from the server and `>` for lines sent to it.
+//!
+//! A recorded session can be replayed with replay(), which starts a MockServer that
+//! sends the recorded server lines and checks that the client sends the recorded
+//! client lines in between.
+
+use std::io;
+use std::io::{IoError, IoResult, BufferedReader, File};
+use std::u64;
+use time;
+use mock;
+use mock::{MockServer, Step, Expect, Close};
+
+/// The direction of a recorded line
+#[deriving(Eq,Clone)]
+pub enum Direction {
+    /// The line was received from the server
+    Received,
+    /// The line was sent to the server
+    Sent
+}
+
+/// A single recorded line
+#[deriving(Eq,Clone)]
+pub struct Entry {
+    /// When the line was sent or received, in milliseconds since the epoch
+    time: u64,
+    /// Whether the line was sent or received
+    direction: Direction,
+    /// The raw line, without the line ending
+    line: ~[u8]
+}
+
+impl Entry {
+    /// Parses an entry from a line of a recording
+    pub fn parse(v: &[u8]) -> Option<Entry> {
+        let idx = match v.position_elem(&(' ' as u8)) {
+            None => return None,
+            Some(idx) => idx
+        };
+        let time = match u64::parse_bytes(v.slice_to(idx), 10) {
+            None => return None,
+            Some(time) => time
+        };
+        let rest = v.slice_from(idx+1);
+        if rest.len() < 2 || rest[1] != ' ' as u8 {
+            return None;
+        }
+        let direction = match rest[0] as char {
+            '<' => Received,
+            '>' => Sent,
+            _ => return None
+        };
+        Some(Entry{ time: time, direction: direction, line: rest.slice_from(2).to_owned() })
+    }
+
+    /// Formats the entry as a line of a recording, including the line ending
+    pub fn to_raw(&self) -> ~[u8] {
+        let mut v = self.time.to_str().into_bytes();
+        v.push_all(match self.direction {
+            Received => bytes!(" < "),
+            Sent => bytes!(" > ")
+        });
+        v.push_all(self.line);
+        v.push('\n' as u8);
+        v
+    }
+}
+
+/// Records lines to a Writer on a separate task.
+///
+/// Recorders can be cloned, and all clones write to the same Writer.
+#[deriving(Clone)]
+pub struct Recorder {
+    priv chan: Chan<Entry>
+}
+
+impl Recorder {
+    /// Returns a Recorder that writes to `w`. Write errors stop the recording.
+    pub fn new<W: Writer + Send>(w: W) -> Recorder {
+        let (port, chan) = Chan::<Entry>::new();
+        spawn(proc() {
+            let mut w = w;
+            loop {
+                let entry = match port.recv_opt() {
+                    None => break,
+                    Some(entry) => entry
+                };
+                if w.write(entry.to_raw()).and_then(|_| w.flush()).is_err() {
+                    break;
+                }
+            }
+        });
+        Recorder{ chan: chan }
+    }
+
+    /// Returns a Recorder that writes to the file at `path`, replacing it if it exists
+    pub fn create(path: &Path) -> IoResult<Recorder> {
+        File::create(path).map(|f| Recorder::new(f))
+    }
+
+    /// Records a line. Any line ending is removed.
+    pub fn record(&self, direction: Direction, line: &[u8]) {
+        let mut end = line.len();
+        while end > 0 && (line[end-1] == '\n' as u8 || line[end-1] == '\r' as u8) {
+            end -= 1;
+        }
+        let now = time::get_time();
+        let ms = now.sec as u64 * 1000 + now.nsec as u64 / 1000000;
+        self.chan.try_send(Entry{ time: ms, direction: direction,
+                                  line: line.slice_to(end).to_owned() });
+    }
+}
+
+/// Reads a recording
+pub fn read<R: Buffer>(r: &mut R) -> IoResult<~[Entry]> {
+    let mut entries = ~[];
+    let mut lineno = 0u;
+    loop {
+        let line = match r.read_until('\n' as u8) {
+            Err(ref e) if e.kind == io::EndOfFile => break,
+            Err(e) => return Err(e),
+            Ok(line) => line
+        };
+        lineno += 1;
+        let mut end = line.len();
+        while end > 0 && (line[end-1] == '\n' as u8 || line[end-1] == '\r' as u8) {
+            end -= 1;
+        }
+        let line = line.slice_to(end);
+        if line.is_empty() {
+            continue;
+        }
+        match Entry::parse(line) {
+            Some(entry) => entries.push(entry),
+            None => return Err(IoError{
+                kind: io::InvalidInput,
+                desc: "invalid session recording",
+                detail: Some(format!("line {}", lineno))
+            })
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads a recording from the file at `path`
+pub fn load(path: &Path) -> IoResult<~[Entry]> {
+    File::open(path).and_then(|f| read(&mut BufferedReader::new(f)))
+}
+
+/// Converts a recording into a MockServer script that sends the received lines
+/// and expects the sent lines, then closes the connection.
+pub fn script(entries: &[Entry]) -> ~[Step] {
+    let mut steps = ~[];
+    for entry in entries.iter() {
+        let line = ::std::str::from_utf8_lossy(entry.line).into_owned();
+        steps.push(match entry.direction {
+            // mock::Send is not imported, as it would hide the Send kind
+            Received => mock::Send(line),
+            Sent => Expect(mock::escape(line))
+        });
+    }
+    steps.push(Close);
+    steps
+}
+
+/// Starts a MockServer that replays the recording.
+/// Connect to it and check its transcript to verify the client behaves as recorded.
+pub fn replay(entries: &[Entry]) -> IoResult<MockServer> {
+    MockServer::start(script(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, Received, Sent, read, replay};
+    use conn;
+    use conn::{Options, LineReceived, IRCCode};
+    use std::io::BufReader;
+
+    #[test]
+    fn test_entry() {
+        let entry = Entry::parse(bytes!("1391212800123 < :irc.test PING :x")).unwrap();
+        assert_eq!(entry.time, 1391212800123);
+        assert_eq!(entry.direction, Received);
+        assert_eq!(entry.line.as_slice(), bytes!(":irc.test PING :x"));
+        assert_eq!(entry.to_raw(), bytes!("1391212800123 < :irc.test PING :x\n").to_owned());
+        assert!(Entry::parse(bytes!("12 ! PING")).is_none());
+        assert!(Entry::parse(bytes!("abc > PING")).is_none());
+    }
+
+    #[test]
+    fn test_replay() {
+        let log = bytes!("1000 > CAP LS 302\n",
+                         "1001 > NICK ircnick\n",
+                         "1002 > USER ircuser 8 * :rust-irclib user\n",
+                         "1100 < :irc.test 001 ircnick :Welcome\n",
+                         "1101 > QUIT :bye *\n");
+        let entries = read(&mut BufReader::new(log)).unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[4].direction, Sent);
+
+        let server = replay(entries).unwrap();
+        let opts = Options::new("127.0.0.1", server.port());
+        let res = conn::connect(opts, |conn, event| {
+            match event {
+                LineReceived(ref line) if line.command == IRCCode(1) => {
+                    conn.quit(bytes!("bye *"));
+                }
+                _ => ()
+            }
+        });
+        assert!(res.is_ok());
+        server.finish().assert_ok();
+    }
+}
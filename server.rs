@@ -0,0 +1,1702 @@
+//! A minimal IRC server
+//!
+//! The Server implements enough of RFC 2812 for clients to register, chat in channels
+//! and with each other, and manage channels: NICK, USER, PING/PONG, JOIN, PART,
+//! PRIVMSG, NOTICE, TOPIC, MODE, KICK, WHO, NAMES and QUIT. Idle clients are pinged,
+//! and disconnected if they don't answer.
+//!
+//! There is no server linking, no operators, and no services. It is meant to run
+//! in-process as a test fixture or as a small private chat server.
+
+use std::{char, str, uint};
+use std::hashmap::HashMap;
+use std::io::{IoResult, BufferedReader, Listener, Acceptor};
+use std::io::net::ip::SocketAddr;
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::timer;
+use std::sync::arc::UnsafeArc;
+use std::sync::atomics::{AtomicBool, SeqCst};
+use time;
+use conn::{Line, Command, IRCCmd, IRCCode, IRCAction, IRCCTCP, IRCCTCPReply};
+use mask;
+use {User, CaseMapRfc1459};
+
+static NickLen: uint = 30;
+static ChannelLen: uint = 50;
+
+/// Options used when starting a Server
+pub struct ServerOptions<'a> {
+    /// The address to listen on. Use port 0 to pick a free port.
+    addr: SocketAddr,
+    /// The server name
+    name: &'a str,
+    /// The network name, sent in RPL_ISUPPORT
+    network: &'a str,
+    /// The lines of the message of the day. If empty, ERR_NOMOTD is sent instead.
+    motd: &'a [&'a str],
+    /// How long a client may be idle before it is pinged, in milliseconds
+    ping_interval: u64,
+    /// How long a client has to answer a ping before it is disconnected, in milliseconds
+    ping_timeout: u64
+}
+
+impl<'a> ServerOptions<'a> {
+    /// Returns a new ServerOptions struct with default values
+    pub fn new(addr: SocketAddr, name: &'a str) -> ServerOptions<'a> {
+        ServerOptions {
+            addr: addr,
+            name: name,
+            network: "rust-irclib",
+            motd: &[],
+            ping_interval: 120 * 1000,
+            ping_timeout: 60 * 1000
+        }
+    }
+}
+
+/// A running server
+///
+/// The server runs on its own tasks until shutdown() is called.
+pub struct Server {
+    priv addr: SocketAddr,
+    priv chan: Chan<Msg>,
+    priv running: UnsafeArc<AtomicBool>
+}
+
+enum Msg {
+    MsgConnect(uint, Chan<~[u8]>, ~[u8]),
+    MsgLine(uint, ~[u8]),
+    MsgClosed(uint),
+    MsgTick,
+    MsgShutdown
+}
+
+impl Server {
+    /// Starts a server with the given options
+    pub fn start(opts: ServerOptions) -> IoResult<Server> {
+        let mut listener = match TcpListener::bind(opts.addr) {
+            Err(e) => return Err(e),
+            Ok(l) => l
+        };
+        let addr = match listener.socket_name() {
+            Err(e) => return Err(e),
+            Ok(addr) => addr
+        };
+        let acceptor = match listener.listen() {
+            Err(e) => return Err(e),
+            Ok(a) => a
+        };
+        let running = UnsafeArc::new(AtomicBool::new(true));
+        let (port, chan) = Chan::new();
+
+        let mut core = Core {
+            name: opts.name.as_bytes().to_owned(),
+            network: opts.network.as_bytes().to_owned(),
+            motd: opts.motd.iter().map(|l| l.as_bytes().to_owned()).collect(),
+            ping_interval: opts.ping_interval,
+            ping_timeout: opts.ping_timeout,
+            created: time::now().rfc822(),
+            clients: HashMap::new(),
+            nicks: HashMap::new(),
+            channels: HashMap::new()
+        };
+        spawn(proc() {
+            core.run(port);
+        });
+
+        {
+            let chan = chan.clone();
+            let running = running.clone();
+            spawn(proc() {
+                accept_loop(acceptor, chan, running);
+            });
+        }
+        {
+            let chan = chan.clone();
+            let interval = ::std::cmp::min(opts.ping_interval, opts.ping_timeout) / 4;
+            let interval = ::std::cmp::max(interval, 10);
+            spawn(proc() {
+                loop {
+                    timer::sleep(interval);
+                    if !chan.try_send(MsgTick) {
+                        break;
+                    }
+                }
+            });
+        }
+        Ok(Server{ addr: addr, chan: chan, running: running })
+    }
+
+    /// Returns the address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the port the server is listening on
+    pub fn port(&self) -> u16 {
+        self.addr.port
+    }
+
+    /// Disconnects all clients and stops the server
+    pub fn shutdown(self) {
+        unsafe { (*self.running.get()).store(false, SeqCst); }
+        self.chan.try_send(MsgShutdown);
+        // wake up the acceptor so it notices we're done
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn accept_loop<A: Acceptor<TcpStream>>(mut acceptor: A, chan: Chan<Msg>,
+                                      running: UnsafeArc<AtomicBool>) {
+    let mut next_id = 0u;
+    loop {
+        let stream = acceptor.accept();
+        if unsafe { !(*running.get()).load(SeqCst) } {
+            break;
+        }
+        let mut stream = match stream {
+            Err(_) => continue,
+            Ok(stream) => stream
+        };
+        let host = match stream.peer_name() {
+            Err(_) => bytes!("unknown").to_owned(),
+            Ok(addr) => addr.ip.to_str().into_bytes()
+        };
+        let id = next_id;
+        next_id += 1;
+
+        let (write_port, write_chan) = Chan::new();
+        if !chan.try_send(MsgConnect(id, write_chan, host)) {
+            break;
+        }
+        {
+            let stream = stream.clone();
+            spawn(proc() {
+                let mut stream = stream;
+                loop {
+                    let line: ~[u8] = match write_port.recv_opt() {
+                        None => break,
+                        // an empty line tells us to stop
+                        Some(v) => if v.is_empty() { break } else { v }
+                    };
+                    if stream.write(line).and_then(|_| stream.flush()).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        let chan = chan.clone();
+        spawn(proc() {
+            let mut reader = BufferedReader::new(stream);
+            loop {
+                match reader.read_until('\n' as u8) {
+                    Err(_) => break,
+                    Ok(line) => {
+                        // TcpStream can't be shut down from another task, so the connection
+                        // only closes once we stop reading. After a QUIT there's nothing
+                        // more to read. Otherwise we wait for the client to hang up.
+                        let quit = Line::parse(trim_line(line)).map_or(false, |l| {
+                            match l.command { IRCCmd(cmd) => upper(cmd) == ~"QUIT", _ => false }
+                        });
+                        if !chan.try_send(MsgLine(id, line)) || quit {
+                            return;
+                        }
+                    }
+                }
+            }
+            chan.try_send(MsgClosed(id));
+        });
+    }
+}
+
+struct Client {
+    chan: Chan<~[u8]>,
+    host: ~[u8],
+    nick: Option<~[u8]>,
+    user: Option<(~[u8], ~[u8])>,
+    modes: ~[u8],
+    registered: bool,
+    negotiating: bool,
+    last_active: u64,
+    ping_sent: Option<u64>
+}
+
+impl Client {
+    fn nick<'a>(&'a self) -> &'a [u8] {
+        match self.nick {
+            None => bytes!("*"),
+            Some(ref nick) => nick.as_slice()
+        }
+    }
+
+    fn prefix(&self) -> ~[u8] {
+        let user = self.user.as_ref().map_or(bytes!("*"), |&(ref u, _)| u.as_slice());
+        User::new(self.nick(), Some(user), Some(self.host.as_slice())).raw().to_owned()
+    }
+}
+
+struct Channel {
+    name: ~[u8],
+    topic: Option<(~[u8], ~[u8], i64)>,
+    created: i64,
+    // member ids and their prefix modes, in join order
+    members: ~[(uint, ~[u8])],
+    modes: ~[u8],
+    key: Option<~[u8]>,
+    limit: Option<uint>,
+    bans: ~[(~[u8], ~[u8], i64)]
+}
+
+impl Channel {
+    fn member_modes<'a>(&'a self, id: uint) -> Option<&'a [u8]> {
+        self.members.iter().find(|&&(m, _)| m == id).map(|&(_, ref modes)| modes.as_slice())
+    }
+
+    fn is_op(&self, id: uint) -> bool {
+        self.member_modes(id).map_or(false, |m| m.contains(&('o' as u8)))
+    }
+}
+
+struct Core {
+    name: ~[u8],
+    network: ~[u8],
+    motd: ~[~[u8]],
+    ping_interval: u64,
+    ping_timeout: u64,
+    created: ~str,
+    clients: HashMap<uint, Client>,
+    // lowercased nick to client id
+    nicks: HashMap<~[u8], uint>,
+    // lowercased name to channel
+    channels: HashMap<~[u8], Channel>
+}
+
+fn now_ms() -> u64 {
+    time::precise_time_ns() / 1000000
+}
+
+fn upper(cmd: &str) -> ~str {
+    cmd.chars().map(|c| match c { 'a'..'z' => (c as u8 - 32) as char, c => c }).collect()
+}
+
+fn trim_line<'a>(v: &'a [u8]) -> &'a [u8] {
+    let mut end = v.len();
+    while end > 0 && (v[end-1] == '\n' as u8 || v[end-1] == '\r' as u8) {
+        end -= 1;
+    }
+    v.slice_to(end)
+}
+
+fn lower(v: &[u8]) -> ~[u8] {
+    CaseMapRfc1459.to_lower(v)
+}
+
+// Builds a raw line, adding a colon to the last arg when it needs one
+fn build_line(prefix: &[u8], cmd: &str, args: &[&[u8]]) -> ~[u8] {
+    let mut line = ~[];
+    if !prefix.is_empty() {
+        line.push(':' as u8);
+        line.push_all(prefix);
+        line.push(' ' as u8);
+    }
+    line.push_all(cmd.as_bytes());
+    for (i, arg) in args.iter().enumerate() {
+        line.push(' ' as u8);
+        if i == args.len() - 1 &&
+           (arg.is_empty() || arg[0] == ':' as u8 || arg.contains(&(' ' as u8))) {
+            line.push(':' as u8);
+        }
+        line.push_all(*arg);
+    }
+    line.push_all(bytes!("\r\n"));
+    line
+}
+
+fn valid_nick(nick: &[u8]) -> bool {
+    fn special(b: u8) -> bool {
+        match b as char { '[' | ']' | '\\' | '`' | '_' | '^' | '{' | '|' | '}' => true, _ => false }
+    }
+    !nick.is_empty() && nick.len() <= NickLen &&
+        (char::is_alphabetic(nick[0] as char) || special(nick[0])) &&
+        nick.iter().all(|&b| b < 0x80 && (char::is_alphanumeric(b as char) || special(b) ||
+                                          b == '-' as u8))
+}
+
+fn valid_channel(name: &[u8]) -> bool {
+    name.len() > 1 && name.len() <= ChannelLen && name[0] == '#' as u8 &&
+        name.iter().all(|&b| b != ' ' as u8 && b != ',' as u8 && b != 7 && b != 0)
+}
+
+impl Core {
+    fn run(&mut self, port: Port<Msg>) {
+        loop {
+            match port.recv() {
+                MsgConnect(id, chan, host) => {
+                    self.clients.insert(id, Client {
+                        chan: chan,
+                        host: host,
+                        nick: None,
+                        user: None,
+                        modes: ~[],
+                        registered: false,
+                        negotiating: false,
+                        last_active: now_ms(),
+                        ping_sent: None
+                    });
+                }
+                MsgLine(id, raw) => {
+                    let raw = trim_line(raw);
+                    if raw.is_empty() {
+                        continue;
+                    }
+                    match self.clients.find_mut(&id) {
+                        None => continue,
+                        Some(client) => client.last_active = now_ms()
+                    }
+                    match Line::parse(raw) {
+                        None => (),
+                        Some(line) => self.handle_line(id, line)
+                    }
+                }
+                MsgClosed(id) => self.disconnect(id, bytes!("Connection closed")),
+                MsgTick => self.check_pings(),
+                MsgShutdown => {
+                    let ids: ~[uint] = self.clients.keys().map(|&id| id).collect();
+                    for &id in ids.iter() {
+                        self.disconnect(id, bytes!("Server shutting down"));
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    fn send(&self, id: uint, line: ~[u8]) {
+        match self.clients.find(&id) {
+            None => (),
+            Some(client) => { client.chan.try_send(line); }
+        }
+    }
+
+    fn numeric(&self, id: uint, code: uint, args: &[&[u8]]) {
+        let line = {
+            let client = match self.clients.find(&id) {
+                None => return,
+                Some(client) => client
+            };
+            let code = format!("{:03u}", code);
+            let mut full = ~[client.nick()];
+            full.push_all(args);
+            build_line(self.name, code, full)
+        };
+        self.send(id, line);
+    }
+
+    // Sends the line to every member of every channel the client is in, once each.
+    // The client itself is included if `echo` is set.
+    fn send_peers(&self, id: uint, line: ~[u8], echo: bool) {
+        let mut sent = ~[];
+        if echo {
+            self.send(id, line.clone());
+        }
+        sent.push(id);
+        for chan in self.channels.values() {
+            if chan.member_modes(id).is_none() {
+                continue;
+            }
+            for &(member, _) in chan.members.iter() {
+                if !sent.contains(&member) {
+                    sent.push(member);
+                    self.send(member, line.clone());
+                }
+            }
+        }
+    }
+
+    fn send_channel(&self, chan: &[u8], line: ~[u8], except: Option<uint>) {
+        match self.channels.find(&lower(chan)) {
+            None => (),
+            Some(chan) => {
+                for &(member, _) in chan.members.iter() {
+                    if Some(member) != except {
+                        self.send(member, line.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_pings(&mut self) {
+        let now = now_ms();
+        let (interval, timeout) = (self.ping_interval, self.ping_timeout);
+        let mut timed_out = ~[];
+        let mut ping = ~[];
+        for (&id, client) in self.clients.mut_iter() {
+            match client.ping_sent {
+                Some(sent) if now - sent >= timeout => timed_out.push(id),
+                Some(_) => (),
+                None if client.registered && now - client.last_active >= interval => {
+                    client.ping_sent = Some(now);
+                    ping.push(id);
+                }
+                None => ()
+            }
+        }
+        for &id in ping.iter() {
+            let line = build_line([], "PING", [self.name.as_slice()]);
+            self.send(id, line);
+        }
+        for &id in timed_out.iter() {
+            self.disconnect(id, bytes!("Ping timeout"));
+        }
+    }
+
+    fn disconnect(&mut self, id: uint, reason: &[u8]) {
+        let (registered, prefix, nick, host) = match self.clients.find(&id) {
+            None => return,
+            Some(c) => (c.registered, c.prefix(), c.nick.clone(), c.host.clone())
+        };
+        if registered {
+            self.send_peers(id, build_line(prefix, "QUIT", [reason]), false);
+        }
+        let error = format!("Closing Link: {} ({})", str::from_utf8_lossy(host).as_slice(),
+                            str::from_utf8_lossy(reason).as_slice());
+        self.send(id, build_line([], "ERROR", [error.as_bytes()]));
+        self.send(id, ~[]);
+        self.remove_from_channels(id);
+        for nick in nick.iter() {
+            self.nicks.pop(&lower(*nick));
+        }
+        self.clients.pop(&id);
+    }
+
+    fn remove_from_channels(&mut self, id: uint) {
+        for chan in self.channels.mut_values() {
+            chan.members.retain(|&(m, _)| m != id);
+        }
+        self.remove_empty_channels();
+    }
+
+    fn remove_empty_channels(&mut self) {
+        let empty: ~[~[u8]] = self.channels.iter().filter(|&(_, c)| c.members.is_empty())
+                                                  .map(|(k, _)| k.clone()).collect();
+        for name in empty.iter() {
+            self.channels.pop(name);
+        }
+    }
+
+    fn handle_line(&mut self, id: uint, line: Line) {
+        let Line{ command, args, .. } = line;
+        let cmd = match command {
+            IRCCmd(ref cmd) => upper(*cmd),
+            IRCCode(_) => return,
+            IRCAction(..) | IRCCTCP(..) | IRCCTCPReply(..) => ~"PRIVMSG"
+        };
+        let registered = self.clients.find(&id).map_or(false, |c| c.registered);
+        let args: ~[&[u8]] = args.iter().map(|a| a.as_slice()).collect();
+        let args = args.as_slice();
+        match (cmd.as_slice(), registered) {
+            ("PING", _) => {
+                let token = if args.is_empty() { self.name.as_slice() } else { args[0] };
+                let line = build_line(self.name, "PONG", [self.name.as_slice(), token]);
+                self.send(id, line);
+            }
+            ("PONG", _) => {
+                self.clients.find_mut(&id).map(|c| c.ping_sent = None);
+            }
+            ("QUIT", _) => {
+                let reason = if args.is_empty() { bytes!("Client Quit") } else { args[0] };
+                let reason = bytes!("Quit: ") + reason;
+                self.disconnect(id, reason);
+            }
+            ("CAP", false) => self.handle_cap(id, args),
+            ("CAP", true) => (),
+            ("PASS", _) => (),
+            ("NICK", _) => self.handle_nick(id, args),
+            ("USER", false) => {
+                if args.len() < 4 {
+                    self.numeric(id, 461, [bytes!("USER"), bytes!("Not enough parameters")]);
+                    return;
+                }
+                self.clients.find_mut(&id).map(|c| {
+                    c.user = Some((args[0].to_owned(), args[3].to_owned()));
+                });
+                self.try_register(id);
+            }
+            ("USER", true) => self.numeric(id, 462, [bytes!("You may not reregister")]),
+            (_, false) => self.numeric(id, 451, [bytes!("You have not registered")]),
+            ("JOIN", true) => self.handle_join(id, args),
+            ("PART", true) => self.handle_part(id, args),
+            ("PRIVMSG", true) | ("NOTICE", true) => self.handle_message(id, command, args),
+            ("TOPIC", true) => self.handle_topic(id, args),
+            ("MODE", true) => self.handle_mode(id, args),
+            ("KICK", true) => self.handle_kick(id, args),
+            ("WHO", true) => self.handle_who(id, args),
+            ("NAMES", true) => {
+                if args.is_empty() {
+                    self.numeric(id, 366, [bytes!("*"), bytes!("End of /NAMES list")]);
+                } else {
+                    for name in args[0].split(|&b| b == ',' as u8) {
+                        self.send_names(id, name);
+                    }
+                }
+            }
+            ("MOTD", true) => self.send_motd(id),
+            (_, true) => self.numeric(id, 421, [cmd.as_bytes(), bytes!("Unknown command")])
+        }
+    }
+
+    fn handle_cap(&mut self, id: uint, args: &[&[u8]]) {
+        // we don't support any capabilities, but clients that ask wait for CAP END
+        let sub = if args.is_empty() { ~[] } else { CaseMapRfc1459.to_lower(args[0]) };
+        let nick = self.clients.find(&id).map_or(~[], |c| c.nick().to_owned());
+        match sub.as_slice() {
+            b if b == bytes!("ls") || b == bytes!("list") => {
+                self.clients.find_mut(&id).map(|c| c.negotiating = true);
+                let line = build_line(self.name, "CAP", [nick.as_slice(), args[0], bytes!("")]);
+                self.send(id, line);
+            }
+            b if b == bytes!("req") => {
+                self.clients.find_mut(&id).map(|c| c.negotiating = true);
+                let caps = if args.len() > 1 { args[1] } else { bytes!("") };
+                self.send(id, build_line(self.name, "CAP", [nick.as_slice(), bytes!("NAK"), caps]));
+            }
+            b if b == bytes!("end") => {
+                self.clients.find_mut(&id).map(|c| c.negotiating = false);
+                self.try_register(id);
+            }
+            _ => self.numeric(id, 410, [args.head().map_or(bytes!(""), |a| *a),
+                                        bytes!("Invalid CAP command")])
+        }
+    }
+
+    fn handle_nick(&mut self, id: uint, args: &[&[u8]]) {
+        if args.is_empty() {
+            self.numeric(id, 431, [bytes!("No nickname given")]);
+            return;
+        }
+        let nick = args[0];
+        if !valid_nick(nick) {
+            self.numeric(id, 432, [nick, bytes!("Erroneous nickname")]);
+            return;
+        }
+        match self.nicks.find(&lower(nick)) {
+            Some(&other) if other != id => {
+                self.numeric(id, 433, [nick, bytes!("Nickname is already in use")]);
+                return;
+            }
+            _ => ()
+        }
+        let (old, prefix, registered) = match self.clients.find(&id) {
+            None => return,
+            Some(c) => (c.nick.clone(), c.prefix(), c.registered)
+        };
+        for old in old.iter() {
+            self.nicks.pop(&lower(*old));
+        }
+        self.nicks.insert(lower(nick), id);
+        self.clients.find_mut(&id).map(|c| c.nick = Some(nick.to_owned()));
+        if registered {
+            self.send_peers(id, build_line(prefix, "NICK", [nick]), true);
+        } else {
+            self.try_register(id);
+        }
+    }
+
+    fn try_register(&mut self, id: uint) {
+        let ready = self.clients.find(&id).map_or(false, |c| {
+            !c.registered && !c.negotiating && c.nick.is_some() && c.user.is_some()
+        });
+        if !ready {
+            return;
+        }
+        let prefix = {
+            let client = self.clients.find_mut(&id).unwrap();
+            client.registered = true;
+            client.prefix()
+        };
+        let welcome = format!("Welcome to the {} Internet Relay Chat Network {}",
+                              str::from_utf8_lossy(self.network).as_slice(),
+                              str::from_utf8_lossy(prefix).as_slice());
+        let host = format!("Your host is {}, running rust-irclib",
+                           str::from_utf8_lossy(self.name).as_slice());
+        let created = format!("This server was created {}", self.created);
+        self.numeric(id, 1, [welcome.as_bytes()]);
+        self.numeric(id, 2, [host.as_bytes()]);
+        self.numeric(id, 3, [created.as_bytes()]);
+        self.numeric(id, 4, [self.name.as_slice(), bytes!("rust-irclib"), bytes!("i"),
+                             bytes!("biklmnotv")]);
+        let network = bytes!("NETWORK=") + self.network;
+        let nicklen = format!("NICKLEN={}", NickLen);
+        let chanlen = format!("CHANNELLEN={}", ChannelLen);
+        self.numeric(id, 5, [bytes!("CASEMAPPING=rfc1459"), bytes!("CHANTYPES=#"),
+                             bytes!("CHANMODES=b,k,l,imnt"), bytes!("PREFIX=(ov)@+"),
+                             network.as_slice(), nicklen.as_bytes(), chanlen.as_bytes(),
+                             bytes!("are supported by this server")]);
+        self.send_motd(id);
+    }
+
+    fn send_motd(&self, id: uint) {
+        if self.motd.is_empty() {
+            self.numeric(id, 422, [bytes!("MOTD File is missing")]);
+            return;
+        }
+        let start = format!("- {} Message of the day - ",
+                            str::from_utf8_lossy(self.name).as_slice());
+        self.numeric(id, 375, [start.as_bytes()]);
+        for line in self.motd.iter() {
+            self.numeric(id, 372, [bytes!("- ") + *line]);
+        }
+        self.numeric(id, 376, [bytes!("End of /MOTD command")]);
+    }
+
+    fn handle_join(&mut self, id: uint, args: &[&[u8]]) {
+        if args.is_empty() {
+            self.numeric(id, 461, [bytes!("JOIN"), bytes!("Not enough parameters")]);
+            return;
+        }
+        if args[0] == bytes!("0") {
+            let names: ~[~[u8]] = self.channels.values().filter(|c| c.member_modes(id).is_some())
+                                                      .map(|c| c.name.clone()).collect();
+            for name in names.iter() {
+                self.part(id, *name, bytes!(""));
+            }
+            return;
+        }
+        let keys: ~[&[u8]] = if args.len() > 1 {
+            args[1].split(|&b| b == ',' as u8).collect()
+        } else {
+            ~[]
+        };
+        for (i, name) in args[0].split(|&b| b == ',' as u8).enumerate() {
+            let key = if i < keys.len() { Some(keys[i]) } else { None };
+            self.join(id, name, key);
+        }
+    }
+
+    fn join(&mut self, id: uint, name: &[u8], key: Option<&[u8]>) {
+        if !valid_channel(name) {
+            self.numeric(id, 403, [name, bytes!("No such channel")]);
+            return;
+        }
+        let prefix = match self.clients.find(&id) {
+            None => return,
+            Some(c) => c.prefix()
+        };
+        let lname = lower(name);
+        let err = match self.channels.find(&lname) {
+            None => None,
+            Some(chan) => {
+                let user = User::parse(prefix.as_slice());
+                if chan.member_modes(id).is_some() {
+                    return;
+                } else if chan.limit.map_or(false, |l| chan.members.len() >= l) {
+                    Some((471, "Cannot join channel (+l)"))
+                } else if chan.modes.contains(&('i' as u8)) {
+                    Some((473, "Cannot join channel (+i)"))
+                } else if chan.bans.iter().any(|&(ref m, _, _)| {
+                    mask::Mask::parse(*m).matches(&user, None, CaseMapRfc1459)
+                }) {
+                    Some((474, "Cannot join channel (+b)"))
+                } else if chan.key.is_some() && chan.key.as_ref().map(|k| k.as_slice()) != key {
+                    Some((475, "Cannot join channel (+k)"))
+                } else {
+                    None
+                }
+            }
+        };
+        match err {
+            Some((code, msg)) => {
+                self.numeric(id, code, [name, msg.as_bytes()]);
+                return;
+            }
+            None => ()
+        }
+        let now = time::get_time().sec;
+        let name = {
+            let chan = self.channels.find_or_insert_with(lname.clone(), |_| {
+                Channel {
+                    name: name.to_owned(),
+                    topic: None,
+                    created: now,
+                    members: ~[],
+                    modes: bytes!("nt").to_owned(),
+                    key: None,
+                    limit: None,
+                    bans: ~[]
+                }
+            });
+            // the first member gets ops
+            let modes = if chan.members.is_empty() { ~['o' as u8] } else { ~[] };
+            chan.members.push((id, modes));
+            chan.name.clone()
+        };
+
+        self.send_channel(name, build_line(prefix, "JOIN", [name.as_slice()]), None);
+        let topic = self.channels.find(&lname).and_then(|c| c.topic.clone());
+        match topic {
+            None => (),
+            Some((topic, setter, time)) => {
+                self.numeric(id, 332, [name.as_slice(), topic.as_slice()]);
+                let time = time.to_str();
+                self.numeric(id, 333, [name.as_slice(), setter.as_slice(), time.as_bytes()]);
+            }
+        }
+        self.send_names(id, name);
+    }
+
+    fn send_names(&self, id: uint, name: &[u8]) {
+        match self.channels.find(&lower(name)) {
+            None => (),
+            Some(chan) => {
+                let mut names = ~[];
+                for &(member, ref modes) in chan.members.iter() {
+                    let nick = match self.clients.find(&member) {
+                        None => continue,
+                        Some(c) => c.nick()
+                    };
+                    if !names.is_empty() {
+                        names.push(' ' as u8);
+                    }
+                    if modes.contains(&('o' as u8)) {
+                        names.push('@' as u8);
+                    } else if modes.contains(&('v' as u8)) {
+                        names.push('+' as u8);
+                    }
+                    names.push_all(nick);
+                }
+                self.numeric(id, 353, [bytes!("="), chan.name.as_slice(), names.as_slice()]);
+            }
+        }
+        self.numeric(id, 366, [name, bytes!("End of /NAMES list")]);
+    }
+
+    fn handle_part(&mut self, id: uint, args: &[&[u8]]) {
+        if args.is_empty() {
+            self.numeric(id, 461, [bytes!("PART"), bytes!("Not enough parameters")]);
+            return;
+        }
+        let reason = if args.len() > 1 { args[1] } else { bytes!("") };
+        for name in args[0].split(|&b| b == ',' as u8) {
+            self.part(id, name, reason);
+        }
+    }
+
+    fn part(&mut self, id: uint, name: &[u8], reason: &[u8]) {
+        let lname = lower(name);
+        let member = match self.channels.find(&lname) {
+            None => {
+                self.numeric(id, 403, [name, bytes!("No such channel")]);
+                return;
+            }
+            Some(chan) => chan.member_modes(id).is_some()
+        };
+        if !member {
+            self.numeric(id, 442, [name, bytes!("You're not on that channel")]);
+            return;
+        }
+        let prefix = self.clients.find(&id).map_or(~[], |c| c.prefix());
+        let name = self.channels.find(&lname).unwrap().name.clone();
+        let line = if reason.is_empty() {
+            build_line(prefix, "PART", [name.as_slice()])
+        } else {
+            build_line(prefix, "PART", [name.as_slice(), reason])
+        };
+        self.send_channel(name, line, None);
+        self.channels.find_mut(&lname).unwrap().members.retain(|&(m, _)| m != id);
+        self.remove_empty_channels();
+    }
+
+    fn handle_message(&mut self, id: uint, command: Command, args: &[&[u8]]) {
+        let notice = match command {
+            IRCCmd(ref cmd) => upper(*cmd) == ~"NOTICE",
+            IRCCTCPReply(..) => true,
+            _ => false
+        };
+        let (target, text) = match command {
+            IRCCmd(_) => {
+                match args {
+                    [] => {
+                        if !notice {
+                            self.numeric(id, 411, [bytes!("No recipient given")]);
+                        }
+                        return;
+                    }
+                    [_] => {
+                        if !notice {
+                            self.numeric(id, 412, [bytes!("No text to send")]);
+                        }
+                        return;
+                    }
+                    [target, text, ..] => (target.to_owned(), Some(text))
+                }
+            }
+            IRCAction(ref dst) | IRCCTCP(_, ref dst) | IRCCTCPReply(_, ref dst) => {
+                (dst.clone(), None)
+            }
+            IRCCode(_) => return
+        };
+        let prefix = self.clients.find(&id).map_or(~[], |c| c.prefix());
+        // CTCP messages are rebuilt from the parsed line
+        let line = match text {
+            Some(text) => {
+                let cmd = if notice { "NOTICE" } else { "PRIVMSG" };
+                build_line(prefix, cmd, [target.as_slice(), text])
+            }
+            None => {
+                let line = Line{
+                    tags: ~[],
+                    prefix: Some(User::parse(prefix.as_slice())),
+                    command: command.clone(),
+                    args: args.iter().map(|a| a.to_owned()).collect()
+                };
+                line.to_raw() + bytes!("\r\n")
+            }
+        };
+
+        if target.starts_with(bytes!("#")) {
+            let err = match self.channels.find(&lower(target)) {
+                None => Some((403, "No such channel")),
+                Some(chan) => {
+                    let modes = chan.member_modes(id);
+                    if modes.is_none() && chan.modes.contains(&('n' as u8)) {
+                        Some((404, "Cannot send to channel"))
+                    } else if chan.modes.contains(&('m' as u8)) &&
+                              !modes.map_or(false, |m| !m.is_empty()) {
+                        Some((404, "Cannot send to channel"))
+                    } else {
+                        None
+                    }
+                }
+            };
+            match err {
+                Some((code, msg)) => {
+                    if !notice {
+                        self.numeric(id, code, [target.as_slice(), msg.as_bytes()]);
+                    }
+                }
+                None => self.send_channel(target, line, Some(id))
+            }
+        } else {
+            match self.nicks.find(&lower(target)) {
+                Some(&dst) => self.send(dst, line),
+                None => {
+                    if !notice {
+                        self.numeric(id, 401, [target.as_slice(), bytes!("No such nick/channel")]);
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_topic(&mut self, id: uint, args: &[&[u8]]) {
+        if args.is_empty() {
+            self.numeric(id, 461, [bytes!("TOPIC"), bytes!("Not enough parameters")]);
+            return;
+        }
+        let lname = lower(args[0]);
+        let (name, topic, member, op, locked) = match self.channels.find(&lname) {
+            None => {
+                self.numeric(id, 403, [args[0], bytes!("No such channel")]);
+                return;
+            }
+            Some(c) => (c.name.clone(), c.topic.clone(), c.member_modes(id).is_some(),
+                        c.is_op(id), c.modes.contains(&('t' as u8)))
+        };
+        if args.len() == 1 {
+            match topic {
+                None => self.numeric(id, 331, [name.as_slice(), bytes!("No topic is set")]),
+                Some((topic, setter, time)) => {
+                    self.numeric(id, 332, [name.as_slice(), topic.as_slice()]);
+                    let time = time.to_str();
+                    self.numeric(id, 333, [name.as_slice(), setter.as_slice(), time.as_bytes()]);
+                }
+            }
+            return;
+        }
+        if !member {
+            self.numeric(id, 442, [name.as_slice(), bytes!("You're not on that channel")]);
+            return;
+        }
+        if locked && !op {
+            self.numeric(id, 482, [name.as_slice(), bytes!("You're not channel operator")]);
+            return;
+        }
+        let (prefix, nick) = match self.clients.find(&id) {
+            None => return,
+            Some(c) => (c.prefix(), c.nick().to_owned())
+        };
+        let text = args[1];
+        self.channels.find_mut(&lname).unwrap().topic = if text.is_empty() {
+            None
+        } else {
+            Some((text.to_owned(), nick, time::get_time().sec))
+        };
+        self.send_channel(name, build_line(prefix, "TOPIC", [name.as_slice(), text]), None);
+    }
+
+    fn handle_mode(&mut self, id: uint, args: &[&[u8]]) {
+        if args.is_empty() {
+            self.numeric(id, 461, [bytes!("MODE"), bytes!("Not enough parameters")]);
+            return;
+        }
+        if !args[0].starts_with(bytes!("#")) {
+            self.user_mode(id, args);
+            return;
+        }
+        let lname = lower(args[0]);
+        let (name, op) = match self.channels.find(&lname) {
+            None => {
+                self.numeric(id, 403, [args[0], bytes!("No such channel")]);
+                return;
+            }
+            Some(c) => (c.name.clone(), c.is_op(id))
+        };
+        if args.len() == 1 {
+            let (modes, params, created) = {
+                let chan = self.channels.find(&lname).unwrap();
+                let mut modes = bytes!("+").to_owned();
+                modes.push_all(chan.modes);
+                let mut params = ~[];
+                for key in chan.key.iter() {
+                    modes.push('k' as u8);
+                    params.push(key.clone());
+                }
+                for limit in chan.limit.iter() {
+                    modes.push('l' as u8);
+                    params.push(limit.to_str().into_bytes());
+                }
+                (modes, params, chan.created.to_str())
+            };
+            let mut reply = ~[name.as_slice(), modes.as_slice()];
+            for p in params.iter() {
+                reply.push(p.as_slice());
+            }
+            self.numeric(id, 324, reply);
+            self.numeric(id, 329, [name.as_slice(), created.as_bytes()]);
+            return;
+        }
+        if args.len() == 2 && (args[1] == bytes!("b") || args[1] == bytes!("+b")) {
+            let bans = self.channels.find(&lname).unwrap().bans.clone();
+            for &(ref mask, ref setter, time) in bans.iter() {
+                let time = time.to_str();
+                self.numeric(id, 367, [name.as_slice(), mask.as_slice(), setter.as_slice(),
+                                       time.as_bytes()]);
+            }
+            self.numeric(id, 368, [name.as_slice(), bytes!("End of channel ban list")]);
+            return;
+        }
+        if !op {
+            self.numeric(id, 482, [name.as_slice(), bytes!("You're not channel operator")]);
+            return;
+        }
+
+        let (prefix, nick) = match self.clients.find(&id) {
+            None => return,
+            Some(c) => (c.prefix(), c.nick().to_owned())
+        };
+        let mut params = args.slice_from(2).iter();
+        let mut set = true;
+        // the changes that were applied, as (set, mode, param)
+        let mut applied: ~[(bool, u8, Option<~[u8]>)] = ~[];
+        for &m in args[1].iter() {
+            match m as char {
+                '+' => set = true,
+                '-' => set = false,
+                'o' | 'v' => {
+                    let target = match params.next() {
+                        None => continue,
+                        Some(t) => *t
+                    };
+                    let tid = match self.nicks.find(&lower(target)) {
+                        None => {
+                            self.numeric(id, 401, [target, bytes!("No such nick/channel")]);
+                            continue;
+                        }
+                        Some(&tid) => tid
+                    };
+                    let tnick = self.clients.find(&tid).map_or(~[], |c| c.nick().to_owned());
+                    let idx = self.channels.find(&lname).and_then(|c| {
+                        c.members.iter().position(|&(mid, _)| mid == tid)
+                    });
+                    let idx = match idx {
+                        None => {
+                            let line = build_line(self.name, "441",
+                                                  [nick.as_slice(), tnick.as_slice(),
+                                                   name.as_slice(),
+                                                   bytes!("They aren't on that channel")]);
+                            self.send(id, line);
+                            continue;
+                        }
+                        Some(idx) => idx
+                    };
+                    match self.channels.find_mut(&lname).unwrap().members[idx] {
+                        (_, ref mut modes) => {
+                            if set && !modes.contains(&m) {
+                                modes.push(m);
+                            } else if !set {
+                                modes.retain(|&x| x != m);
+                            }
+                        }
+                    }
+                    applied.push((set, m, Some(tnick)));
+                }
+                'b' => {
+                    let mask = match params.next() {
+                        None => continue,
+                        Some(mask) => mask::normalize(*mask)
+                    };
+                    let chan = self.channels.find_mut(&lname).unwrap();
+                    let exists = chan.bans.iter().any(|&(ref b, _, _)| {
+                        CaseMapRfc1459.equiv(*b, mask)
+                    });
+                    if set && !exists {
+                        chan.bans.push((mask.clone(), nick.clone(), time::get_time().sec));
+                    } else if !set && exists {
+                        chan.bans.retain(|&(ref b, _, _)| !CaseMapRfc1459.equiv(*b, mask));
+                    } else {
+                        continue;
+                    }
+                    applied.push((set, m, Some(mask)));
+                }
+                'k' => {
+                    let chan = self.channels.find_mut(&lname).unwrap();
+                    if set {
+                        let key = match params.next() {
+                            None => continue,
+                            Some(key) => key.to_owned()
+                        };
+                        chan.key = Some(key.clone());
+                        applied.push((set, m, Some(key)));
+                    } else {
+                        params.next();
+                        if chan.key.take().is_some() {
+                            applied.push((set, m, Some(bytes!("*").to_owned())));
+                        }
+                    }
+                }
+                'l' => {
+                    let chan = self.channels.find_mut(&lname).unwrap();
+                    if set {
+                        let limit = match params.next().and_then(|l| uint::parse_bytes(*l, 10)) {
+                            None => continue,
+                            Some(limit) => limit
+                        };
+                        chan.limit = Some(limit);
+                        applied.push((set, m, Some(limit.to_str().into_bytes())));
+                    } else if chan.limit.take().is_some() {
+                        applied.push((set, m, None));
+                    }
+                }
+                'i' | 'm' | 'n' | 't' => {
+                    let chan = self.channels.find_mut(&lname).unwrap();
+                    if set && !chan.modes.contains(&m) {
+                        chan.modes.push(m);
+                    } else if !set && chan.modes.contains(&m) {
+                        chan.modes.retain(|&x| x != m);
+                    } else {
+                        continue;
+                    }
+                    applied.push((set, m, None));
+                }
+                _ => {
+                    let c = [m];
+                    self.numeric(id, 472, [c.as_slice(), bytes!("is unknown mode char to me")]);
+                }
+            }
+        }
+        if applied.is_empty() {
+            return;
+        }
+
+        let mut modes = ~[];
+        let mut mode_args = ~[];
+        let mut last = None;
+        for &(set, m, ref param) in applied.iter() {
+            if last != Some(set) {
+                modes.push(if set { '+' as u8 } else { '-' as u8 });
+                last = Some(set);
+            }
+            modes.push(m);
+            for p in param.iter() {
+                mode_args.push(p.as_slice());
+            }
+        }
+        let mut line_args = ~[name.as_slice(), modes.as_slice()];
+        line_args.push_all(mode_args);
+        self.send_channel(name, build_line(prefix, "MODE", line_args), None);
+    }
+
+    fn user_mode(&mut self, id: uint, args: &[&[u8]]) {
+        let nick = self.clients.find(&id).map_or(~[], |c| c.nick().to_owned());
+        if !CaseMapRfc1459.equiv(args[0], nick) {
+            if self.nicks.contains_key(&lower(args[0])) {
+                self.numeric(id, 502, [bytes!("Cant change mode for other users")]);
+            } else {
+                self.numeric(id, 401, [args[0], bytes!("No such nick/channel")]);
+            }
+            return;
+        }
+        if args.len() == 1 {
+            let modes = bytes!("+") + self.clients.find(&id).map_or(~[], |c| c.modes.clone());
+            self.numeric(id, 221, [modes.as_slice()]);
+            return;
+        }
+        let mut set = true;
+        let mut changed = ~[];
+        let mut last = None;
+        {
+            let client = self.clients.find_mut(&id).unwrap();
+            for &m in args[1].iter() {
+                match m as char {
+                    '+' => set = true,
+                    '-' => set = false,
+                    // only invisible is supported, and there are no operators
+                    'i' | 'w' => {
+                        if set == client.modes.contains(&m) {
+                            continue;
+                        }
+                        if set {
+                            client.modes.push(m);
+                        } else {
+                            client.modes.retain(|&x| x != m);
+                        }
+                        if last != Some(set) {
+                            changed.push(if set { '+' as u8 } else { '-' as u8 });
+                            last = Some(set);
+                        }
+                        changed.push(m);
+                    }
+                    _ => ()
+                }
+            }
+        }
+        if !changed.is_empty() {
+            self.send(id, build_line(nick, "MODE", [nick.as_slice(), changed.as_slice()]));
+        }
+    }
+
+    fn handle_kick(&mut self, id: uint, args: &[&[u8]]) {
+        if args.len() < 2 {
+            self.numeric(id, 461, [bytes!("KICK"), bytes!("Not enough parameters")]);
+            return;
+        }
+        let lname = lower(args[0]);
+        let (name, op) = match self.channels.find(&lname) {
+            None => {
+                self.numeric(id, 403, [args[0], bytes!("No such channel")]);
+                return;
+            }
+            Some(c) => (c.name.clone(), c.is_op(id))
+        };
+        if !op {
+            self.numeric(id, 482, [name.as_slice(), bytes!("You're not channel operator")]);
+            return;
+        }
+        let (prefix, nick) = match self.clients.find(&id) {
+            None => return,
+            Some(c) => (c.prefix(), c.nick().to_owned())
+        };
+        let reason = if args.len() > 2 { args[2] } else { nick.as_slice() };
+        for target in args[1].split(|&b| b == ',' as u8) {
+            let tid = self.nicks.find(&lower(target)).map(|&t| t);
+            let member = tid.map_or(false, |t| {
+                self.channels.find(&lname).map_or(false, |c| c.member_modes(t).is_some())
+            });
+            if !member {
+                let me = nick.as_slice();
+                let line = build_line(self.name, "441", [me, target, name.as_slice(),
+                                                         bytes!("They aren't on that channel")]);
+                self.send(id, line);
+                continue;
+            }
+            let line = build_line(prefix, "KICK", [name.as_slice(), target, reason]);
+            self.send_channel(name, line, None);
+            let tid = tid.unwrap();
+            self.channels.find_mut(&lname).map(|c| c.members.retain(|&(m, _)| m != tid));
+        }
+        self.remove_empty_channels();
+    }
+
+    fn handle_who(&mut self, id: uint, args: &[&[u8]]) {
+        let target = if args.is_empty() { bytes!("*") } else { args[0] };
+        // (channel name, member id, prefix modes) for each user to list
+        let mut rows: ~[(~[u8], uint, ~[u8])] = ~[];
+        if target.starts_with(bytes!("#")) {
+            match self.channels.find(&lower(target)) {
+                None => (),
+                Some(chan) => {
+                    for &(member, ref modes) in chan.members.iter() {
+                        rows.push((chan.name.clone(), member, modes.clone()));
+                    }
+                }
+            }
+        } else {
+            for (&cid, client) in self.clients.iter() {
+                if client.registered && mask::glob_match(target, client.nick(), CaseMapRfc1459) {
+                    rows.push((bytes!("*").to_owned(), cid, ~[]));
+                }
+            }
+        }
+        for &(ref chan, member, ref modes) in rows.iter() {
+            let (user, host, nick, real) = match self.clients.find(&member) {
+                None => continue,
+                Some(c) => {
+                    let (user, real) = c.user.clone().unwrap_or((~[], ~[]));
+                    (user, c.host.clone(), c.nick().to_owned(), real)
+                }
+            };
+            // there is no AWAY support, so everyone is here
+            let mut flags = bytes!("H").to_owned();
+            if modes.contains(&('o' as u8)) {
+                flags.push('@' as u8);
+            } else if modes.contains(&('v' as u8)) {
+                flags.push('+' as u8);
+            }
+            let real = bytes!("0 ") + real;
+            self.numeric(id, 352, [chan.as_slice(), user.as_slice(), host.as_slice(),
+                                   self.name.as_slice(), nick.as_slice(), flags.as_slice(),
+                                   real.as_slice()]);
+        }
+        self.numeric(id, 315, [target, bytes!("End of /WHO list")]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Server, ServerOptions, trim_line};
+    use conn;
+    use conn::{Options, LineReceived, IRCCmd, IRCCode};
+    use mask::glob_match;
+    use std::io::BufferedStream;
+    use std::io::net::ip::{Ipv4Addr, SocketAddr};
+    use std::io::net::tcp::TcpStream;
+    use std::str;
+    use CaseMapAscii;
+
+    // A client that speaks raw IRC, checking what it receives against glob patterns
+    // in the manner of a MockServer script
+    struct Client {
+        stream: BufferedStream<TcpStream>
+    }
+
+    impl Client {
+        fn connect(server: &Server) -> Client {
+            let stream = TcpStream::connect(server.addr()).unwrap();
+            Client{ stream: BufferedStream::new(stream) }
+        }
+
+        // Connects and registers, skipping the welcome burst
+        fn register(server: &Server, nick: &str) -> Client {
+            let mut client = Client::connect(server);
+            client.send(format!("NICK {}", nick).as_slice());
+            client.send(format!("USER {} 8 * :{}", nick, nick).as_slice());
+            client.expect_eventually(":irc.test 422 *");
+            client
+        }
+
+        fn send(&mut self, line: &str) {
+            self.stream.write(line.as_bytes()).unwrap();
+            self.stream.write(bytes!("\r\n")).unwrap();
+            self.stream.flush().unwrap();
+        }
+
+        fn line(&mut self) -> Option<~[u8]> {
+            match self.stream.read_until('\n' as u8) {
+                Err(_) => None,
+                Ok(line) => Some(trim_line(line).to_owned())
+            }
+        }
+
+        // Reads a line, which must match the pattern
+        fn expect(&mut self, pattern: &str) {
+            match self.line() {
+                None => fail!("expected `{}`, got EOF", pattern),
+                Some(line) => {
+                    if !glob_match(pattern.as_bytes(), line, CaseMapAscii) {
+                        fail!("expected `{}`, got `{}`", pattern,
+                              str::from_utf8_lossy(line).as_slice());
+                    }
+                }
+            }
+        }
+
+        // Reads lines until one matches the pattern
+        fn expect_eventually(&mut self, pattern: &str) {
+            loop {
+                match self.line() {
+                    None => fail!("expected `{}`, got EOF", pattern),
+                    Some(line) => {
+                        if glob_match(pattern.as_bytes(), line, CaseMapAscii) {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Waits for the server to handle everything we sent so far, and checks that
+        // nothing else was sent to us in the meantime
+        fn sync(&mut self) {
+            self.send("PING sync");
+            self.expect(":irc.test PONG irc.test sync");
+        }
+    }
+
+    fn start_server() -> Server {
+        let addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+        Server::start(ServerOptions::new(addr, "irc.test")).unwrap()
+    }
+
+    // Registers alice and bob, and joins them to #chat with alice as the operator
+    fn start_chat(server: &Server) -> (Client, Client) {
+        let mut alice = Client::register(server, "alice");
+        alice.send("JOIN #chat");
+        alice.expect(":alice!alice@127.0.0.1 JOIN #chat");
+        alice.expect(":irc.test 353 alice = #chat @alice");
+        alice.expect(":irc.test 366 alice #chat :End of /NAMES list");
+        let mut bob = Client::register(server, "bob");
+        bob.send("JOIN #chat");
+        bob.expect(":bob!bob@127.0.0.1 JOIN #chat");
+        bob.expect(":irc.test 353 bob = #chat :@alice bob");
+        bob.expect(":irc.test 366 bob #chat :End of /NAMES list");
+        alice.expect(":bob!bob@127.0.0.1 JOIN #chat");
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_server() {
+        let addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+        let server = Server::start(ServerOptions::new(addr, "irc.test")).unwrap();
+
+        let mut opts = Options::new("127.0.0.1", server.port());
+        opts.nick = "tester";
+        opts.autojoin = &[("#test", "")];
+        let mut topic = None;
+        let mut welcomed = false;
+        let res = conn::connect(opts, |conn, event| {
+            match event {
+                LineReceived(ref line) => {
+                    match line.command {
+                        IRCCode(1) => welcomed = true,
+                        IRCCode(366) => {
+                            conn.send_command(IRCCmd(~"TOPIC"),
+                                              [bytes!("#test"), bytes!("hello world")], true);
+                        }
+                        IRCCmd(~"TOPIC") => {
+                            topic = Some(line.args[1].clone());
+                            let ops = conn.state().channel(bytes!("#test")).map_or(false, |c| {
+                                c.members.iter().any(|m| m.modes == ~['o' as u8])
+                            });
+                            assert!(ops);
+                            conn.quit([]);
+                        }
+                        _ => ()
+                    }
+                }
+                _ => ()
+            }
+        });
+        assert!(res.is_ok());
+        assert!(welcomed);
+        assert_eq!(topic, Some(bytes!("hello world").to_owned()));
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_messages() {
+        let server = start_server();
+        let (mut alice, mut bob) = start_chat(&server);
+        let mut carol = Client::register(&server, "carol");
+
+        // channel messages go to every member but the sender
+        alice.send("PRIVMSG #chat :hi all");
+        bob.expect(":alice!alice@127.0.0.1 PRIVMSG #chat :hi all");
+        alice.sync();
+        alice.send("PRIVMSG #chat :\x01ACTION waves\x01");
+        bob.expect(":alice!alice@127.0.0.1 PRIVMSG #chat :\x01ACTION waves*");
+        bob.send("NOTICE #CHAT :noted");
+        alice.expect(":bob!bob@127.0.0.1 NOTICE #CHAT noted");
+        carol.sync();
+
+        // private messages find the target regardless of case
+        alice.send("PRIVMSG Bob :psst");
+        bob.expect(":alice!alice@127.0.0.1 PRIVMSG Bob psst");
+        bob.send("NOTICE alice :what");
+        alice.expect(":bob!bob@127.0.0.1 NOTICE alice what");
+        carol.sync();
+
+        // +n keeps outsiders out
+        carol.send("PRIVMSG #chat :let me in");
+        carol.expect(":irc.test 404 carol #chat :Cannot send to channel");
+        carol.send("PRIVMSG #nowhere :hello");
+        carol.expect(":irc.test 403 carol #nowhere :No such channel");
+        carol.send("PRIVMSG nobody :hello");
+        carol.expect(":irc.test 401 carol nobody :No such nick/channel");
+        carol.send("PRIVMSG");
+        carol.expect(":irc.test 411 carol :No recipient given");
+        carol.send("PRIVMSG bob");
+        carol.expect(":irc.test 412 carol :No text to send");
+        // notices never get error replies
+        carol.send("NOTICE #chat :let me in");
+        carol.send("NOTICE nobody :hello");
+        carol.sync();
+        alice.sync();
+        bob.sync();
+
+        // +m only lets voiced users and operators speak
+        alice.send("MODE #chat +m");
+        alice.expect(":alice!alice@127.0.0.1 MODE #chat +m");
+        bob.expect(":alice!alice@127.0.0.1 MODE #chat +m");
+        bob.send("PRIVMSG #chat :can you hear me");
+        bob.expect(":irc.test 404 bob #chat :Cannot send to channel");
+        alice.send("MODE #chat +v bob");
+        alice.expect(":alice!alice@127.0.0.1 MODE #chat +v bob");
+        bob.expect(":alice!alice@127.0.0.1 MODE #chat +v bob");
+        bob.send("PRIVMSG #chat :now?");
+        alice.expect(":bob!bob@127.0.0.1 PRIVMSG #chat now?");
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_channel_modes() {
+        let server = start_server();
+        let (mut alice, mut bob) = start_chat(&server);
+
+        bob.send("MODE #chat +t");
+        bob.expect(":irc.test 482 bob #chat :You're not channel operator");
+        bob.send("TOPIC #chat :mine now");
+        bob.expect(":irc.test 482 bob #chat :You're not channel operator");
+        alice.send("MODE #chat +z");
+        alice.expect(":irc.test 472 alice z :is unknown mode char to me");
+
+        alice.send("MODE #chat +o bob");
+        alice.expect(":alice!alice@127.0.0.1 MODE #chat +o bob");
+        bob.expect(":alice!alice@127.0.0.1 MODE #chat +o bob");
+        bob.send("MODE #chat -o+v alice alice");
+        bob.expect(":bob!bob@127.0.0.1 MODE #chat -o+v alice alice");
+        alice.expect(":bob!bob@127.0.0.1 MODE #chat -o+v alice alice");
+        alice.send("NAMES #chat");
+        alice.expect(":irc.test 353 alice = #chat :+alice @bob");
+        alice.expect(":irc.test 366 alice #chat :End of /NAMES list");
+        alice.send("MODE #chat +o alice");
+        alice.expect(":irc.test 482 alice #chat :You're not channel operator");
+
+        bob.send("MODE #chat +kl secret 2");
+        bob.expect(":bob!bob@127.0.0.1 MODE #chat +kl secret 2");
+        alice.expect(":bob!bob@127.0.0.1 MODE #chat +kl secret 2");
+        alice.send("MODE #chat");
+        alice.expect(":irc.test 324 alice #chat +ntkl secret 2");
+        alice.expect(":irc.test 329 alice #chat *");
+
+        // +l is checked first, then +k
+        let mut carol = Client::register(&server, "carol");
+        carol.send("JOIN #chat secret");
+        carol.expect(":irc.test 471 carol #chat :Cannot join channel (+l)");
+        bob.send("MODE #chat -l");
+        bob.expect(":bob!bob@127.0.0.1 MODE #chat -l");
+        alice.expect(":bob!bob@127.0.0.1 MODE #chat -l");
+        carol.send("JOIN #chat");
+        carol.expect(":irc.test 475 carol #chat :Cannot join channel (+k)");
+        carol.send("JOIN #chat wrong");
+        carol.expect(":irc.test 475 carol #chat :Cannot join channel (+k)");
+        carol.send("JOIN #chat secret");
+        carol.expect(":carol!carol@127.0.0.1 JOIN #chat");
+        carol.expect(":irc.test 353 carol = #chat :+alice @bob carol");
+        carol.expect(":irc.test 366 carol #chat :End of /NAMES list");
+        alice.expect(":carol!carol@127.0.0.1 JOIN #chat");
+        bob.expect(":carol!carol@127.0.0.1 JOIN #chat");
+
+        bob.send("MODE #chat -k+i whatever");
+        bob.expect(":bob!bob@127.0.0.1 MODE #chat -k+i *");
+        alice.expect(":bob!bob@127.0.0.1 MODE #chat -k+i *");
+        let mut dave = Client::register(&server, "dave");
+        dave.send("JOIN #chat");
+        dave.expect(":irc.test 473 dave #chat :Cannot join channel (+i)");
+
+        // user modes only apply to ourselves
+        alice.send("MODE alice +i");
+        alice.expect(":alice MODE alice +i");
+        alice.send("MODE alice");
+        alice.expect(":irc.test 221 alice +i");
+        alice.send("MODE bob +i");
+        alice.expect(":irc.test 502 alice :Cant change mode for other users");
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_bans() {
+        let server = start_server();
+        let (mut alice, mut bob) = start_chat(&server);
+
+        alice.send("MODE #chat +b bob");
+        alice.expect(":alice!alice@127.0.0.1 MODE #chat +b bob!*@*");
+        bob.expect(":alice!alice@127.0.0.1 MODE #chat +b bob!*@*");
+        // setting the same ban again changes nothing
+        alice.send("MODE #chat +b BOB!*@*");
+        alice.sync();
+        bob.send("MODE #chat b");
+        bob.expect(":irc.test 367 bob #chat bob!*@* alice *");
+        bob.expect(":irc.test 368 bob #chat :End of channel ban list");
+
+        // bans apply when joining
+        bob.send("PART #chat");
+        bob.expect(":bob!bob@127.0.0.1 PART #chat");
+        alice.expect(":bob!bob@127.0.0.1 PART #chat");
+        bob.send("JOIN #chat");
+        bob.expect(":irc.test 474 bob #chat :Cannot join channel (+b)");
+        let mut bobby = Client::register(&server, "bobby");
+        bobby.send("JOIN #chat");
+        bobby.expect(":bobby!bobby@127.0.0.1 JOIN #chat");
+        bobby.expect(":irc.test 353 bobby = #chat :@alice bobby");
+        bobby.expect(":irc.test 366 bobby #chat :End of /NAMES list");
+        alice.expect(":bobby!bobby@127.0.0.1 JOIN #chat");
+
+        alice.send("MODE #chat -b Bob!*@*");
+        alice.expect(":alice!alice@127.0.0.1 MODE #chat -b bob!*@*");
+        bobby.expect(":alice!alice@127.0.0.1 MODE #chat -b bob!*@*");
+        bob.send("JOIN #chat");
+        bob.expect(":bob!bob@127.0.0.1 JOIN #chat");
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_kick() {
+        let server = start_server();
+        let (mut alice, mut bob) = start_chat(&server);
+        let mut carol = Client::register(&server, "carol");
+        carol.send("JOIN #chat");
+        carol.expect_eventually(":irc.test 366 carol #chat *");
+        alice.expect(":carol!carol@127.0.0.1 JOIN #chat");
+        bob.expect(":carol!carol@127.0.0.1 JOIN #chat");
+
+        bob.send("KICK #chat carol");
+        bob.expect(":irc.test 482 bob #chat :You're not channel operator");
+        carol.sync();
+
+        alice.send("KICK #chat bob,nobody :bye now");
+        alice.expect(":alice!alice@127.0.0.1 KICK #chat bob :bye now");
+        bob.expect(":alice!alice@127.0.0.1 KICK #chat bob :bye now");
+        carol.expect(":alice!alice@127.0.0.1 KICK #chat bob :bye now");
+        alice.expect(":irc.test 441 alice nobody #chat :They aren't on that channel");
+
+        // bob is gone from the channel
+        alice.send("KICK #chat bob");
+        alice.expect(":irc.test 441 alice bob #chat :They aren't on that channel");
+        bob.send("PRIVMSG #chat :hey");
+        bob.expect(":irc.test 404 bob #chat :Cannot send to channel");
+        alice.send("KICK #chat carol");
+        alice.expect(":alice!alice@127.0.0.1 KICK #chat carol alice");
+        carol.expect(":alice!alice@127.0.0.1 KICK #chat carol alice");
+        bob.sync();
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_who_names_part() {
+        let server = start_server();
+        let (mut alice, mut bob) = start_chat(&server);
+
+        alice.send("WHO #chat");
+        alice.expect(":irc.test 352 alice #chat alice 127.0.0.1 irc.test alice H@ :0 alice");
+        alice.expect(":irc.test 352 alice #chat bob 127.0.0.1 irc.test bob H :0 bob");
+        alice.expect(":irc.test 315 alice #chat :End of /WHO list");
+        alice.send("WHO b*");
+        alice.expect(":irc.test 352 alice \\* bob 127.0.0.1 irc.test bob H :0 bob");
+        alice.expect(":irc.test 315 alice b\\* :End of /WHO list");
+
+        bob.send("NAMES #chat,#none");
+        bob.expect(":irc.test 353 bob = #chat :@alice bob");
+        bob.expect(":irc.test 366 bob #chat :End of /NAMES list");
+        bob.expect(":irc.test 366 bob #none :End of /NAMES list");
+
+        bob.send("PART #chat :see you");
+        bob.expect(":bob!bob@127.0.0.1 PART #chat :see you");
+        alice.expect(":bob!bob@127.0.0.1 PART #chat :see you");
+        bob.send("PART #chat");
+        bob.expect(":irc.test 442 bob #chat :You're not on that channel");
+        bob.send("PART #none");
+        bob.expect(":irc.test 403 bob #none :No such channel");
+
+        // the channel goes away with its last member
+        alice.send("JOIN 0");
+        alice.expect(":alice!alice@127.0.0.1 PART #chat");
+        alice.send("MODE #chat");
+        alice.expect(":irc.test 403 alice #chat :No such channel");
+        bob.send("JOIN #chat");
+        bob.expect(":bob!bob@127.0.0.1 JOIN #chat");
+        bob.expect(":irc.test 353 bob = #chat @bob");
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_nick() {
+        let server = start_server();
+        let (mut alice, mut bob) = start_chat(&server);
+
+        let mut carol = Client::connect(&server);
+        carol.send("JOIN #chat");
+        carol.expect(":irc.test 451 * :You have not registered");
+        carol.send("NICK ALICE");
+        carol.expect(":irc.test 433 * ALICE :Nickname is already in use");
+        carol.send("NICK 1carol");
+        carol.expect(":irc.test 432 * 1carol :Erroneous nickname");
+        carol.send("NICK carol");
+        carol.send("USER carol 8 * :carol");
+        carol.expect(":irc.test 001 carol *");
+        carol.expect_eventually(":irc.test 422 carol *");
+
+        bob.send("NICK Alice");
+        bob.expect(":irc.test 433 bob Alice :Nickname is already in use");
+        bob.send("NICK Bobby");
+        bob.expect(":bob!bob@127.0.0.1 NICK Bobby");
+        alice.expect(":bob!bob@127.0.0.1 NICK Bobby");
+        carol.sync();
+
+        // the old nick is free, and the new one gets messages
+        carol.send("NICK bob");
+        carol.expect(":carol!carol@127.0.0.1 NICK bob");
+        alice.send("PRIVMSG bobby :hi");
+        bob.expect(":alice!alice@127.0.0.1 PRIVMSG bobby hi");
+        alice.send("PRIVMSG bob :hi");
+        carol.expect(":alice!alice@127.0.0.1 PRIVMSG bob hi");
+        bob.sync();
+
+        // changing case is not a collision
+        alice.send("NICK ALICE");
+        alice.expect(":alice!alice@127.0.0.1 NICK ALICE");
+        bob.expect(":alice!alice@127.0.0.1 NICK ALICE");
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_ping_timeout() {
+        let addr = SocketAddr{ ip: Ipv4Addr(127, 0, 0, 1), port: 0 };
+        let mut opts = ServerOptions::new(addr, "irc.test");
+        opts.ping_interval = 200;
+        opts.ping_timeout = 300;
+        let server = Server::start(opts).unwrap();
+
+        let mut bob = Client::register(&server, "bob");
+        bob.send("JOIN #chat");
+        let mut alice = Client::register(&server, "alice");
+        alice.send("JOIN #chat");
+
+        // bob never answers, and alice sees him time out while she stays connected
+        bob.expect_eventually("PING irc.test");
+        bob.expect("ERROR :Closing Link: 127.0.0.1 (Ping timeout)");
+        let mut pings = 0;
+        loop {
+            let line = alice.line().expect("alice was disconnected");
+            if line.as_slice() == bytes!("PING irc.test") {
+                pings += 1;
+                alice.send("PONG irc.test");
+            } else if line.as_slice() == bytes!(":bob!bob@127.0.0.1 QUIT :Ping timeout") {
+                break;
+            }
+        }
+        assert!(pings > 0);
+        alice.sync();
+        server.shutdown();
+    }
+}
@@ -0,0 +1,741 @@
+//! Tracking of users and channels visible to a connection
+//!
+//! A State is kept up to date by Conn as lines are received, and can be accessed
+//! with Conn::state(). It can also be fed lines directly with handle_line().
+
+use std::hashmap::HashMap;
+use std::i64;
+use time;
+use conn::{Line, IRCCmd, IRCCode};
+use mask::{Mask, QuietMask};
+use {User, CaseMapping, CaseMapRfc1459};
+
+/// Information about a user that shares at least one channel with us
+#[deriving(Clone)]
+pub struct UserInfo {
+    /// The user's nick!user@host, as far as it is known
+    user: User,
+    /// The user's real name, if known
+    realname: Option<~[u8]>,
+    /// The services account the user is logged in to, if known
+    account: Option<~[u8]>,
+    /// The user's away message if they are away. The message may be empty
+    /// if the away state was learned from a WHO reply.
+    away: Option<~[u8]>,
+    /// The channels shared with the user
+    channels: ~[~[u8]]
+}
+
+/// A member of a channel
+#[deriving(Clone)]
+pub struct Member {
+    /// The member's nickname
+    nick: ~[u8],
+    /// The prefix modes the member holds in the channel, e.g. "ov"
+    modes: ~[u8]
+}
+
+/// A channel that we are in
+#[deriving(Clone)]
+pub struct Channel {
+    /// The channel name
+    name: ~[u8],
+    /// The channel topic, if known
+    topic: Option<~[u8]>,
+    /// The members of the channel
+    members: ~[Member],
+    /// Entries of the list modes (bans, exceptions, invite exceptions, quiets)
+    lists: ~[ListEntry]
+}
+
+/// An entry in a channel list mode, such as a ban
+#[deriving(Eq,Clone)]
+pub struct ListEntry {
+    /// The list mode, e.g. 'b' for bans
+    mode: u8,
+    /// The mask
+    mask: ~[u8],
+    /// Who set the entry, if known
+    setter: Option<~[u8]>,
+    /// When the entry was set, in seconds since the epoch, if known
+    time: Option<i64>
+}
+
+/// A single change parsed from a MODE line
+#[deriving(Eq,Clone)]
+pub struct ModeChange {
+    /// `true` if the mode is being set, `false` if it's being unset
+    set: bool,
+    /// The mode character
+    mode: u8,
+    /// The mode argument, if the mode takes one
+    arg: Option<~[u8]>
+}
+
+/// Tracks the users and channels visible to a connection
+pub struct State {
+    priv users: HashMap<~[u8], UserInfo>,
+    priv channels: HashMap<~[u8], Channel>,
+    priv casemap: CaseMapping,
+    priv chantypes: ~[u8],
+    priv prefix: ~[(u8, u8)],
+    priv chanmodes: [~[u8], ..4],
+    // (channel key, mode) for list replies that are in progress
+    priv loading: ~[(~[u8], u8)],
+    priv last_quit: Option<UserInfo>
+}
+
+impl State {
+    /// Returns a new, empty State using the protocol defaults for ISUPPORT values
+    pub fn new() -> State {
+        State {
+            users: HashMap::new(),
+            channels: HashMap::new(),
+            casemap: CaseMapRfc1459,
+            chantypes: bytes!("#&").to_owned(),
+            prefix: ~[('o' as u8, '@' as u8), ('v' as u8, '+' as u8)],
+            chanmodes: [bytes!("beI").to_owned(), bytes!("k").to_owned(),
+                        bytes!("l").to_owned(), bytes!("imnpst").to_owned()],
+            loading: ~[],
+            last_quit: None
+        }
+    }
+
+    /// Returns the case mapping used for nicknames and channel names
+    pub fn casemapping(&self) -> CaseMapping {
+        self.casemap
+    }
+
+    /// Returns `true` if the name is a channel name according to CHANTYPES
+    pub fn is_channel(&self, name: &[u8]) -> bool {
+        !name.is_empty() && self.chantypes.contains(&name[0])
+    }
+
+    /// Returns the status prefix symbol (e.g. '@') for a prefix mode (e.g. 'o')
+    pub fn prefix_symbol(&self, mode: u8) -> Option<u8> {
+        self.prefix.iter().find(|&&(m, _)| m == mode).map(|&(_, s)| s)
+    }
+
+    /// Returns the prefix mode (e.g. 'o') for a status prefix symbol (e.g. '@')
+    pub fn prefix_mode(&self, symbol: u8) -> Option<u8> {
+        self.prefix.iter().find(|&&(_, s)| s == symbol).map(|&(m, _)| m)
+    }
+
+    /// Returns `true` if the user with the given nickname holds the prefix mode
+    /// (e.g. 'o') or a higher one in the channel, according to the order of PREFIX.
+    pub fn has_status(&self, chan: &[u8], nick: &[u8], mode: u8) -> bool {
+        let rank = match self.prefix.iter().position(|&(m, _)| m == mode) {
+            None => return false,
+            Some(rank) => rank
+        };
+        let member = self.channel(chan).and_then(|c| c.member(nick, self.casemap));
+        member.map_or(false, |m| {
+            m.modes.iter().any(|&held| {
+                self.prefix.iter().position(|&(p, _)| p == held).map_or(false, |r| r <= rank)
+            })
+        })
+    }
+
+    /// Returns `true` if the mode is a list mode according to CHANMODES
+    pub fn is_list_mode(&self, mode: u8) -> bool {
+        self.chanmodes[0].contains(&mode)
+    }
+
+    /// Returns `true` if the user with the given nickname matches a ban in the channel
+    /// and doesn't match any ban exception. Users that aren't tracked are matched by
+    /// nickname alone.
+    ///
+    /// This relies on the ban lists having been fetched, see Conn::fetch_lists().
+    pub fn is_banned(&self, chan: &[u8], nick: &[u8]) -> bool {
+        self.list_matches(chan, nick, 'b' as u8, |m| match *m { QuietMask(_) => false, _ => true }) &&
+            !self.list_matches(chan, nick, 'e' as u8, |_| true)
+    }
+
+    /// Returns `true` if the user with the given nickname matches a quiet in the channel,
+    /// either in the +q list or as a `~q:` extended ban, and doesn't match any ban exception.
+    pub fn is_quieted(&self, chan: &[u8], nick: &[u8]) -> bool {
+        let quieted = (self.is_list_mode('q' as u8) &&
+                       self.list_matches(chan, nick, 'q' as u8, |_| true)) ||
+                      self.list_matches(chan, nick, 'b' as u8,
+                                        |m| match *m { QuietMask(_) => true, _ => false });
+        quieted && !self.list_matches(chan, nick, 'e' as u8, |_| true)
+    }
+
+    fn list_matches(&self, chan: &[u8], nick: &[u8], mode: u8, filter: |&Mask| -> bool) -> bool {
+        let chan = match self.channel(chan) {
+            None => return false,
+            Some(c) => c
+        };
+        let info = self.user(nick);
+        let user = match info {
+            Some(info) => info.user.clone(),
+            None => User::new(nick, Some(bytes!("*")), Some(bytes!("*")))
+        };
+        chan.lists.iter().filter(|e| e.mode == mode).any(|e| {
+            let mask = Mask::parse(e.mask);
+            filter(&mask) && mask.matches(&user, info, self.casemap)
+        })
+    }
+
+    /// Returns information about the user with the given nickname, if known
+    pub fn user<'a>(&'a self, nick: &[u8]) -> Option<&'a UserInfo> {
+        self.users.find(&self.casemap.to_lower(nick))
+    }
+
+    /// Returns information about the user who most recently quit, as it was before the
+    /// QUIT was handled. A Conn callback sees a QUIT line after the user is forgotten,
+    /// so this is where it can find the channels they left.
+    pub fn last_quit<'a>(&'a self) -> Option<&'a UserInfo> {
+        self.last_quit.as_ref()
+    }
+
+    /// Returns all known users
+    pub fn users<'a>(&'a self) -> ~[&'a UserInfo] {
+        self.users.values().collect()
+    }
+
+    /// Returns the channel with the given name, if we are in it
+    pub fn channel<'a>(&'a self, name: &[u8]) -> Option<&'a Channel> {
+        self.channels.find(&self.casemap.to_lower(name))
+    }
+
+    /// Returns all channels we are in
+    pub fn channels<'a>(&'a self) -> ~[&'a Channel] {
+        self.channels.values().collect()
+    }
+
+    /// Forgets all users and channels
+    pub fn clear(&mut self) {
+        self.users.clear();
+        self.channels.clear();
+        self.last_quit = None;
+    }
+
+    /// Parses the arguments of a channel MODE line, starting with the mode string,
+    /// into individual changes. The current PREFIX and CHANMODES values are used to
+    /// determine which modes take arguments.
+    pub fn parse_modes<V: Vector<u8>>(&self, args: &[V]) -> ~[ModeChange] {
+        let mut changes = ~[];
+        if args.is_empty() {
+            return changes;
+        }
+        let mut params = args.slice_from(1).iter();
+        let mut set = true;
+        for &mode in args[0].as_slice().iter() {
+            match mode as char {
+                '+' => set = true,
+                '-' => set = false,
+                _ => {
+                    let takes_arg = self.prefix_symbol(mode).is_some() ||
+                                    self.chanmodes[0].contains(&mode) ||
+                                    self.chanmodes[1].contains(&mode) ||
+                                    (set && self.chanmodes[2].contains(&mode));
+                    let arg = if takes_arg {
+                        params.next().map(|v| v.as_slice().to_owned())
+                    } else { None };
+                    changes.push(ModeChange{ set: set, mode: mode, arg: arg });
+                }
+            }
+        }
+        changes
+    }
+
+    /// Updates the state from a received line.
+    /// `me` is our nickname at the time the line was received.
+    pub fn handle_line(&mut self, me: &[u8], line: &Line) {
+        match line.prefix {
+            Some(ref user) if user.host().is_some() => self.learn_host(user),
+            _ => ()
+        }
+        let args = line.args.as_slice();
+        match line.command {
+            IRCCmd(ref cmd) => {
+                let user = match line.prefix {
+                    Some(ref user) => user,
+                    None => return
+                };
+                match (cmd.as_slice(), args) {
+                    ("JOIN", [ref chan]) => self.join(me, user, *chan, None, None),
+                    ("JOIN", [ref chan, ref account, ref real]) => {
+                        let account = if account.as_slice() == bytes!("*") {
+                            None
+                        } else {
+                            Some(account.as_slice())
+                        };
+                        self.join(me, user, *chan, Some(account), Some(real.as_slice()))
+                    }
+                    ("PART", [ref chan, ..]) => self.part(me, user.nick(), *chan),
+                    ("KICK", [ref chan, ref nick, ..]) => self.part(me, *nick, *chan),
+                    ("QUIT", _) => self.quit(user.nick()),
+                    ("NICK", [ref nick, ..]) => self.nick(user.nick(), *nick),
+                    ("ACCOUNT", [ref account, ..]) => {
+                        let account = if account.as_slice() == bytes!("*") {
+                            None
+                        } else {
+                            Some(account.clone())
+                        };
+                        self.with_user(user.nick(), |info| info.account = account.clone());
+                    }
+                    ("AWAY", []) => self.with_user(user.nick(), |info| info.away = None),
+                    ("AWAY", [ref msg, ..]) => {
+                        self.with_user(user.nick(), |info| info.away = Some(msg.clone()))
+                    }
+                    ("CHGHOST", [ref newuser, ref newhost, ..]) => {
+                        let new = User::new(user.nick(), Some(newuser.as_slice()),
+                                            Some(newhost.as_slice()));
+                        self.with_user(user.nick(), |info| info.user = new.clone());
+                    }
+                    ("TOPIC", [ref chan, ref topic, ..]) => {
+                        self.with_channel(*chan, |c| c.topic = Some(topic.clone()))
+                    }
+                    ("MODE", [ref chan, ..]) if self.is_channel(*chan) => {
+                        let changes = self.parse_modes(args.slice_from(1));
+                        self.channel_modes(*chan, user.raw(), changes);
+                    }
+                    _ => ()
+                }
+            }
+            IRCCode(005) if args.len() > 2 => {
+                for param in args.slice(1, args.len()-1).iter() {
+                    self.isupport(param.as_slice());
+                }
+            }
+            // RPL_TOPIC
+            IRCCode(332) if args.len() > 2 => {
+                self.with_channel(args[1], |c| c.topic = Some(args[2].clone()))
+            }
+            // RPL_WHOREPLY: me chan user host server nick flags :hops realname
+            IRCCode(352) if args.len() > 7 => {
+                let real = match args[7].position_elem(&(' ' as u8)) {
+                    None => &[],
+                    Some(idx) => args[7].slice_from(idx+1)
+                };
+                self.who_reply(args[5], args[2], args[3], args[6], None, real);
+            }
+            // RPL_WHOSPCRPL for the WHOX request sent by Conn:
+            // me token chan user host nick flags account :realname
+            IRCCode(354) if args.len() > 8 && args[1].as_slice() == bytes!("743") => {
+                let account = if args[7].as_slice() == bytes!("0") {
+                    None
+                } else {
+                    Some(args[7].as_slice())
+                };
+                self.who_reply(args[5], args[3], args[4], args[6], Some(account), args[8]);
+            }
+            // list replies: me chan mask [setter time]
+            IRCCode(367) if args.len() > 2 => self.list_entry(args, 'b' as u8, 2),
+            IRCCode(348) if args.len() > 2 => self.list_entry(args, 'e' as u8, 2),
+            IRCCode(346) if args.len() > 2 => self.list_entry(args, 'I' as u8, 2),
+            // quiet lists include the mode: me chan q mask [setter time]
+            IRCCode(728) if args.len() > 3 => self.list_entry(args, 'q' as u8, 3),
+            IRCCode(368) if args.len() > 1 => self.list_end(args[1], 'b' as u8),
+            IRCCode(349) if args.len() > 1 => self.list_end(args[1], 'e' as u8),
+            IRCCode(347) if args.len() > 1 => self.list_end(args[1], 'I' as u8),
+            IRCCode(729) if args.len() > 1 => self.list_end(args[1], 'q' as u8),
+            // RPL_NAMREPLY: me symbol chan :names
+            IRCCode(353) if args.len() > 3 => {
+                for name in args[3].split(|&b| b == ' ' as u8).filter(|n| !n.is_empty()) {
+                    self.names_entry(args[2], name);
+                }
+            }
+            _ => ()
+        }
+    }
+
+    fn isupport(&mut self, param: &[u8]) {
+        let (key, value) = match param.position_elem(&('=' as u8)) {
+            None => (param, &[]),
+            Some(idx) => (param.slice_to(idx), param.slice_from(idx+1))
+        };
+        if key == bytes!("CASEMAPPING") {
+            self.casemap = CaseMapping::from_name(Some(value));
+        } else if key == bytes!("CHANTYPES") {
+            self.chantypes = value.to_owned();
+        } else if key == bytes!("PREFIX") {
+            // (modes)symbols
+            if value.starts_with(bytes!("(")) {
+                match value.position_elem(&(')' as u8)) {
+                    None => (),
+                    Some(idx) => {
+                        let (modes, symbols) = (value.slice(1, idx), value.slice_from(idx+1));
+                        self.prefix = modes.iter().zip(symbols.iter())
+                                           .map(|(&m, &s)| (m, s)).collect();
+                    }
+                }
+            }
+        } else if key == bytes!("CHANMODES") {
+            for (i, modes) in value.split(|&b| b == ',' as u8).take(4).enumerate() {
+                self.chanmodes[i] = modes.to_owned();
+            }
+        }
+    }
+
+    fn with_user(&mut self, nick: &[u8], f: |&mut UserInfo|) {
+        match self.users.find_mut(&self.casemap.to_lower(nick)) {
+            None => (),
+            Some(info) => f(info)
+        }
+    }
+
+    fn with_channel(&mut self, name: &[u8], f: |&mut Channel|) {
+        match self.channels.find_mut(&self.casemap.to_lower(name)) {
+            None => (),
+            Some(chan) => f(chan)
+        }
+    }
+
+    // fills in the username and hostname of a known user
+    fn learn_host(&mut self, user: &User) {
+        self.with_user(user.nick(), |info| {
+            if info.user.host().is_none() {
+                info.user = user.clone();
+            }
+        });
+    }
+
+    // adds a user to a channel, creating the user entry if necessary
+    fn add_member(&mut self, user: &User, chan: &[u8], modes: &[u8]) {
+        let key = self.casemap.to_lower(user.nick());
+        let casemap = self.casemap;
+        let chankey = casemap.to_lower(chan);
+        let name = match self.channels.find_mut(&chankey) {
+            None => return,
+            Some(c) => {
+                if !c.members.iter().any(|m| casemap.equiv(m.nick, user.nick())) {
+                    c.members.push(Member{ nick: user.nick().to_owned(), modes: modes.to_owned() });
+                } else if !modes.is_empty() {
+                    for m in c.members.mut_iter().filter(|m| casemap.equiv(m.nick, user.nick())) {
+                        m.modes = modes.to_owned();
+                    }
+                }
+                c.name.clone()
+            }
+        };
+        let info = self.users.find_or_insert_with(key, |_| UserInfo {
+            user: user.clone(),
+            realname: None,
+            account: None,
+            away: None,
+            channels: ~[]
+        });
+        if info.user.host().is_none() && user.host().is_some() {
+            info.user = user.clone();
+        }
+        if !info.channels.iter().any(|c| casemap.equiv(*c, name)) {
+            info.channels.push(name);
+        }
+    }
+
+    fn join(&mut self, me: &[u8], user: &User, chan: &[u8], account: Option<Option<&[u8]>>,
+            real: Option<&[u8]>) {
+        if self.casemap.equiv(user.nick(), me) {
+            let key = self.casemap.to_lower(chan);
+            self.channels.insert(key, Channel{
+                name: chan.to_owned(),
+                topic: None,
+                members: ~[],
+                lists: ~[]
+            });
+        }
+        self.add_member(user, chan, []);
+        self.with_user(user.nick(), |info| {
+            match account {
+                None => (),
+                Some(account) => info.account = account.map(|a| a.to_owned())
+            }
+            match real {
+                None => (),
+                Some(real) => info.realname = Some(real.to_owned())
+            }
+        });
+    }
+
+    fn part(&mut self, me: &[u8], nick: &[u8], chan: &[u8]) {
+        let casemap = self.casemap;
+        let chankey = casemap.to_lower(chan);
+        if casemap.equiv(nick, me) {
+            // we left, so forget the channel and everyone in it
+            let members = match self.channels.pop(&chankey) {
+                None => return,
+                Some(c) => c.members
+            };
+            for m in members.iter() {
+                self.remove_from_channel(m.nick, chan);
+            }
+        } else {
+            self.with_channel(chan, |c| c.members.retain(|m| !casemap.equiv(m.nick, nick)));
+            self.remove_from_channel(nick, chan);
+        }
+    }
+
+    // removes the channel from a user's list, forgetting the user if no channels remain
+    fn remove_from_channel(&mut self, nick: &[u8], chan: &[u8]) {
+        let casemap = self.casemap;
+        let key = casemap.to_lower(nick);
+        let empty = match self.users.find_mut(&key) {
+            None => return,
+            Some(info) => {
+                info.channels.retain(|c| !casemap.equiv(*c, chan));
+                info.channels.is_empty()
+            }
+        };
+        if empty {
+            self.users.remove(&key);
+        }
+    }
+
+    fn quit(&mut self, nick: &[u8]) {
+        let casemap = self.casemap;
+        let info = match self.users.pop(&casemap.to_lower(nick)) {
+            None => return,
+            Some(info) => info
+        };
+        for chan in info.channels.iter() {
+            self.with_channel(*chan, |c| c.members.retain(|m| !casemap.equiv(m.nick, nick)));
+        }
+        self.last_quit = Some(info);
+    }
+
+    fn nick(&mut self, old: &[u8], new: &[u8]) {
+        let casemap = self.casemap;
+        let mut info = match self.users.pop(&casemap.to_lower(old)) {
+            None => return,
+            Some(info) => info
+        };
+        info.user = info.user.with_nick(new);
+        for chan in info.channels.iter() {
+            self.with_channel(*chan, |c| {
+                for m in c.members.mut_iter().filter(|m| casemap.equiv(m.nick, old)) {
+                    m.nick = new.to_owned();
+                }
+            });
+        }
+        self.users.insert(casemap.to_lower(new), info);
+    }
+
+    fn who_reply(&mut self, nick: &[u8], user: &[u8], host: &[u8], flags: &[u8],
+                 account: Option<Option<&[u8]>>, real: &[u8]) {
+        let full = User::new(nick, Some(user), Some(host));
+        self.with_user(nick, |info| {
+            info.user = full.clone();
+            info.realname = Some(real.to_owned());
+            match account {
+                None => (),
+                Some(account) => info.account = account.map(|a| a.to_owned())
+            }
+            if flags.starts_with(bytes!("G")) {
+                if info.away.is_none() {
+                    info.away = Some(~[]);
+                }
+            } else if flags.starts_with(bytes!("H")) {
+                info.away = None;
+            }
+        });
+    }
+
+    fn names_entry(&mut self, chan: &[u8], name: &[u8]) {
+        // strip the status prefixes, which may be several with multi-prefix
+        let mut modes = ~[];
+        let mut name = name;
+        while !name.is_empty() {
+            match self.prefix_mode(name[0]) {
+                None => break,
+                Some(m) => {
+                    modes.push(m);
+                    name = name.slice_from(1);
+                }
+            }
+        }
+        if name.is_empty() {
+            return;
+        }
+        // with userhost-in-names, this is a full nick!user@host
+        let user = User::parse(name);
+        self.add_member(&user, chan, modes);
+    }
+
+    // the first reply of a list replaces the whole list
+    fn list_entry(&mut self, args: &[~[u8]], mode: u8, idx: uint) {
+        let key = (self.casemap.to_lower(args[1]), mode);
+        if !self.loading.contains(&key) {
+            self.with_channel(args[1], |c| c.lists.retain(|e| e.mode != mode));
+            self.loading.push(key);
+        }
+        let entry = ListEntry {
+            mode: mode,
+            mask: args[idx].clone(),
+            setter: args.get_opt(idx+1).map(|s| s.clone()),
+            time: args.get_opt(idx+2).and_then(|t| i64::parse_bytes(*t, 10))
+        };
+        self.with_channel(args[1], |c| c.lists.push(entry.clone()));
+    }
+
+    fn list_end(&mut self, chan: &[u8], mode: u8) {
+        let key = (self.casemap.to_lower(chan), mode);
+        if !self.loading.contains(&key) {
+            // no entries were sent, so the list is empty
+            self.with_channel(chan, |c| c.lists.retain(|e| e.mode != mode));
+        }
+        self.loading.retain(|k| *k != key);
+    }
+
+    fn channel_modes(&mut self, chan: &[u8], setter: &[u8], changes: ~[ModeChange]) {
+        let casemap = self.casemap;
+        let prefix = self.prefix.clone();
+        let lists = self.chanmodes[0].clone();
+        let now = time::get_time().sec;
+        self.with_channel(chan, |c| {
+            for change in changes.iter() {
+                if lists.contains(&change.mode) {
+                    let mask = match change.arg {
+                        None => continue,
+                        Some(ref mask) => mask.as_slice()
+                    };
+                    c.lists.retain(|e| !(e.mode == change.mode && casemap.equiv(e.mask, mask)));
+                    if change.set {
+                        c.lists.push(ListEntry{
+                            mode: change.mode,
+                            mask: mask.to_owned(),
+                            setter: Some(setter.to_owned()),
+                            time: Some(now)
+                        });
+                    }
+                    continue;
+                }
+                if !prefix.iter().any(|&(m, _)| m == change.mode) {
+                    continue;
+                }
+                let nick = match change.arg {
+                    None => continue,
+                    Some(ref nick) => nick.as_slice()
+                };
+                for m in c.members.mut_iter().filter(|m| casemap.equiv(m.nick, nick)) {
+                    m.modes.retain(|&b| b != change.mode);
+                    if change.set {
+                        m.modes.push(change.mode);
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Channel {
+    /// Returns the member with the given nickname, if present.
+    /// Nicknames are compared using the given case mapping.
+    pub fn member<'a>(&'a self, nick: &[u8], casemap: CaseMapping) -> Option<&'a Member> {
+        self.members.iter().find(|m| casemap.equiv(m.nick, nick))
+    }
+}
+
+impl Member {
+    /// Returns `true` if the member holds the given prefix mode, e.g. 'o'
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.modes.contains(&(mode as u8))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{State, ModeChange};
+    use conn::Line;
+
+    fn feed(state: &mut State, me: &[u8], lines: &[&[u8]]) {
+        for &line in lines.iter() {
+            state.handle_line(me, &Line::parse(line).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_state_tracking() {
+        let mut state = State::new();
+        feed(&mut state, bytes!("me"), [
+            bytes!(":me!u@h JOIN #Chan"),
+            bytes!(":server 353 me = #chan :@me +Bob carol!c@carol.host"),
+            bytes!(":bob!b@bob.host JOIN #other acct :Bob Real"),
+            bytes!(":server 352 me #chan c carol.host server carol G :0 Carol Real"),
+            bytes!(":Carol!c@carol.host NICK dave"),
+        ]);
+        let chan = state.channel(bytes!("#CHAN")).unwrap();
+        assert_eq!(chan.name.as_slice(), bytes!("#Chan"));
+        assert_eq!(chan.members.len(), 3);
+        assert!(chan.member(bytes!("bob"), state.casemapping()).unwrap().has_mode('v'));
+        assert!(state.has_status(bytes!("#chan"), bytes!("ME"), 'v' as u8));
+        assert!(state.has_status(bytes!("#chan"), bytes!("bob"), 'v' as u8));
+        assert!(!state.has_status(bytes!("#chan"), bytes!("bob"), 'o' as u8));
+        assert!(!state.has_status(bytes!("#chan"), bytes!("carol"), 'v' as u8));
+        let bob = state.user(bytes!("BOB")).unwrap();
+        assert_eq!(bob.user.host(), Some(bytes!("bob.host")));
+        assert_eq!(bob.account, Some(bytes!("acct").to_owned()));
+        assert_eq!(bob.realname, Some(bytes!("Bob Real").to_owned()));
+        assert!(state.user(bytes!("carol")).is_none());
+        let dave = state.user(bytes!("dave")).unwrap();
+        assert_eq!(dave.user.raw(), bytes!("dave!c@carol.host"));
+        assert_eq!(dave.realname, Some(bytes!("Carol Real").to_owned()));
+        assert!(dave.away.is_some());
+
+        feed(&mut state, bytes!("me"), [
+            bytes!(":server MODE #chan -v+o bob dave"),
+            bytes!(":dave!c@carol.host QUIT :bye"),
+        ]);
+        let chan = state.channel(bytes!("#chan")).unwrap();
+        assert!(!chan.member(bytes!("bob"), state.casemapping()).unwrap().has_mode('v'));
+        assert!(chan.member(bytes!("dave"), state.casemapping()).is_none());
+        assert!(state.user(bytes!("dave")).is_none());
+        let quit = state.last_quit().unwrap();
+        assert_eq!(quit.user.nick(), bytes!("dave"));
+        assert_eq!(quit.channels, ~[bytes!("#Chan").to_owned()]);
+
+        feed(&mut state, bytes!("me"), [bytes!(":me!u@h PART #chan")]);
+        assert!(state.channel(bytes!("#chan")).is_none());
+        assert!(state.user(bytes!("bob")).is_none());
+        assert!(state.user(bytes!("me")).is_none());
+    }
+
+    #[test]
+    fn test_list_modes() {
+        let mut state = State::new();
+        feed(&mut state, bytes!("me"), [
+            bytes!(":me!u@h JOIN #chan"),
+            bytes!(":server 353 me = #chan :@me bob!b@bad.host carol!c@good.host"),
+            bytes!(":server 367 me #chan *!*@*.host op 1392000000"),
+            bytes!(":server 367 me #chan ~q:carol!*@*"),
+            bytes!(":server 368 me #chan :End of Channel Ban List"),
+            bytes!(":server 349 me #chan :End of Channel Exception List"),
+            bytes!(":op!o@op.host MODE #chan +e *!*@good.host"),
+        ]);
+        {
+            let chan = state.channel(bytes!("#chan")).unwrap();
+            assert_eq!(chan.lists.len(), 3);
+            assert_eq!(chan.lists[0].setter, Some(bytes!("op").to_owned()));
+            assert_eq!(chan.lists[0].time, Some(1392000000));
+            assert_eq!(chan.lists[2].setter, Some(bytes!("op!o@op.host").to_owned()));
+        }
+        assert!(state.is_banned(bytes!("#chan"), bytes!("bob")));
+        assert!(!state.is_banned(bytes!("#chan"), bytes!("carol")));
+        assert!(!state.is_quieted(bytes!("#chan"), bytes!("carol")));
+        assert!(!state.is_banned(bytes!("#chan"), bytes!("dave")));
+
+        feed(&mut state, bytes!("me"), [
+            bytes!(":op!o@op.host MODE #chan -eb *!*@good.host *!*@*.host"),
+            bytes!(":server 367 me #chan dave!*@*"),
+            bytes!(":server 368 me #chan :End of Channel Ban List"),
+        ]);
+        assert!(!state.is_banned(bytes!("#chan"), bytes!("bob")));
+        assert!(state.is_banned(bytes!("#chan"), bytes!("dave")));
+        assert_eq!(state.channel(bytes!("#chan")).unwrap().lists.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_modes() {
+        let state = State::new();
+        let changes = state.parse_modes([bytes!("+ol-k+b"), bytes!("nick"), bytes!("10"),
+                                         bytes!("key"), bytes!("*!*@host")]);
+        assert_eq!(changes, ~[
+            ModeChange{ set: true, mode: 'o' as u8, arg: Some(bytes!("nick").to_owned()) },
+            ModeChange{ set: true, mode: 'l' as u8, arg: Some(bytes!("10").to_owned()) },
+            ModeChange{ set: false, mode: 'k' as u8, arg: Some(bytes!("key").to_owned()) },
+            ModeChange{ set: true, mode: 'b' as u8, arg: Some(bytes!("*!*@host").to_owned()) },
+        ]);
+    }
+}